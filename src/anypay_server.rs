@@ -3,15 +3,18 @@ use std::sync::Arc;
 use axum::Server;
 use tracing::info;
 use anyhow::Result;
-use crate::server::AnypayEventsServer;
+use crate::server::{AnypayEventsServer, ShutdownHandle};
+use crate::config::ServerConfig;
 use crate::supabase::SupabaseClient;
 use crate::http::HttpServer;
 use crate::amqp::AmqpClient;
 use crate::xrpl::XRPLClient;
 use crate::ethereum::EthereumClient;
+use crate::log_stream::LogStream;
 
 pub struct AnypayServer {
     ws_server: AnypayEventsServer,
+    supabase: Arc<SupabaseClient>,
     http_server: HttpServer,
     xrpl_client: Option<XRPLClient>,
     eth_client: Option<EthereumClient>,
@@ -62,10 +65,11 @@ impl AnypayServer {
             supabase_url,
             supabase_anon_key,
             supabase_service_role_key,
-        );
+        ).with_config(ServerConfig::from_env()?)
+            .with_store_events(supabase.events().clone());
 
         // Initialize HTTP server
-        let http_server = HttpServer::new(supabase);
+        let http_server = HttpServer::new(supabase.clone());
 
         // Initialize blockchain clients
         let eth_client = if let Some(ws_url) = eth_wss_url {
@@ -140,6 +144,7 @@ impl AnypayServer {
 
         Ok(Self {
             ws_server,
+            supabase,
             http_server,
             xrpl_client,
             eth_client,
@@ -151,6 +156,22 @@ impl AnypayServer {
         })
     }
 
+    /// The client whose writes reach WebSocket subscribers. Payment watchers
+    /// should write through it, or a clone of it.
+    pub fn supabase(&self) -> SupabaseClient {
+        (*self.supabase).clone()
+    }
+
+    /// Forwards `log_stream`'s lines to admins subscribed to `logs`.
+    pub fn with_log_stream(mut self, log_stream: LogStream) -> Self {
+        self.ws_server = self.ws_server.with_log_stream(log_stream);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.ws_server.shutdown_handle()
+    }
+
     pub async fn run(self) -> Result<()> {
         let http_app = self.http_server.router();
         let http_addr = SocketAddr::from(([127, 0, 0, 1], self.http_port));
@@ -158,24 +179,30 @@ impl AnypayServer {
         info!("Starting WebSocket server...");
         info!("Starting HTTP server on http://127.0.0.1:{}", self.http_port);
 
-        match self.xrpl_client {
-            Some(mut xrpl) => {
-                if let Some(url) = self.xrpl_url {
-                    tokio::join!(
-                        self.ws_server.run(),
-                        Server::bind(&http_addr).serve(http_app.into_make_service()),
-                        async move { xrpl.run_with_url(&url).await }
-                    );
-                }
-            }
-            None => {
-                tokio::join!(
+        // Everything stops on the WebSocket server's shutdown, which returns
+        // once its connections have drained
+        let shutdown = self.ws_server.shutdown_handle();
+        let http = Server::bind(&http_addr)
+            .serve(http_app.into_make_service())
+            .with_graceful_shutdown(shutdown.requested());
+        let (ws_result, _) = match (self.xrpl_client, self.xrpl_url) {
+            (Some(mut xrpl), Some(url)) => {
+                let stopped = shutdown.clone();
+                let (ws_result, http_result, _) = tokio::join!(
                     self.ws_server.run(),
-                    Server::bind(&http_addr).serve(http_app.into_make_service())
+                    http,
+                    async move {
+                        tokio::select! {
+                            _ = xrpl.run_with_url(&url) => {}
+                            _ = stopped.requested() => {}
+                        }
+                    }
                 );
+                (ws_result, http_result)
             }
-        }
+            _ => tokio::join!(self.ws_server.run(), http),
+        };
 
-        Ok(())
+        ws_result
     }
 }
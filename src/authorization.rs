@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+use crate::event_bus::StoreEvent;
+
+/// Caches whether an account may subscribe to an invoice, so reconnect storms
+/// don't repeat the same ownership lookup against the store.
+pub struct AuthorizationCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<(i32, String), (bool, Instant)>>,
+}
+
+impl AuthorizationCache {
+    pub fn new(ttl: Duration) -> Self {
+        AuthorizationCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached decision for `(account_id, invoice_uid)`, or runs
+    /// `check` and caches its result. Errors are not cached.
+    pub async fn is_authorized<F, Fut>(&self, account_id: i32, invoice_uid: &str, check: F) -> anyhow::Result<bool>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<bool>>,
+    {
+        let key = (account_id, invoice_uid.to_string());
+        if let Some((allowed, checked_at)) = self.entries.read().await.get(&key) {
+            if checked_at.elapsed() < self.ttl {
+                return Ok(*allowed);
+            }
+        }
+
+        let allowed = check().await?;
+        let mut entries = self.entries.write().await;
+        entries.retain(|_, (_, checked_at)| checked_at.elapsed() < self.ttl);
+        entries.insert(key, (allowed, Instant::now()));
+        Ok(allowed)
+    }
+
+    /// Drops cached decisions for an invoice.
+    pub async fn invalidate_invoice(&self, invoice_uid: &str) {
+        self.entries.write().await.retain(|(_, uid), _| uid != invoice_uid);
+    }
+}
+
+/// Invalidates cached decisions for invoices as the store reports changes to them.
+pub async fn invalidate_on_changes(mut events: broadcast::Receiver<StoreEvent>, cache: Arc<AuthorizationCache>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => cache.invalidate_invoice(&event.invoice().uid).await,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_repeated_subscribes_hit_backend_once_within_ttl() {
+        let cache = AuthorizationCache::new(Duration::from_secs(60));
+        let lookups = AtomicUsize::new(0);
+        let check = || async {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        };
+
+        for _ in 0..5 {
+            assert!(cache.is_authorized(7, "inv_123", check).await.unwrap());
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // Another account is a separate decision
+        assert!(cache.is_authorized(8, "inv_123", check).await.unwrap());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        cache.invalidate_invoice("inv_123").await;
+        assert!(cache.is_authorized(7, "inv_123", check).await.unwrap());
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_expired_decisions_are_rechecked() {
+        let cache = AuthorizationCache::new(Duration::ZERO);
+        let lookups = AtomicUsize::new(0);
+        let check = || async {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(false)
+        };
+
+        assert!(!cache.is_authorized(7, "inv_123", check).await.unwrap());
+        assert!(!cache.is_authorized(7, "inv_123", check).await.unwrap());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+}
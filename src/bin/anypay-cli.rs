@@ -464,6 +464,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     let msg = WsMessage::Subscribe {
                         sub_type: "invoice".to_string(),
                         id: uid.clone(),
+                        filter: None,
+                        fields: None,
+                        view: None,
                     };
                     
                     write.send(Message::Text(serde_json::to_string(&msg)?)).await?;
@@ -1,11 +1,15 @@
 use clap::Parser;
 use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 use anypay::anypay_server::AnypayServer;
 use anyhow::Result;
 use anypay::blockbook::BlockbookClient;
+use anypay::config::ServerConfig;
+use anypay::log_stream::LogStream;
 use tokio::signal;
-use anypay::supabase::SupabaseClient;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -75,30 +79,20 @@ struct Args {
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Setup logging
+    // Setup logging. Lines also feed admin `logs` subscriptions.
     let log_level = if args.debug { Level::DEBUG } else { Level::INFO };
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(log_level)
-        .with_target(false)
-        .with_thread_ids(false)
-        .with_file(true)
-        .with_line_number(true)
-        .compact()
+    let log_stream = LogStream::default();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_thread_ids(false)
+            .with_file(true)
+            .with_line_number(true)
+            .compact()
+            .with_filter(LevelFilter::from_level(log_level)))
+        .with(log_stream.layer(Level::INFO, 50))
         .init();
 
-    // Initialize Blockbook client if configured
-    let blockbook_handle = if let Some(blockbook_url) = args.blockbook_url {
-        let api_key = args.blockbook_api_key.ok_or_else(|| {
-            anyhow::anyhow!("Blockbook API key is required when Blockbook URL is provided")
-        })?;
-
-        let supabase = SupabaseClient::new(&args.supabase_url, &args.supabase_anon_key, &args.supabase_service_role_key);
-        let blockbook = BlockbookClient::new(blockbook_url, api_key, supabase);
-        Some(blockbook.start_subscription().await?)
-    } else {
-        None
-    };
-
     info!("Starting Anypay server...");
 
     // Initialize and run server
@@ -115,16 +109,37 @@ async fn main() -> Result<()> {
         args.polygon_wss_url,
         args.avax_wss_url,
         args.bnb_wss_url,
-    ).await?;
-    
-    // Wait for shutdown signal
+    ).await?
+        .with_log_stream(log_stream);
+
+    // Initialize Blockbook client if configured. It writes through the server's
+    // client so confirmed payments reach WebSocket subscribers.
+    let blockbook_handle = if let Some(blockbook_url) = args.blockbook_url {
+        let api_key = args.blockbook_api_key.ok_or_else(|| {
+            anyhow::anyhow!("Blockbook API key is required when Blockbook URL is provided")
+        })?;
+
+        let blockbook = BlockbookClient::new(blockbook_url, api_key, server.supabase())
+            .with_thresholds(ServerConfig::from_env()?.confirmations_required);
+        Some(blockbook.start_subscription().await?)
+    } else {
+        None
+    };
+
+    let shutdown = server.shutdown_handle();
+    let running = server.run();
+    tokio::pin!(running);
+
+    // On the shutdown signal, keep running the server until it has closed its connections
     tokio::select! {
-        _ = server.run() => {},
+        result = &mut running => result?,
         _ = signal::ctrl_c() => {
             info!("Received shutdown signal");
+            shutdown.shutdown();
             if let Some(handle) = blockbook_handle {
                 handle.shutdown().await;
             }
+            running.await?;
         }
     }
 
@@ -1,12 +1,14 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::{connect_async, tungstenite::{Message, http::{Uri, Request, HeaderValue}}};
-use tracing::{info, error};
-use tokio::sync::oneshot;
+use tracing::{info, error, debug};
+use tokio::sync::{oneshot, Mutex};
 use reqwest;
 use crate::supabase::SupabaseClient;
-use crate::confirmations;
+use crate::confirmations::{self, Confirmation, ConfirmationThresholds, Payment};
 use chrono::{DateTime, Utc};
 
 #[derive(Debug, Serialize)]
@@ -93,10 +95,16 @@ struct BlockbookTransaction {
     // We can add other fields if needed later
 }
 
+#[derive(Clone)]
 pub struct BlockbookClient {
     ws_url: String,
     api_key: String,
     supabase: SupabaseClient,
+    thresholds: ConfirmationThresholds,
+    /// Payments seen in a block but short of their currency's threshold, by
+    /// txid, with the confirmation from the block that included them. Kept in
+    /// memory, so a restart forgets payments still waiting.
+    pending: Arc<Mutex<HashMap<String, (Payment, Confirmation)>>>,
 }
 
 pub struct BlockbookHandle {
@@ -105,7 +113,20 @@ pub struct BlockbookHandle {
 
 impl BlockbookClient {
     pub fn new(ws_url: String, api_key: String, supabase: SupabaseClient) -> Self {
-        Self { ws_url, api_key, supabase }
+        Self {
+            ws_url,
+            api_key,
+            supabase,
+            thresholds: ConfirmationThresholds::default(),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Marks invoices paid only once their payment has `thresholds`
+    /// confirmations, announcing each one before that.
+    pub fn with_thresholds(mut self, thresholds: ConfirmationThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
     }
 
     pub async fn start_subscription(&self) -> Result<BlockbookHandle> {
@@ -135,9 +156,7 @@ impl BlockbookClient {
 
         info!("Subscribed to blocks and transactions from Blockbook");
 
-        let ws_url = self.ws_url.clone();
-        let api_key = self.api_key.clone();
-        let supabase = self.supabase.clone();
+        let client = self.clone();
 
         tokio::spawn(async move {
             tokio::select! {
@@ -158,7 +177,6 @@ impl BlockbookClient {
                                             match data {
                                                 BlockbookData::Block(block) => {
                                                     info!("New block: hash={} height={}", block.hash, block.height);
-                                                    let client = BlockbookClient::new(ws_url.clone(), api_key.clone(), supabase.clone());
                                                     if let Err(e) = client.process_block(&block).await {
                                                         error!("Failed to process block {}: {}", block.hash, e);
                                                     }
@@ -214,10 +232,20 @@ impl BlockbookClient {
         info!("Processing block {} at height {}", block.hash, block.height);
         
         let txids = self.get_block_txids(&block.hash).await?;
-        
+        self.confirm_block(block, txids).await
+    }
+
+    /// Counts `block` as a confirmation of every payment still waiting on
+    /// one, starting with the payments among its `txids`, and confirms the
+    /// payments that reach their currency's threshold.
+    async fn confirm_block(&self, block: &BlockNotification, txids: Vec<String>) -> Result<()> {
+        let mut pending = self.pending.lock().await;
         for txid in txids {
+            if pending.contains_key(&txid) {
+                continue;
+            }
             if let Some(payment) = self.supabase.get_unconfirmed_payment_by_txid(&txid).await? {
-                let confirmation = confirmations::Confirmation {
+                let included = Confirmation {
                     confirmation_hash: block.hash.clone(),
                     confirmation_height: block.height as i32,
                     confirmation_date: if block.timestamp > 0 {
@@ -226,15 +254,30 @@ impl BlockbookClient {
                     } else {
                         Utc::now()
                     },
-                    confirmations: Some(1),
+                    confirmations: None,
                 };
+                pending.insert(txid, (payment, included));
+            }
+        }
 
-                match self.supabase.confirm_payment(payment, confirmation).await {
-                    Ok(_) => info!("Confirmed payment for txid {}", txid),
-                    Err(e) => error!("Failed to confirm payment for txid {}: {}", txid, e),
+        let mut confirmed = Vec::new();
+        for (txid, (payment, included)) in pending.iter() {
+            let confirmation = Confirmation {
+                confirmations: Some(block.height as i32 - included.confirmation_height + 1),
+                ..included.clone()
+            };
+            match confirmations::apply_confirmation(&self.supabase, &self.thresholds, payment, &confirmation).await {
+                Ok(Some(_)) => {
+                    info!("Confirmed payment for txid {}", txid);
+                    confirmed.push(txid.clone());
                 }
+                Ok(None) => debug!("Payment for txid {} is waiting on confirmations", txid),
+                Err(e) => error!("Failed to confirm payment for txid {}: {}", txid, e),
             }
         }
+        for txid in confirmed {
+            pending.remove(&txid);
+        }
         Ok(())
     }
 }
@@ -243,4 +286,57 @@ impl BlockbookHandle {
     pub async fn shutdown(self) {
         let _ = self.shutdown.send(());
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_bus::StoreEvent;
+    use crate::test_support::spawn_memory_store;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_invoice_is_paid_once_its_payment_has_the_required_confirmations() {
+        let url = spawn_memory_store().await;
+        let supabase = SupabaseClient::new(&url, "anon", "service");
+        let created = supabase.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+        reqwest::Client::new()
+            .post(format!("{}/rest/v1/payments", url))
+            .json(&json!([{ "txid": "tx_1", "chain": "BTC", "currency": "BTC", "status": "unconfirmed", "invoice_uid": uid }]))
+            .send()
+            .await
+            .unwrap();
+
+        let client = BlockbookClient::new("blockbook.invalid".to_string(), "key".to_string(), supabase.clone())
+            .with_thresholds(ConfirmationThresholds::new([("BTC".to_string(), 6)]));
+        let mut events = supabase.events().subscribe();
+        let block = |height: u32| BlockNotification { hash: format!("block_{}", height), height, timestamp: 0 };
+
+        // Included at height 10, then confirmed by each block on top of it
+        client.confirm_block(&block(9), vec!["tx_other".to_string()]).await.unwrap();
+        for height in 10..15 {
+            let txids = if height == 10 { vec!["tx_1".to_string()] } else { Vec::new() };
+            client.confirm_block(&block(height), txids).await.unwrap();
+            match events.try_recv() {
+                Ok(StoreEvent::InvoiceConfirmation { invoice, confirmations, required }) => {
+                    assert_eq!(invoice.uid, uid);
+                    assert_eq!((confirmations, required), (height - 9, 6));
+                }
+                other => panic!("expected a confirmation at height {}, got {:?}", height, other),
+            }
+            assert!(events.try_recv().is_err(), "paid before the 6th confirmation");
+        }
+
+        client.confirm_block(&block(15), Vec::new()).await.unwrap();
+        let paid = events.try_recv().expect("paid at the 6th confirmation");
+        assert_eq!(paid.event_type(), "invoice.paid");
+        assert_eq!(paid.invoice().uid, uid);
+        let payment = supabase.get_payment_by_txid("tx_1").await.unwrap().unwrap();
+        assert_eq!((payment.status.as_str(), payment.confirmation_hash.as_deref()), ("confirmed", Some("block_10")));
+
+        // Later blocks no longer count towards it
+        client.confirm_block(&block(16), Vec::new()).await.unwrap();
+        assert!(events.try_recv().is_err());
+    }
+}
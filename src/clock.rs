@@ -0,0 +1,18 @@
+use chrono::{DateTime, Utc};
+
+/// Where the server reads the current time. Tests fix it to a known instant.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Clock {
+    #[default]
+    System,
+    Fixed(DateTime<Utc>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::System => Utc::now(),
+            Clock::Fixed(now) => *now,
+        }
+    }
+}
@@ -1,5 +1,17 @@
 use serde::Deserialize;
 use anyhow::{Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::clock::Clock;
+use crate::confirmations::ConfirmationThresholds;
+use crate::event_dispatcher::{UnknownStatusPolicy, KNOWN_INVOICE_STATUSES};
+use crate::session::{SlowReaderAction, SubscriberCounts};
+use crate::currency::{AmountPrecisionPolicy, AmountRepresentation, CurrencyTable};
+use crate::supabase::BackoffPolicy;
+use crate::uid::UidScheme;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -41,4 +53,422 @@ impl Config {
                 .map_err(|e| anyhow!("Invalid HTTP_PORT: {}", e))?,
         })
     }
-} 
\ No newline at end of file
+}
+
+/// Paging defaults applied to every list action on the WebSocket server.
+#[derive(Debug, Clone)]
+pub struct PaginationConfig {
+    pub default_limit: usize,
+    pub max_limit: usize,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        PaginationConfig {
+            default_limit: 50,
+            max_limit: 500,
+        }
+    }
+}
+
+impl PaginationConfig {
+    /// Resolves a client-supplied limit: omitted uses the default, anything above
+    /// the max is clamped to it.
+    pub fn limit(&self, requested: Option<usize>) -> usize {
+        requested.unwrap_or(self.default_limit).clamp(1, self.max_limit)
+    }
+}
+
+/// Runtime settings for the WebSocket server. Every field has a default so
+/// `ServerConfig::default()` matches the behaviour of an unconfigured server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub pagination: PaginationConfig,
+    /// Allows `create_invoice` with `test: true`, which marks the invoice paid
+    /// after `test_payment_delay`. Must stay off in production.
+    pub test_mode: bool,
+    pub test_payment_delay: Duration,
+    /// Envelope version used when the client does not request one on connect.
+    pub default_envelope_version: u8,
+    /// Accounts whose authenticated sessions may use admin actions.
+    pub admin_account_ids: HashSet<i32>,
+    /// Upper bound on the combined size of handshake request headers. Larger
+    /// handshakes are refused with 431 Request Header Fields Too Large.
+    pub max_handshake_header_bytes: usize,
+    /// Only accept connections a trusted proxy forwarded over HTTPS, going by
+    /// its `X-Forwarded-Proto` header.
+    pub require_https: bool,
+    /// Peers whose `X-Forwarded-Proto` header is believed.
+    pub trusted_proxies: HashSet<IpAddr>,
+    /// Queued outbound frames at which a session is logged as a slow reader. 0 disables.
+    pub send_queue_high_water_mark: usize,
+    /// Queued outbound frames at which a session that sends another request
+    /// is treated as not reading its replies. 0 disables the check.
+    pub slow_reader_limit: usize,
+    /// Whether such a session has its requests paused or is disconnected.
+    pub slow_reader_action: SlowReaderAction,
+    /// How long events are collected per session before going out together
+    /// as one array frame. Zero sends each event as it happens.
+    pub event_batch_interval: Duration,
+    /// Inbound messages a session may send in a burst. 0 disables rate limiting.
+    pub rate_limit_burst: u32,
+    /// Time for a rate-limited session to earn back one message.
+    pub rate_limit_refill: Duration,
+    /// Store-bound requests an account's sessions may send in a burst
+    /// between them. 0 disables account rate limiting.
+    pub account_rate_limit_burst: u32,
+    /// Time for a rate-limited account to earn back one request.
+    pub account_rate_limit_refill: Duration,
+    /// `create_invoice` requests all sessions together may send in a burst,
+    /// since each new invoice takes addresses from a limited pool. 0 disables the limit.
+    pub create_rate_limit_burst: u32,
+    /// Time for the server to earn back one invoice creation.
+    pub create_rate_limit_refill: Duration,
+    /// How `create_invoice` treats decimal amounts more precise than their currency.
+    pub amount_precision: AmountPrecisionPolicy,
+    /// Whether invoice amounts go out as minor-unit integers or decimal strings.
+    pub amount_representation: AmountRepresentation,
+    /// Invoice statuses subscribers are expected to understand.
+    pub known_invoice_statuses: HashSet<String>,
+    /// Whether events for invoices in any other status are passed through or rejected.
+    pub unknown_status_policy: UnknownStatusPolicy,
+    /// Longest a `wait_for_payment` request may block, and its default timeout.
+    pub max_wait_for_payment: Duration,
+    /// Log events that could not be delivered to any subscriber.
+    pub dead_letter_log: bool,
+    /// Require sessions to own the invoice, account, or tag they subscribe to.
+    pub authorize_subscriptions: bool,
+    /// Answer `fetch_invoice` for sessions that haven't authenticated, for
+    /// deployments where payers fetch the invoice they are paying.
+    pub allow_unauthenticated_fetch: bool,
+    /// How long an invoice ownership decision is reused before re-checking.
+    pub authorization_cache_ttl: Duration,
+    /// Include the store's error body in responses. Must stay off in production.
+    pub debug_errors: bool,
+    /// Connections that send nothing this long after the handshake are closed,
+    /// unless they were auto-subscribed. 0 disables.
+    pub first_message_timeout: Duration,
+    /// Report soft-deleted invoices as `GONE`. When off they are reported as `NOT_FOUND`.
+    pub report_deleted_invoices: bool,
+    /// Most frames read off a connection at once when several are ready. 1 disables batching.
+    pub inbound_batch_size: usize,
+    /// How many read-only messages from one batch are handled at the same time.
+    pub inbound_batch_concurrency: usize,
+    /// Decimal places per currency, used to convert and format amounts.
+    pub currencies: CurrencyTable,
+    /// New subscriptions are refused while this many events wait to be dispatched. 0 disables.
+    pub dispatch_queue_high_water_mark: usize,
+    /// How long refused subscribers are told to wait before retrying.
+    pub overload_retry_after: Duration,
+    /// Longest topic id a subscription may use, in bytes. 0 disables the limit.
+    pub max_subscription_id_length: usize,
+    /// Who gets the topic's current `subscriber_count` in a subscribe ack.
+    pub subscriber_counts: SubscriberCounts,
+    /// Reject messages carrying fields their action doesn't define, instead of ignoring them.
+    pub strict_messages: bool,
+    /// Events held per paused subscription; older ones are dropped past this. 0 drops all.
+    pub paused_buffer_size: usize,
+    /// How new invoice uids are generated.
+    pub invoice_uid_scheme: UidScheme,
+    /// Whether `fetch_invoice` loads payment options when the client doesn't say.
+    pub expand_payment_options: bool,
+    /// Most values a session can keep with `set_context`.
+    pub max_context_entries: usize,
+    /// Largest a session's context can be, serialized as JSON.
+    pub max_context_bytes: usize,
+    /// Connections are closed this long after they open, so clients reconnect
+    /// and authenticate again. 0 disables.
+    pub max_connection_lifetime: Duration,
+    /// How store requests answered with 429 are retried before the client is
+    /// told to back off.
+    pub store_backoff: BackoffPolicy,
+    /// Events kept per subscribed topic for `recent_events`, which is also the
+    /// most it returns. 0 disables the buffer.
+    pub recent_events_buffer: usize,
+    /// How long shutdown waits for connections to close before aborting them.
+    pub shutdown_drain_timeout: Duration,
+    /// Patch invoice rows that drifted from the expected schema, logging a
+    /// warning, instead of failing the request.
+    pub tolerate_schema_drift: bool,
+    /// Account the admin `selftest` action creates and cancels invoices for.
+    /// 0 disables the action.
+    pub selftest_account_id: i64,
+    /// Drop an event identical to the last one delivered on a subscription.
+    pub dedup_identical_events: bool,
+    /// How long a paid or cancelled event for an invoice nobody was subscribed
+    /// to is kept for `recent_events`. 0 disables.
+    pub missed_event_retention: Duration,
+    /// Confirmations a payment in each currency needs before its invoice is
+    /// marked paid. `invoice.confirmation` is sent for each one before that.
+    pub confirmations_required: ConfirmationThresholds,
+    /// Close a connection whose request handler panicked. Otherwise the
+    /// requests get `INTERNAL_ERROR` responses and the connection stays open.
+    pub close_on_handler_panic: bool,
+    /// Where `server_time` reads the current time.
+    pub clock: Clock,
+    /// Most `subscription.*` events per second sent to admins subscribed to
+    /// `meta:subscriptions`. 0 disables the topic.
+    pub subscription_events_per_second: u32,
+    /// Topics an event is delivered to at a time before other dispatcher work
+    /// may run, bounding account-wide fan-outs. 0 disables chunking.
+    pub max_topics_per_dispatch: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            pagination: PaginationConfig::default(),
+            test_mode: false,
+            test_payment_delay: Duration::from_secs(3),
+            default_envelope_version: 0,
+            admin_account_ids: HashSet::new(),
+            max_handshake_header_bytes: 16 * 1024,
+            require_https: false,
+            trusted_proxies: HashSet::new(),
+            send_queue_high_water_mark: 1000,
+            slow_reader_limit: 0,
+            slow_reader_action: SlowReaderAction::Pause,
+            event_batch_interval: Duration::ZERO,
+            rate_limit_burst: 0,
+            rate_limit_refill: Duration::from_millis(100),
+            account_rate_limit_burst: 0,
+            account_rate_limit_refill: Duration::from_millis(100),
+            create_rate_limit_burst: 0,
+            create_rate_limit_refill: Duration::from_millis(1000),
+            amount_precision: AmountPrecisionPolicy::Reject,
+            amount_representation: AmountRepresentation::MinorUnits,
+            known_invoice_statuses: KNOWN_INVOICE_STATUSES.iter().map(|status| status.to_string()).collect(),
+            unknown_status_policy: UnknownStatusPolicy::PassThrough,
+            max_wait_for_payment: Duration::from_secs(300),
+            dead_letter_log: false,
+            authorize_subscriptions: false,
+            allow_unauthenticated_fetch: true,
+            authorization_cache_ttl: Duration::from_secs(30),
+            debug_errors: false,
+            first_message_timeout: Duration::from_secs(30),
+            report_deleted_invoices: true,
+            inbound_batch_size: 1,
+            inbound_batch_concurrency: 4,
+            currencies: CurrencyTable::default(),
+            dispatch_queue_high_water_mark: 0,
+            overload_retry_after: Duration::from_millis(1000),
+            max_subscription_id_length: 256,
+            subscriber_counts: SubscriberCounts::Hidden,
+            strict_messages: false,
+            paused_buffer_size: 100,
+            invoice_uid_scheme: UidScheme::default(),
+            expand_payment_options: false,
+            max_context_entries: 16,
+            max_context_bytes: 4096,
+            max_connection_lifetime: Duration::ZERO,
+            store_backoff: BackoffPolicy::default(),
+            recent_events_buffer: crate::event_dispatcher::DEFAULT_RECENT_EVENTS,
+            shutdown_drain_timeout: Duration::from_secs(10),
+            tolerate_schema_drift: true,
+            selftest_account_id: 0,
+            dedup_identical_events: false,
+            missed_event_retention: Duration::ZERO,
+            confirmations_required: ConfirmationThresholds::default(),
+            close_on_handler_panic: true,
+            clock: Clock::System,
+            subscription_events_per_second: 0,
+            max_topics_per_dispatch: 0,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = ServerConfig::default();
+
+        let config = ServerConfig {
+            pagination: PaginationConfig {
+                default_limit: env_or("WS_DEFAULT_PAGE_SIZE", defaults.pagination.default_limit)?,
+                max_limit: env_or("WS_MAX_PAGE_SIZE", defaults.pagination.max_limit)?,
+            },
+            test_mode: env_or("WS_TEST_MODE", defaults.test_mode)?,
+            test_payment_delay: Duration::from_millis(
+                env_or("WS_TEST_PAYMENT_DELAY_MS", defaults.test_payment_delay.as_millis() as u64)?
+            ),
+            default_envelope_version: env_or("WS_DEFAULT_ENVELOPE_VERSION", defaults.default_envelope_version)?,
+            admin_account_ids: env_list("WS_ADMIN_ACCOUNT_IDS")?.into_iter().collect(),
+            max_handshake_header_bytes: env_or("WS_MAX_HANDSHAKE_HEADER_BYTES", defaults.max_handshake_header_bytes)?,
+            require_https: env_or("WS_REQUIRE_HTTPS", defaults.require_https)?,
+            trusted_proxies: env_list("WS_TRUSTED_PROXIES")?.into_iter().collect(),
+            send_queue_high_water_mark: env_or("WS_SEND_QUEUE_HIGH_WATER_MARK", defaults.send_queue_high_water_mark)?,
+            slow_reader_limit: env_or("WS_SLOW_READER_LIMIT", defaults.slow_reader_limit)?,
+            slow_reader_action: env_or("WS_SLOW_READER_ACTION", defaults.slow_reader_action)?,
+            event_batch_interval: Duration::from_millis(
+                env_or("WS_EVENT_BATCH_INTERVAL_MS", defaults.event_batch_interval.as_millis() as u64)?
+            ),
+            rate_limit_burst: env_or("WS_RATE_LIMIT_BURST", defaults.rate_limit_burst)?,
+            rate_limit_refill: Duration::from_millis(
+                env_or("WS_RATE_LIMIT_REFILL_MS", defaults.rate_limit_refill.as_millis() as u64)?
+            ),
+            account_rate_limit_burst: env_or("WS_ACCOUNT_RATE_LIMIT_BURST", defaults.account_rate_limit_burst)?,
+            account_rate_limit_refill: Duration::from_millis(
+                env_or("WS_ACCOUNT_RATE_LIMIT_REFILL_MS", defaults.account_rate_limit_refill.as_millis() as u64)?
+            ),
+            create_rate_limit_burst: env_or("WS_CREATE_RATE_LIMIT_BURST", defaults.create_rate_limit_burst)?,
+            create_rate_limit_refill: Duration::from_millis(
+                env_or("WS_CREATE_RATE_LIMIT_REFILL_MS", defaults.create_rate_limit_refill.as_millis() as u64)?
+            ),
+            amount_precision: env_or("WS_AMOUNT_PRECISION_POLICY", defaults.amount_precision)?,
+            amount_representation: env_or("WS_AMOUNT_REPRESENTATION", defaults.amount_representation)?,
+            known_invoice_statuses: match env_list("WS_KNOWN_INVOICE_STATUSES")? {
+                statuses if statuses.is_empty() => defaults.known_invoice_statuses,
+                statuses => statuses.into_iter().collect(),
+            },
+            unknown_status_policy: env_or("WS_UNKNOWN_STATUS_POLICY", defaults.unknown_status_policy)?,
+            max_wait_for_payment: Duration::from_secs(
+                env_or("WS_MAX_WAIT_FOR_PAYMENT_SECS", defaults.max_wait_for_payment.as_secs())?
+            ),
+            dead_letter_log: env_or("WS_DEAD_LETTER_LOG", defaults.dead_letter_log)?,
+            authorize_subscriptions: env_or("WS_AUTHORIZE_SUBSCRIPTIONS", defaults.authorize_subscriptions)?,
+            allow_unauthenticated_fetch: env_or("WS_ALLOW_UNAUTHENTICATED_FETCH", defaults.allow_unauthenticated_fetch)?,
+            authorization_cache_ttl: Duration::from_secs(
+                env_or("WS_AUTHORIZATION_CACHE_TTL_SECS", defaults.authorization_cache_ttl.as_secs())?
+            ),
+            debug_errors: env_or("WS_DEBUG_ERRORS", defaults.debug_errors)?,
+            first_message_timeout: Duration::from_secs(
+                env_or("WS_FIRST_MESSAGE_TIMEOUT_SECS", defaults.first_message_timeout.as_secs())?
+            ),
+            report_deleted_invoices: env_or("WS_REPORT_DELETED_INVOICES", defaults.report_deleted_invoices)?,
+            inbound_batch_size: env_or("WS_INBOUND_BATCH_SIZE", defaults.inbound_batch_size)?,
+            inbound_batch_concurrency: env_or("WS_INBOUND_BATCH_CONCURRENCY", defaults.inbound_batch_concurrency)?,
+            currencies: currencies_from_env(defaults.currencies)?,
+            dispatch_queue_high_water_mark: env_or("WS_DISPATCH_QUEUE_HIGH_WATER_MARK", defaults.dispatch_queue_high_water_mark)?,
+            overload_retry_after: Duration::from_millis(
+                env_or("WS_OVERLOAD_RETRY_AFTER_MS", defaults.overload_retry_after.as_millis() as u64)?
+            ),
+            max_subscription_id_length: env_or("WS_MAX_SUBSCRIPTION_ID_LENGTH", defaults.max_subscription_id_length)?,
+            subscriber_counts: env_or("WS_SUBSCRIBER_COUNTS", defaults.subscriber_counts)?,
+            strict_messages: env_or("WS_STRICT_MESSAGES", defaults.strict_messages)?,
+            paused_buffer_size: env_or("WS_PAUSED_BUFFER_SIZE", defaults.paused_buffer_size)?,
+            invoice_uid_scheme: env_or("WS_INVOICE_UID_SCHEME", defaults.invoice_uid_scheme)?,
+            expand_payment_options: env_or("WS_EXPAND_PAYMENT_OPTIONS", defaults.expand_payment_options)?,
+            max_context_entries: env_or("WS_MAX_CONTEXT_ENTRIES", defaults.max_context_entries)?,
+            max_context_bytes: env_or("WS_MAX_CONTEXT_BYTES", defaults.max_context_bytes)?,
+            max_connection_lifetime: Duration::from_secs(
+                env_or("WS_MAX_CONNECTION_LIFETIME_SECS", defaults.max_connection_lifetime.as_secs())?
+            ),
+            store_backoff: BackoffPolicy {
+                retries: env_or("WS_STORE_RATE_LIMIT_RETRIES", defaults.store_backoff.retries)?,
+                max_wait: Duration::from_millis(
+                    env_or("WS_STORE_MAX_BACKOFF_MS", defaults.store_backoff.max_wait.as_millis() as u64)?
+                ),
+            },
+            recent_events_buffer: env_or("WS_RECENT_EVENTS_BUFFER", defaults.recent_events_buffer)?,
+            shutdown_drain_timeout: Duration::from_secs(
+                env_or("WS_SHUTDOWN_DRAIN_TIMEOUT_SECS", defaults.shutdown_drain_timeout.as_secs())?
+            ),
+            tolerate_schema_drift: env_or("WS_TOLERATE_SCHEMA_DRIFT", defaults.tolerate_schema_drift)?,
+            selftest_account_id: env_or("WS_SELFTEST_ACCOUNT_ID", defaults.selftest_account_id)?,
+            dedup_identical_events: env_or("WS_DEDUP_IDENTICAL_EVENTS", defaults.dedup_identical_events)?,
+            missed_event_retention: Duration::from_secs(
+                env_or("WS_MISSED_EVENT_RETENTION_SECS", defaults.missed_event_retention.as_secs())?
+            ),
+            confirmations_required: confirmations_from_env()?,
+            close_on_handler_panic: env_or("WS_CLOSE_ON_HANDLER_PANIC", defaults.close_on_handler_panic)?,
+            clock: defaults.clock,
+            subscription_events_per_second: env_or("WS_SUBSCRIPTION_EVENTS_PER_SECOND", defaults.subscription_events_per_second)?,
+            max_topics_per_dispatch: env_or("WS_MAX_TOPICS_PER_DISPATCH", defaults.max_topics_per_dispatch)?,
+        };
+
+        if config.default_envelope_version > crate::envelope::LATEST_VERSION {
+            return Err(anyhow!("Unsupported WS_DEFAULT_ENVELOPE_VERSION: {}", config.default_envelope_version));
+        }
+
+        if config.pagination.max_limit == 0 {
+            return Err(anyhow!("WS_MAX_PAGE_SIZE must be at least 1"));
+        }
+
+        Ok(config)
+    }
+}
+
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value.parse().map_err(|e| anyhow!("Invalid {}: {}", name, e)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Applies overrides from `WS_CURRENCY_DECIMALS_FILE`, a JSON object such as
+/// `{"PYUSD": 6}`, then from `WS_CURRENCY_DECIMALS`, e.g. `PYUSD:6,EURC:6`.
+fn currencies_from_env(table: CurrencyTable) -> Result<CurrencyTable> {
+    let table = match std::env::var("WS_CURRENCY_DECIMALS_FILE") {
+        Ok(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read WS_CURRENCY_DECIMALS_FILE {}: {}", path, e))?;
+            let overrides: HashMap<String, u32> = serde_json::from_str(&contents)
+                .map_err(|e| anyhow!("Invalid WS_CURRENCY_DECIMALS_FILE {}: {}", path, e))?;
+            table.with_overrides(overrides)
+                .map_err(|e| anyhow!("Invalid WS_CURRENCY_DECIMALS_FILE {}: {}", path, e))?
+        }
+        Err(_) => table,
+    };
+
+    let overrides = env_list::<String>("WS_CURRENCY_DECIMALS")?
+        .into_iter()
+        .map(|entry| match entry.split_once(':') {
+            Some((currency, places)) => places.trim().parse()
+                .map(|places| (currency.to_string(), places))
+                .map_err(|e| anyhow!("Invalid WS_CURRENCY_DECIMALS entry '{}': {}", entry, e)),
+            None => Err(anyhow!("Invalid WS_CURRENCY_DECIMALS entry '{}': expected CODE:places", entry)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    table.with_overrides(overrides).map_err(|e| anyhow!("Invalid WS_CURRENCY_DECIMALS: {}", e))
+}
+
+/// Reads `WS_CONFIRMATIONS_REQUIRED`, e.g. `BTC:6,ETH:12`.
+fn confirmations_from_env() -> Result<ConfirmationThresholds> {
+    let required = env_list::<String>("WS_CONFIRMATIONS_REQUIRED")?
+        .into_iter()
+        .map(|entry| match entry.split_once(':') {
+            Some((currency, count)) => count.trim().parse()
+                .map(|count| (currency.to_string(), count))
+                .map_err(|e| anyhow!("Invalid WS_CONFIRMATIONS_REQUIRED entry '{}': {}", entry, e)),
+            None => Err(anyhow!("Invalid WS_CONFIRMATIONS_REQUIRED entry '{}': expected CODE:count", entry)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ConfirmationThresholds::new(required))
+}
+
+/// Parses a comma-separated env var, treating an unset var as an empty list.
+fn env_list<T>(name: &str) -> Result<Vec<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse().map_err(|e| anyhow!("Invalid {} entry '{}': {}", name, item, e)))
+            .collect(),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pagination_limit() {
+        let pagination = PaginationConfig {
+            default_limit: 25,
+            max_limit: 100,
+        };
+
+        assert_eq!(pagination.limit(None), 25);
+        assert_eq!(pagination.limit(Some(10)), 10);
+        assert_eq!(pagination.limit(Some(1000)), 100);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 use tracing::{info, error, debug};
+use crate::event_bus::StoreEvent;
 use crate::supabase::SupabaseClient;
 use anyhow::anyhow;
 // Core types
@@ -80,14 +82,82 @@ pub struct BlockNotification {
     pub txids: Vec<String>,
 }
 
+/// Confirmations a payment needs before its invoice is marked paid, by
+/// currency. Currencies without an entry need one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfirmationThresholds {
+    required: HashMap<String, u32>,
+}
+
+impl ConfirmationThresholds {
+    pub fn new<I>(required: I) -> Self
+    where
+        I: IntoIterator<Item = (String, u32)>,
+    {
+        ConfirmationThresholds {
+            required: required.into_iter()
+                .map(|(currency, count)| (currency.trim().to_uppercase(), count.max(1)))
+                .collect(),
+        }
+    }
+
+    pub fn required(&self, currency: &str) -> u32 {
+        self.required.get(&currency.to_uppercase()).copied().unwrap_or(1)
+    }
+
+    /// The event announcing a payment in `currency` seen with `confirmations`,
+    /// or None once that is enough to mark the invoice paid.
+    pub fn pending_event(&self, invoice: &crate::types::Invoice, currency: &str, confirmations: u32) -> Option<StoreEvent> {
+        let required = self.required(currency);
+        (confirmations < required).then(|| StoreEvent::InvoiceConfirmation {
+            invoice: invoice.clone(),
+            confirmations,
+            required,
+        })
+    }
+}
+
+/// Applies `confirmation` to an unconfirmed `payment`. Short of the currency's
+/// threshold this announces the progress and returns None; otherwise the
+/// payment is confirmed, its invoice marked paid and the confirmed payment
+/// returned.
+pub async fn apply_confirmation(
+    supabase: &SupabaseClient,
+    thresholds: &ConfirmationThresholds,
+    payment: &Payment,
+    confirmation: &Confirmation,
+) -> Result<Option<Payment>> {
+    let confirmations = confirmation.confirmations.unwrap_or(1).max(0) as u32;
+    let required = thresholds.required(&payment.currency);
+    if confirmations < required {
+        let (invoice, _) = supabase.get_invoice(&payment.invoice_uid, true).await?.ok_or_else(|| anyhow!("Invoice not found"))?;
+        if let Some(event) = thresholds.pending_event(&invoice, &payment.currency, confirmations) {
+            debug!("Payment {} has {} of {} confirmations", payment.id, confirmations, required);
+            supabase.events().publish(event);
+        }
+        return Ok(None);
+    }
+
+    debug!("Updating payment record {}", payment.id);
+    let confirmed = supabase.confirm_payment(payment.clone(), confirmation.clone()).await?;
+    supabase.mark_paid(&payment.invoice_uid).await?;
+    Ok(Some(confirmed))
+}
+
 pub struct ConfirmationService {
     supabase: SupabaseClient,
     block_tx: broadcast::Sender<BlockNotification>,
+    thresholds: ConfirmationThresholds,
 }
 
 impl ConfirmationService {
     pub fn new(supabase: SupabaseClient, block_tx: broadcast::Sender<BlockNotification>) -> Self {
-        Self { supabase, block_tx }
+        Self { supabase, block_tx, thresholds: ConfirmationThresholds::default() }
+    }
+
+    pub fn with_thresholds(mut self, thresholds: ConfirmationThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
     }
 
     pub async fn confirm_payment(&self, payment: Payment, confirmation: Confirmation) -> Result<Payment> {
@@ -98,21 +168,14 @@ impl ConfirmationService {
             return Ok(payment);
         }
 
-        // Update payment record
-        debug!("Updating payment record {}", payment.id);
-        let updated_payment = self.supabase.update_payment(
-            payment.id,
-            &confirmation.confirmation_hash,
-            confirmation.confirmation_height,
-            &confirmation.confirmation_date,
-        ).await?;
+        // Short of the currency's threshold the payment is left unconfirmed
+        let Some(updated_payment) = apply_confirmation(&self.supabase, &self.thresholds, &payment, &confirmation).await? else {
+            return Ok(payment);
+        };
 
         // Get associated invoice
         let (invoice, _) = self.supabase.get_invoice(&payment.invoice_uid, true).await?.ok_or_else(|| anyhow!("Invoice not found"))?;
-        
         debug!("Found associated invoice {}", invoice.id);
-        // Update invoice status
-        self.supabase.update_invoice_status(&invoice.uid, "paid").await?;
 
         // Publish confirmation event
         let event = PaymentConfirmedEvent {
@@ -206,4 +269,4 @@ impl ConfirmationService {
         }
         Ok(())
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::{BigDecimal, RoundingMode, ToPrimitive};
+use serde::{Deserialize, Serialize};
+
+/// Decimal places of the minor unit for currencies known without configuration.
+const DEFAULT_DECIMALS: &[(&str, u32)] = &[
+    ("JPY", 0), ("KRW", 0),
+    ("USD", 2), ("EUR", 2), ("GBP", 2), ("CAD", 2), ("AUD", 2), ("CHF", 2), ("MXN", 2),
+    ("XRP", 6), ("USDC", 6), ("USDT", 6), ("RLUSD", 6),
+    ("BTC", 8), ("BCH", 8), ("BSV", 8), ("LTC", 8), ("DOGE", 8), ("DASH", 8),
+    ("SOL", 9),
+    ("ETH", 18), ("MATIC", 18), ("POL", 18), ("AVAX", 18), ("BNB", 18),
+];
+
+/// Most decimal places a currency may have before minor-unit amounts overflow.
+pub const MAX_DECIMALS: u32 = 18;
+
+/// Decimal places of the minor unit for each supported currency: the built-in
+/// table plus any entries the operator added or replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyTable {
+    decimals: HashMap<String, u32>,
+}
+
+impl Default for CurrencyTable {
+    fn default() -> Self {
+        CurrencyTable {
+            decimals: DEFAULT_DECIMALS.iter()
+                .map(|(currency, places)| (currency.to_string(), *places))
+                .collect(),
+        }
+    }
+}
+
+impl CurrencyTable {
+    pub fn decimals(&self, currency: &str) -> Option<u32> {
+        self.decimals.get(&currency.to_uppercase()).copied()
+    }
+
+    /// Adds or replaces entries, refusing any with more than `MAX_DECIMALS` places.
+    pub fn with_overrides<I>(mut self, overrides: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = (String, u32)>,
+    {
+        for (currency, places) in overrides {
+            let currency = currency.trim().to_uppercase();
+            if currency.is_empty() {
+                return Err("currency code is empty".to_string());
+            }
+            if places > MAX_DECIMALS {
+                return Err(format!("{} has {} decimal places, at most {} are supported", currency, places, MAX_DECIMALS));
+            }
+            self.decimals.insert(currency, places);
+        }
+        Ok(self)
+    }
+
+    /// Converts a client amount to minor units of `currency`, applying `policy`
+    /// when a decimal amount carries more places than the currency has. Either
+    /// form must come to a positive amount.
+    pub fn to_minor_units(&self, amount: &AmountInput, currency: &str, policy: AmountPrecisionPolicy) -> Result<i64, String> {
+        let minor = match amount {
+            AmountInput::MinorUnits(minor) => *minor,
+            AmountInput::Decimal(decimal) => self.decimal_to_minor_units(decimal, currency, policy)?,
+        };
+        if minor <= 0 {
+            return Err(format!("Amount must be greater than zero, got {}", minor));
+        }
+        Ok(minor)
+    }
+
+    fn decimal_to_minor_units(&self, decimal: &str, currency: &str, policy: AmountPrecisionPolicy) -> Result<i64, String> {
+
+        let places = self.decimals(currency)
+            .ok_or_else(|| format!("Unknown precision for currency {}, send amount in minor units", currency))?;
+        let value = BigDecimal::from_str(decimal.trim())
+            .map_err(|_| format!("Invalid amount '{}'", decimal))?;
+
+        let (_, scale) = value.normalized().as_bigint_and_exponent();
+        let value = if scale > places as i64 {
+            match policy {
+                AmountPrecisionPolicy::Reject => {
+                    return Err(format!(
+                        "Amount {} has {} decimal places but {} allows at most {}",
+                        decimal, scale, currency, places
+                    ));
+                }
+                AmountPrecisionPolicy::Round => value.with_scale_round(places as i64, RoundingMode::HalfEven),
+            }
+        } else {
+            value
+        };
+
+        // value is digits * 10^-scale with scale <= places, so the minor units
+        // are digits * 10^(places - scale)
+        let out_of_range = || format!("Amount {} is out of range", decimal);
+        let (digits, scale) = value.normalized().as_bigint_and_exponent();
+        let shift = u32::try_from(places as i64 - scale).map_err(|_| out_of_range())?;
+        digits.to_i64()
+            .zip(10i64.checked_pow(shift))
+            .and_then(|(digits, factor)| digits.checked_mul(factor))
+            .ok_or_else(out_of_range)
+    }
+
+    /// Formats a minor-unit amount in major units, e.g. `1250` USD as `"12.50"`.
+    pub fn format_minor_units(&self, amount: i64, currency: &str) -> Option<String> {
+        let places = self.decimals(currency)?;
+        Some(BigDecimal::new(BigInt::from(amount), places as i64).to_plain_string())
+    }
+}
+
+/// An invoice amount as sent by a client: an integer in minor units, or a
+/// decimal string in major units such as `"12.50"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AmountInput {
+    MinorUnits(i64),
+    Decimal(String),
+}
+
+/// What to do with a decimal amount that is more precise than its currency allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountPrecisionPolicy {
+    Reject,
+    /// Round half-even to the currency's precision.
+    Round,
+}
+
+/// How invoice amounts are written in responses and events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountRepresentation {
+    /// `amount` is an integer in minor units, with `amount_decimal` next to it.
+    MinorUnits,
+    /// `amount` is an exact decimal string in major units, e.g. `"0.10"`.
+    DecimalString,
+}
+
+impl FromStr for AmountRepresentation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minor_units" => Ok(AmountRepresentation::MinorUnits),
+            "decimal" => Ok(AmountRepresentation::DecimalString),
+            other => Err(format!("expected 'minor_units' or 'decimal', got '{}'", other)),
+        }
+    }
+}
+
+impl FromStr for AmountPrecisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(AmountPrecisionPolicy::Reject),
+            "round" => Ok(AmountPrecisionPolicy::Round),
+            other => Err(format!("expected 'reject' or 'round', got '{}'", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(amount: &str) -> AmountInput {
+        AmountInput::Decimal(amount.to_string())
+    }
+
+    fn to_minor_units(amount: &AmountInput, currency: &str, policy: AmountPrecisionPolicy) -> Result<i64, String> {
+        CurrencyTable::default().to_minor_units(amount, currency, policy)
+    }
+
+    #[test]
+    fn test_fiat_amount_with_crypto_precision_is_rejected() {
+        let err = to_minor_units(&decimal("0.00012345"), "USD", AmountPrecisionPolicy::Reject).unwrap_err();
+        assert_eq!(err, "Amount 0.00012345 has 8 decimal places but USD allows at most 2");
+
+        assert_eq!(to_minor_units(&decimal("10.005"), "USD", AmountPrecisionPolicy::Round), Ok(1000));
+    }
+
+    #[test]
+    fn test_matching_amount_and_currency() {
+        assert_eq!(to_minor_units(&decimal("12.50"), "USD", AmountPrecisionPolicy::Reject), Ok(1250));
+        assert_eq!(to_minor_units(&decimal("0.00012345"), "BTC", AmountPrecisionPolicy::Reject), Ok(12345));
+        assert_eq!(to_minor_units(&decimal("500"), "JPY", AmountPrecisionPolicy::Reject), Ok(500));
+        assert_eq!(to_minor_units(&AmountInput::MinorUnits(1000), "USD", AmountPrecisionPolicy::Reject), Ok(1000));
+        assert_eq!(to_minor_units(&decimal("5E+2"), "USD", AmountPrecisionPolicy::Reject), Ok(50000));
+    }
+
+    #[test]
+    fn test_amounts_must_be_positive_in_either_form() {
+        for amount in [AmountInput::MinorUnits(0), AmountInput::MinorUnits(-1000), decimal("0.00"), decimal("-12.50")] {
+            let err = to_minor_units(&amount, "USD", AmountPrecisionPolicy::Reject).unwrap_err();
+            assert!(err.starts_with("Amount must be greater than zero"), "{:?}: {}", amount, err);
+        }
+        // Rounding down to nothing is no amount either
+        assert!(to_minor_units(&decimal("0.001"), "USD", AmountPrecisionPolicy::Round).is_err());
+    }
+
+    #[test]
+    fn test_amounts_past_i64_minor_units_are_out_of_range() {
+        // 18 decimal places leave room for a little over 9.22 ETH
+        assert_eq!(to_minor_units(&decimal("9.2"), "ETH", AmountPrecisionPolicy::Reject), Ok(9_200_000_000_000_000_000));
+        for amount in ["9.3", "10", "92233720368547758070"] {
+            let err = to_minor_units(&decimal(amount), "ETH", AmountPrecisionPolicy::Reject).unwrap_err();
+            assert_eq!(err, format!("Amount {} is out of range", amount));
+        }
+        assert!(to_minor_units(&decimal("1E+30"), "USD", AmountPrecisionPolicy::Reject).is_err());
+    }
+
+    #[test]
+    fn test_overridden_decimals_are_used_to_create_and_format() {
+        let table = CurrencyTable::default()
+            .with_overrides([("pyusd".to_string(), 6), ("USD".to_string(), 3)])
+            .unwrap();
+
+        assert_eq!(table.decimals("PYUSD"), Some(6));
+        assert_eq!(table.to_minor_units(&decimal("12.5"), "PYUSD", AmountPrecisionPolicy::Reject), Ok(12_500_000));
+        assert_eq!(table.format_minor_units(12_500_000, "PYUSD"), Some("12.500000".to_string()));
+        assert_eq!(table.to_minor_units(&decimal("1.005"), "USD", AmountPrecisionPolicy::Reject), Ok(1005));
+        assert_eq!(table.format_minor_units(1005, "USD"), Some("1.005".to_string()));
+
+        // The built-in table knows neither the token nor the extra place
+        let default = CurrencyTable::default();
+        assert!(default.to_minor_units(&decimal("12.5"), "PYUSD", AmountPrecisionPolicy::Reject).is_err());
+        assert_eq!(default.format_minor_units(1250, "USD"), Some("12.50".to_string()));
+
+        assert!(CurrencyTable::default().with_overrides([("HUGE".to_string(), 19)]).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use serde_json::Value;
+use uuid::Uuid;
+use crate::types::Subscription;
+
+/// Why an event could not be delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeadLetterReason {
+    /// No session was subscribed to any of the event's topics.
+    NoSubscribers,
+    /// The session's channel was closed before the event could be queued.
+    SendFailed { session_id: Uuid, error: String },
+    /// The invoice had a status outside the known set and the policy rejects those.
+    UnknownStatus { status: String },
+}
+
+/// An event that did not reach one or more of its intended recipients.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub topics: Vec<Subscription>,
+    pub event: Value,
+    pub reason: DeadLetterReason,
+}
+
+/// Receives undeliverable events so operators can audit what was lost.
+pub trait DeadLetterSink: Send + Sync {
+    fn record(&self, letter: DeadLetter);
+}
+
+/// Writes dead letters to the log.
+pub struct LogDeadLetterSink;
+
+impl DeadLetterSink for LogDeadLetterSink {
+    fn record(&self, letter: DeadLetter) {
+        let topics: Vec<String> = letter.topics.iter()
+            .map(|topic| format!("{}:{}", topic.sub_type, topic.id))
+            .collect();
+        let event_type = letter.event.get("type").and_then(Value::as_str).unwrap_or("unknown");
+
+        match &letter.reason {
+            DeadLetterReason::NoSubscribers => {
+                tracing::info!("Dead letter: {} for [{}] had no subscribers", event_type, topics.join(", "));
+            }
+            DeadLetterReason::SendFailed { session_id, error } => {
+                tracing::warn!(
+                    "Dead letter: {} for [{}] not delivered to session {}: {}",
+                    event_type, topics.join(", "), session_id, error
+                );
+            }
+            DeadLetterReason::UnknownStatus { status } => {
+                tracing::warn!("Dead letter: {} for [{}] has unknown status '{}'", event_type, topics.join(", "), status);
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde_json::{json, Map, Value};
+
+/// Newest response envelope version understood by the server.
+///
+/// - v0: the original implicit envelope, `{"status": ..., "message"|"data": ...}`
+/// - v1: `{"v": 1, "status": ..., "data": ..., "error": ...}` with `data` and
+///   `error` always present (one of them null)
+pub const LATEST_VERSION: u8 = 1;
+
+/// Rewrites a v0 response into the envelope version the session negotiated.
+/// Anything that is not a JSON object is passed through unchanged.
+pub fn wrap(version: u8, response: Value) -> Value {
+    match (version, response) {
+        (0, response) => response,
+        (_, Value::Object(fields)) => to_v1(fields),
+        (_, response) => response,
+    }
+}
+
+fn to_v1(mut fields: Map<String, Value>) -> Value {
+    let status = fields.remove("status").unwrap_or_else(|| json!("success"));
+
+    if status == "error" {
+        return json!({
+            "v": 1,
+            "status": status,
+            "data": null,
+            "error": Value::Object(fields)
+        });
+    }
+
+    let data = match fields.remove("data") {
+        Some(data) => data,
+        None if fields.is_empty() => Value::Null,
+        None => Value::Object(fields),
+    };
+
+    json!({
+        "v": 1,
+        "status": status,
+        "data": data,
+        "error": null
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_fetch_response() {
+        let response = json!({
+            "status": "success",
+            "data": {
+                "invoice": { "uid": "inv_123", "status": "unpaid" },
+                "payment_options": []
+            }
+        });
+
+        assert_eq!(wrap(0, response.clone()), response);
+        assert_eq!(wrap(1, response), json!({
+            "v": 1,
+            "status": "success",
+            "data": {
+                "invoice": { "uid": "inv_123", "status": "unpaid" },
+                "payment_options": []
+            },
+            "error": null
+        }));
+    }
+
+    #[test]
+    fn test_wrap_error_response() {
+        let response = json!({
+            "status": "error",
+            "message": "Invoice not found"
+        });
+
+        assert_eq!(wrap(0, response.clone()), response);
+        assert_eq!(wrap(1, response), json!({
+            "v": 1,
+            "status": "error",
+            "data": null,
+            "error": { "message": "Invoice not found" }
+        }));
+    }
+}
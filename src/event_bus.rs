@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use crate::event_dispatcher::EventDispatcher;
+use crate::types::Invoice;
+
+/// A change written to the store. Published by `SupabaseClient` write methods
+/// so callers never need to dispatch events themselves.
+#[derive(Debug, Clone)]
+pub enum StoreEvent {
+    InvoiceCreated(Invoice),
+    InvoiceStatusChanged(Invoice),
+    /// A payment for the invoice was seen with fewer confirmations than its
+    /// currency requires, so the invoice is not paid yet.
+    InvoiceConfirmation { invoice: Invoice, confirmations: u32, required: u32 },
+}
+
+impl StoreEvent {
+    /// The client-facing event type, e.g. `invoice.paid`.
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            StoreEvent::InvoiceCreated(_) => "invoice.created",
+            StoreEvent::InvoiceStatusChanged(invoice) => match invoice.status.as_str() {
+                "paid" => "invoice.paid",
+                "cancelled" => "invoice.cancelled",
+                _ => "invoice.updated",
+            },
+            StoreEvent::InvoiceConfirmation { .. } => "invoice.confirmation",
+        }
+    }
+
+    pub fn invoice(&self) -> &Invoice {
+        match self {
+            StoreEvent::InvoiceCreated(invoice)
+            | StoreEvent::InvoiceStatusChanged(invoice)
+            | StoreEvent::InvoiceConfirmation { invoice, .. } => invoice,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<StoreEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        EventBus {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Publishes an event to every listener. Having no listeners is not an error.
+    pub fn publish(&self, event: StoreEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Events published but not yet taken by every listener.
+    pub fn depth(&self) -> usize {
+        self.sender.len()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(1024)
+    }
+}
+
+/// Translates store events into dispatches until the bus is closed.
+pub async fn forward_to_dispatcher(mut events: broadcast::Receiver<StoreEvent>, event_dispatcher: Arc<EventDispatcher>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let delivered = match &event {
+                    StoreEvent::InvoiceConfirmation { invoice, confirmations, required } => {
+                        event_dispatcher.dispatch_confirmation_event(invoice, *confirmations, *required).await
+                    }
+                    _ => event_dispatcher.dispatch_invoice_event(event.event_type(), event.invoice()).await,
+                };
+                tracing::debug!("Dispatched {} for {} to {} subscribers", event.event_type(), event.invoice().uid, delivered);
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!("Event bus listener lagged, {} store events were not dispatched", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use futures::StreamExt;
+    use crate::test_support;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_status_change_is_dispatched_without_explicit_call() {
+        let bus = EventBus::default();
+        let dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(session, "invoice", "inv_123").await;
+        tokio::spawn(forward_to_dispatcher(bus.subscribe(), dispatcher));
+
+        let invoice = test_support::invoice("inv_123", "paid");
+        bus.publish(StoreEvent::InvoiceStatusChanged(invoice));
+
+        let message = tokio::time::timeout(std::time::Duration::from_secs(1), receiver.next())
+            .await
+            .expect("event was not dispatched")
+            .unwrap();
+        let event: serde_json::Value = match message {
+            WsMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        assert_eq!(event["type"], "invoice.paid");
+        assert_eq!(event["data"]["uid"], "inv_123");
+    }
+}
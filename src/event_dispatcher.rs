@@ -1,30 +1,343 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use serde_json::json;
 use uuid::Uuid;
-use crate::types::Subscription;
+use crate::types::{project_event, Invoice, Subscription, SubscriptionFilter};
 use crate::session::Session;
+use crate::payment::generate_uid;
+use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink};
+use crate::rate_limit::TokenBucket;
+use crate::currency::{AmountRepresentation, CurrencyTable};
+use crate::invoices;
+
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub session: Session,
+    pub subscription_id: String,
+    pub filter: SubscriptionFilter,
+    /// Invoice fields to deliver, or None for whole events.
+    pub fields: Option<Vec<String>>,
+    // Hash of the last frame delivered, for suppressing repeats
+    last_delivered: Arc<std::sync::Mutex<Option<u64>>>,
+}
+
+impl Subscriber {
+    /// The event as this subscription delivers it.
+    fn project(&self, event: &serde_json::Value) -> Option<serde_json::Value> {
+        self.fields.as_ref().map(|fields| project_event(event, fields))
+    }
+
+    /// Records `text` as delivered, returning false if it repeats the last frame.
+    fn is_new(&self, text: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let hash = hasher.finish();
+        self.last_delivered.lock().unwrap().replace(hash) != Some(hash)
+    }
+}
+
+/// Events held for a paused subscription until it is resumed.
+#[derive(Debug)]
+struct PausedEvents {
+    events: VecDeque<serde_json::Value>,
+    capacity: usize,
+    dropped: usize,
+}
+
+impl PausedEvents {
+    /// Holds an event, dropping the oldest one once `capacity` are held.
+    fn hold(&mut self, event: serde_json::Value) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+}
+
+/// Events kept per topic for `recent_events` unless configured otherwise.
+pub const DEFAULT_RECENT_EVENTS: usize = 20;
+
+/// The admin topic announcing subscriptions as sessions add and remove them.
+pub const META_TOPIC: (&str, &str) = ("meta", "subscriptions");
+
+/// Invoice statuses the server announces unless configured otherwise.
+pub const KNOWN_INVOICE_STATUSES: [&str; 3] = ["unpaid", "paid", "cancelled"];
+
+/// What happens to an invoice event whose status isn't in the known set, such
+/// as a status the store added since this server was written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownStatusPolicy {
+    /// Dispatch it like any other status, as `invoice.updated`.
+    #[default]
+    PassThrough,
+    /// Keep it from subscribers and hand it to the dead letter sink.
+    Reject,
+}
+
+impl FromStr for UnknownStatusPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass_through" => Ok(UnknownStatusPolicy::PassThrough),
+            "reject" => Ok(UnknownStatusPolicy::Reject),
+            other => Err(format!("expected 'pass_through' or 'reject', got '{}'", other)),
+        }
+    }
+}
+
+/// Events after which an invoice changes no more, kept when nobody was subscribed.
+const TERMINAL_EVENTS: [&str; 2] = ["invoice.paid", "invoice.cancelled"];
 
 pub struct EventDispatcher {
-    subscriptions: RwLock<HashMap<Subscription, HashSet<Uuid>>>,
+    subscriptions: RwLock<HashMap<Subscription, HashMap<Uuid, Subscriber>>>,
+    // Server-assigned subscription ids, mapped back to the owning session and topic
+    subscription_ids: RwLock<HashMap<String, (Uuid, Subscription)>>,
+    dead_letters: std::sync::RwLock<Option<Arc<dyn DeadLetterSink>>>,
+    // Paused subscriptions by session. Held across a dispatch so a resume flush
+    // and new events can't interleave out of order.
+    paused: std::sync::Mutex<HashMap<Uuid, HashMap<Subscription, PausedEvents>>>,
+    // The latest events dispatched to each subscribed topic, oldest first
+    recent: std::sync::Mutex<HashMap<Subscription, VecDeque<serde_json::Value>>>,
+    recent_capacity: AtomicUsize,
+    // Terminal invoice events that reached no subscriber, with when they were dispatched
+    missed: std::sync::Mutex<HashMap<Subscription, Vec<(Instant, serde_json::Value)>>>,
+    missed_retention: std::sync::RwLock<Duration>,
+    // Bounds `subscription.*` events; None sends none
+    meta_limiter: std::sync::Mutex<Option<TokenBucket>>,
+    // Precision used to write invoice event amounts as decimal strings; None keeps minor units
+    decimal_amounts: std::sync::RwLock<Option<CurrencyTable>>,
+    known_statuses: std::sync::RwLock<HashSet<String>>,
+    unknown_status_policy: std::sync::RwLock<UnknownStatusPolicy>,
+    dedup_identical: AtomicBool,
+    // Topics resolved per chunk of a dispatch; 0 resolves them all at once
+    max_topics_per_dispatch: AtomicUsize,
+    // Held for a whole chunked dispatch, so events chunked apart still go out one at a time
+    fan_out: tokio::sync::Mutex<()>,
+}
+
+/// Progress of one dispatch across its chunks.
+#[derive(Default)]
+struct FanOut {
+    delivered: usize,
+    /// Sessions the event was sent to.
+    served: HashSet<Uuid>,
+    /// Sessions whose matching subscriptions have all been paused so far, with
+    /// the first paused topic and the event to hold for it. A later chunk may
+    /// still reach them through an active one.
+    held: HashMap<Uuid, (Session, Subscription, serde_json::Value)>,
+    filtered_out: bool,
 }
 
 impl EventDispatcher {
     pub fn new() -> Self {
         EventDispatcher {
             subscriptions: RwLock::new(HashMap::new()),
+            subscription_ids: RwLock::new(HashMap::new()),
+            dead_letters: std::sync::RwLock::new(None),
+            paused: std::sync::Mutex::new(HashMap::new()),
+            recent: std::sync::Mutex::new(HashMap::new()),
+            recent_capacity: AtomicUsize::new(DEFAULT_RECENT_EVENTS),
+            missed: std::sync::Mutex::new(HashMap::new()),
+            missed_retention: std::sync::RwLock::new(Duration::ZERO),
+            meta_limiter: std::sync::Mutex::new(None),
+            decimal_amounts: std::sync::RwLock::new(None),
+            known_statuses: std::sync::RwLock::new(KNOWN_INVOICE_STATUSES.iter().map(|status| status.to_string()).collect()),
+            unknown_status_policy: std::sync::RwLock::new(UnknownStatusPolicy::default()),
+            dedup_identical: AtomicBool::new(false),
+            max_topics_per_dispatch: AtomicUsize::new(0),
+            fan_out: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Routes undeliverable events to `sink`. Without one they are dropped.
+    pub fn set_dead_letter_sink(&self, sink: Arc<dyn DeadLetterSink>) {
+        *self.dead_letters.write().unwrap() = Some(sink);
+    }
+
+    pub fn dead_letter_sink(&self) -> Option<Arc<dyn DeadLetterSink>> {
+        self.dead_letters.read().unwrap().clone()
+    }
+
+    /// Keeps the last `capacity` events of each subscribed topic. 0 keeps none.
+    pub fn set_recent_events_capacity(&self, capacity: usize) {
+        self.recent_capacity.store(capacity, Ordering::SeqCst);
+        if capacity == 0 {
+            self.recent.lock().unwrap().clear();
         }
     }
 
-    pub async fn subscribe(&self, session: Session, sub_type: &str, id: &str) {
+    /// Keeps paid and cancelled events for invoices nobody was subscribed to
+    /// for `retention`, so `recent_events` can return them to a client that
+    /// subscribes later. Zero keeps none.
+    pub fn set_missed_event_retention(&self, retention: Duration) {
+        *self.missed_retention.write().unwrap() = retention;
+        if retention.is_zero() {
+            self.missed.lock().unwrap().clear();
+        }
+    }
+
+    /// Sends `subscription.added` and `subscription.removed` to the meta
+    /// topic, at most `per_second` of them; the rest are dropped. 0 sends none.
+    pub fn set_subscription_event_rate(&self, per_second: u32) {
+        *self.meta_limiter.lock().unwrap() = (per_second > 0)
+            .then(|| TokenBucket::new(per_second, Duration::from_secs(1) / per_second));
+    }
+
+    /// Writes the `amount` of invoices in events as an exact decimal string in
+    /// major units, using the precision in `currencies`. None keeps minor units.
+    pub fn set_decimal_invoice_amounts(&self, currencies: Option<CurrencyTable>) {
+        *self.decimal_amounts.write().unwrap() = currencies;
+    }
+
+    /// Sets the invoice statuses known to subscribers, and what happens to
+    /// events for invoices in any other status.
+    pub fn set_unknown_status_policy(&self, known: HashSet<String>, policy: UnknownStatusPolicy) {
+        *self.known_statuses.write().unwrap() = known;
+        *self.unknown_status_policy.write().unwrap() = policy;
+    }
+
+    fn invoice_data(&self, invoice: &Invoice) -> serde_json::Value {
+        match self.decimal_amounts.read().unwrap().as_ref() {
+            Some(currencies) => invoices::with_decimal_amount(json!(invoice), currencies, AmountRepresentation::DecimalString),
+            None => json!(invoice),
+        }
+    }
+
+    /// Tells meta subscribers a session started or stopped watching `topic`.
+    /// Meta and log subscriptions aren't announced.
+    async fn announce(&self, event_type: &str, session_id: Uuid, subscription_id: &str, topic: &Subscription) {
+        if topic.sub_type == META_TOPIC.0 || topic.sub_type == "logs" {
+            return;
+        }
+        let meta = Subscription {
+            sub_type: META_TOPIC.0.to_string(),
+            id: META_TOPIC.1.to_string(),
+        };
+        // Checked first so announcements nobody watches don't spend the budget
+        if !self.subscriptions.read().await.contains_key(&meta) {
+            return;
+        }
+        let allowed = self.meta_limiter.lock().unwrap()
+            .as_mut()
+            .is_some_and(|limiter| limiter.try_acquire().is_ok());
+        if !allowed {
+            tracing::debug!("Dropping {} for {}:{} over the rate limit", event_type, topic.sub_type, topic.id);
+            return;
+        }
+        self.dispatch(&meta, &json!({
+            "type": event_type,
+            "data": {
+                "session_id": session_id,
+                "subscription_id": subscription_id,
+                "topic": { "type": topic.sub_type, "id": topic.id }
+            }
+        })).await;
+    }
+
+    /// Whether an event identical to the last one delivered on a subscription
+    /// is dropped instead of sent again. Off by default.
+    pub fn set_dedup_identical_events(&self, enabled: bool) {
+        self.dedup_identical.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Resolves at most `limit` topics of a dispatch at a time, letting other
+    /// work on the dispatcher run between chunks. 0 resolves them all at once.
+    pub fn set_max_topics_per_dispatch(&self, limit: usize) {
+        self.max_topics_per_dispatch.store(limit, Ordering::SeqCst);
+    }
+
+    fn dead_letter(&self, topics: &[Subscription], event: &serde_json::Value, reason: DeadLetterReason) {
+        if let Some(sink) = self.dead_letters.read().unwrap().as_ref() {
+            sink.record(DeadLetter {
+                topics: topics.to_vec(),
+                event: event.clone(),
+                reason,
+            });
+        }
+    }
+
+    /// Subscribes the session to a topic and returns the subscription id.
+    /// Subscribing twice to the same topic returns the existing id.
+    pub async fn subscribe(&self, session: Session, sub_type: &str, id: &str) -> String {
+        self.subscribe_filtered(session, sub_type, id, SubscriptionFilter::default()).await
+    }
+
+    /// Like `subscribe`, delivering only events that match `filter`. An existing
+    /// subscription keeps its filter; use `update_filter` to change it.
+    pub async fn subscribe_filtered(&self, session: Session, sub_type: &str, id: &str, filter: SubscriptionFilter) -> String {
+        self.subscribe_projected(session, sub_type, id, filter, None).await
+    }
+
+    /// Like `subscribe_filtered`, delivering only the given fields of invoice
+    /// events. Projections are validated by the caller.
+    pub async fn subscribe_projected(
+        &self,
+        session: Session,
+        sub_type: &str,
+        id: &str,
+        filter: SubscriptionFilter,
+        fields: Option<Vec<String>>,
+    ) -> String {
         let subscription = Subscription {
             sub_type: sub_type.to_string(),
             id: id.to_string(),
         };
-        
+
         let mut subs = self.subscriptions.write().await;
-        subs.entry(subscription)
-            .or_insert_with(HashSet::new)
-            .insert(session.id);
+        let subscribers = subs.entry(subscription.clone())
+            .or_insert_with(HashMap::new);
+
+        if let Some(existing) = subscribers.get(&session.id) {
+            return existing.subscription_id.clone();
+        }
+
+        let subscription_id = format!("sub_{}", generate_uid());
+        let session_id = session.id;
+        self.subscription_ids.write().await
+            .insert(subscription_id.clone(), (session.id, subscription.clone()));
+        subscribers.insert(session.id, Subscriber {
+            session,
+            subscription_id: subscription_id.clone(),
+            filter,
+            fields,
+            last_delivered: Arc::default(),
+        });
+        drop(subs);
+
+        self.announce("subscription.added", session_id, &subscription_id, &subscription).await;
+        subscription_id
+    }
+
+    /// Swaps the filter of an existing subscription. The subscription stays in
+    /// place, so no event is missed across the change. Returns false if the
+    /// session isn't subscribed.
+    pub async fn update_filter(&self, session: &Session, sub_type: &str, id: &str, filter: SubscriptionFilter) -> bool {
+        let subscription = Subscription {
+            sub_type: sub_type.to_string(),
+            id: id.to_string(),
+        };
+        match self.subscriptions.write().await
+            .get_mut(&subscription)
+            .and_then(|subscribers| subscribers.get_mut(&session.id))
+        {
+            Some(subscriber) => {
+                subscriber.filter = filter;
+                true
+            }
+            None => false,
+        }
     }
 
     pub async fn unsubscribe(&self, session: Session, sub_type: &str, id: &str) {
@@ -32,22 +345,1197 @@ impl EventDispatcher {
             sub_type: sub_type.to_string(),
             id: id.to_string(),
         };
-        
+
         let mut subs = self.subscriptions.write().await;
+        let mut removed = None;
+        if let Some(sessions) = subs.get_mut(&subscription) {
+            if let Some(subscriber) = sessions.remove(&session.id) {
+                self.subscription_ids.write().await.remove(&subscriber.subscription_id);
+                removed = Some(subscriber.subscription_id);
+            }
+            if sessions.is_empty() {
+                subs.remove(&subscription);
+                self.recent.lock().unwrap().remove(&subscription);
+            }
+        }
+        drop(subs);
+        self.forget_paused(session.id, &subscription);
+
+        if let Some(subscription_id) = removed {
+            self.announce("subscription.removed", session.id, &subscription_id, &subscription).await;
+        }
+    }
+
+    /// Removes a subscription by its server-assigned id. Returns the topic that
+    /// was unsubscribed, or None if the id is unknown or owned by another session.
+    pub async fn unsubscribe_by_id(&self, session: &Session, subscription_id: &str) -> Option<Subscription> {
+        let mut subs = self.subscriptions.write().await;
+        let mut ids = self.subscription_ids.write().await;
+
+        let subscription = match ids.get(subscription_id) {
+            Some((session_id, subscription)) if *session_id == session.id => subscription.clone(),
+            _ => return None,
+        };
+        ids.remove(subscription_id);
+
         if let Some(sessions) = subs.get_mut(&subscription) {
             sessions.remove(&session.id);
             if sessions.is_empty() {
                 subs.remove(&subscription);
+                self.recent.lock().unwrap().remove(&subscription);
+            }
+        }
+        drop(ids);
+        drop(subs);
+        self.forget_paused(session.id, &subscription);
+
+        self.announce("subscription.removed", session.id, subscription_id, &subscription).await;
+        Some(subscription)
+    }
+
+    /// Drops every subscription held by a session. Called when the connection closes.
+    pub async fn remove_session(&self, session_id: Uuid) {
+        let mut subs = self.subscriptions.write().await;
+        let mut ids = self.subscription_ids.write().await;
+
+        let mut removed = Vec::new();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            subs.retain(|topic, subscribers| {
+                if let Some(subscriber) = subscribers.remove(&session_id) {
+                    ids.remove(&subscriber.subscription_id);
+                    removed.push((subscriber.subscription_id, topic.clone()));
+                }
+                if subscribers.is_empty() {
+                    recent.remove(topic);
+                }
+                !subscribers.is_empty()
+            });
+        }
+        drop(ids);
+        drop(subs);
+        self.paused.lock().unwrap().remove(&session_id);
+
+        for (subscription_id, topic) in removed {
+            self.announce("subscription.removed", session_id, &subscription_id, &topic).await;
+        }
+    }
+
+    fn forget_paused(&self, session_id: Uuid, subscription: &Subscription) {
+        let mut paused = self.paused.lock().unwrap();
+        if let Some(topics) = paused.get_mut(&session_id) {
+            topics.remove(subscription);
+            if topics.is_empty() {
+                paused.remove(&session_id);
+            }
+        }
+    }
+
+    /// Withholds the session's events for a topic, holding up to `capacity` of
+    /// them for `resume`. Returns false if the session isn't subscribed.
+    pub async fn pause(&self, session: &Session, sub_type: &str, id: &str, capacity: usize) -> bool {
+        let subscription = Subscription {
+            sub_type: sub_type.to_string(),
+            id: id.to_string(),
+        };
+        // Keep the read lock until the pause is recorded, so an unsubscribe
+        // racing this can't leave a pause behind for a topic it removed
+        let subs = self.subscriptions.read().await;
+        let subscribed = subs
+            .get(&subscription)
+            .is_some_and(|subscribers| subscribers.contains_key(&session.id));
+        if subscribed {
+            self.paused.lock().unwrap()
+                .entry(session.id)
+                .or_default()
+                .entry(subscription)
+                .or_insert_with(|| PausedEvents { events: VecDeque::new(), capacity, dropped: 0 });
+        }
+        subscribed
+    }
+
+    /// Resumes a paused topic, sending the events held for it. Returns how many
+    /// were sent and how many were dropped, or None if it wasn't paused.
+    pub fn resume(&self, session: &Session, sub_type: &str, id: &str) -> Option<(usize, usize)> {
+        let subscription = Subscription {
+            sub_type: sub_type.to_string(),
+            id: id.to_string(),
+        };
+        let mut paused = self.paused.lock().unwrap();
+        let topics = paused.get_mut(&session.id)?;
+        let held = topics.remove(&subscription)?;
+        if topics.is_empty() {
+            paused.remove(&session.id);
+        }
+
+        let mut sent = 0;
+        for event in held.events {
+            // Sequence numbers are taken now, so they stay in delivery order
+            if session.send_event(&subscription, &event, &event.to_string()).is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        Some((sent, held.dropped))
+    }
+
+    fn remember<'a>(&self, topics: impl Iterator<Item = &'a Subscription>, event: &serde_json::Value) {
+        let capacity = self.recent_capacity.load(Ordering::SeqCst);
+        if capacity == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        for topic in topics {
+            let events = recent.entry(topic.clone()).or_default();
+            if events.len() >= capacity {
+                events.pop_front();
+            }
+            events.push_back(event.clone());
+        }
+    }
+
+    fn remember_missed(&self, topics: &[Subscription], event: &serde_json::Value) {
+        let retention = *self.missed_retention.read().unwrap();
+        let is_terminal = event.get("type")
+            .and_then(serde_json::Value::as_str)
+            .is_some_and(|event_type| TERMINAL_EVENTS.contains(&event_type));
+        if retention.is_zero() || !is_terminal {
+            return;
+        }
+        let mut missed = self.missed.lock().unwrap();
+        missed.retain(|_, events| {
+            events.retain(|(dispatched_at, _)| dispatched_at.elapsed() < retention);
+            !events.is_empty()
+        });
+        // Account and tag topics see many invoices, so only the invoice's own topic keeps it
+        for topic in topics.iter().filter(|topic| topic.sub_type == "invoice") {
+            missed.entry(topic.clone()).or_default().push((Instant::now(), event.clone()));
+        }
+    }
+
+    /// Up to `limit` of the latest events dispatched to a topic the session is
+    /// subscribed to, oldest first and passed through its filter and projection. None if the
+    /// session isn't subscribed. Live delivery is unaffected. Missed terminal
+    /// events still within their retention come first.
+    pub async fn recent_events(&self, session: &Session, sub_type: &str, id: &str, limit: usize) -> Option<Vec<serde_json::Value>> {
+        let subscription = Subscription {
+            sub_type: sub_type.to_string(),
+            id: id.to_string(),
+        };
+        let subs = self.subscriptions.read().await;
+        let subscriber = subs.get(&subscription)?.get(&session.id)?;
+
+        let retention = *self.missed_retention.read().unwrap();
+        let missed = self.missed.lock().unwrap();
+        let missed_events = missed.get(&subscription)
+            .into_iter()
+            .flatten()
+            .filter(|(dispatched_at, _)| dispatched_at.elapsed() < retention)
+            .map(|(_, event)| event);
+        let recent = self.recent.lock().unwrap();
+        let mut events: Vec<serde_json::Value> = missed_events
+            .chain(recent.get(&subscription).into_iter().flatten())
+            .rev()
+            .filter(|event| subscriber.filter.matches(event))
+            .take(limit)
+            .map(|event| subscriber.project(event).unwrap_or_else(|| event.clone()))
+            .collect();
+        events.reverse();
+        Some(events)
+    }
+
+    /// Sends an event to every session subscribed to the topic and returns the
+    /// number of sessions it was handed to.
+    pub async fn dispatch(&self, subscription: &Subscription, event: &serde_json::Value) -> usize {
+        self.dispatch_to_topics(std::slice::from_ref(subscription), event).await
+    }
+
+    /// Sends an event to the subscribers of several topics. A session subscribed
+    /// to more than one of them receives the event once. It is held instead if
+    /// every one of the session's matching subscriptions is paused.
+    ///
+    /// Sending only queues the frame on the session's channel; each connection's
+    /// forward task writes it out, so a stalled client never delays another.
+    ///
+    /// With a topic limit set, topics are resolved that many at a time and the
+    /// dispatcher's locks are released between chunks, so an event for a large
+    /// account doesn't hold up subscribes and other sessions meanwhile. Other
+    /// events still wait for it, keeping each topic in order.
+    pub async fn dispatch_to_topics(&self, topics: &[Subscription], event: &serde_json::Value) -> usize {
+        let text = event.to_string();
+        let limit = self.max_topics_per_dispatch.load(Ordering::SeqCst);
+        let _in_order = match limit {
+            0 => None,
+            _ => Some(self.fan_out.lock().await),
+        };
+        let chunk_size = match limit {
+            0 => topics.len().max(1),
+            limit => limit,
+        };
+
+        let mut fan_out = FanOut::default();
+        for (i, chunk) in topics.chunks(chunk_size).enumerate() {
+            if i > 0 {
+                tokio::task::yield_now().await;
+            }
+            let subs = self.subscriptions.read().await;
+            self.dispatch_chunk(&subs, chunk, topics, event, &text, &mut fan_out);
+        }
+        self.hold_for_paused(topics, event, &mut fan_out);
+
+        // Events every subscriber filtered out were not wanted, not lost
+        if fan_out.served.is_empty() && !fan_out.filtered_out {
+            self.dead_letter(topics, event, DeadLetterReason::NoSubscribers);
+            self.remember_missed(topics, event);
+        }
+
+        fan_out.delivered
+    }
+
+    /// Delivers an event to the subscribers of `chunk`, one of the slices of
+    /// `topics` being dispatched, skipping sessions an earlier chunk served.
+    fn dispatch_chunk(
+        &self,
+        subs: &HashMap<Subscription, HashMap<Uuid, Subscriber>>,
+        chunk: &[Subscription],
+        topics: &[Subscription],
+        event: &serde_json::Value,
+        text: &str,
+        fan_out: &mut FanOut,
+    ) {
+        let paused = self.paused.lock().unwrap();
+        let dedup = self.dedup_identical.load(Ordering::SeqCst);
+        self.remember(chunk.iter().filter(|topic| subs.contains_key(*topic)), event);
+
+        // Each session once, with the topic it is delivered through, or the
+        // paused topic to hold it for if the session has no active one
+        let mut targets: Vec<(&Subscriber, &Subscription, bool)> = Vec::new();
+        let mut index: HashMap<Uuid, usize> = HashMap::new();
+        for topic in chunk {
+            for subscriber in subs.get(topic).into_iter().flat_map(|subscribers| subscribers.values()) {
+                if !subscriber.filter.matches(event) {
+                    fan_out.filtered_out = true;
+                    continue;
+                }
+                if fan_out.served.contains(&subscriber.session.id) {
+                    continue;
+                }
+                let is_paused = paused.get(&subscriber.session.id).is_some_and(|held| held.contains_key(topic));
+                match index.get(&subscriber.session.id) {
+                    Some(&i) if !is_paused && targets[i].2 => (targets[i].1, targets[i].2) = (topic, false),
+                    Some(_) => {}
+                    None if is_paused && fan_out.held.contains_key(&subscriber.session.id) => {}
+                    None => {
+                        index.insert(subscriber.session.id, targets.len());
+                        targets.push((subscriber, topic, is_paused));
+                    }
+                }
+            }
+        }
+        for (subscriber, _, is_paused) in &targets {
+            if !is_paused {
+                fan_out.served.insert(subscriber.session.id);
+                fan_out.held.remove(&subscriber.session.id);
+            }
+        }
+
+        for (subscriber, topic, is_paused) in &targets {
+            // A session subscribed through several topics gets the projection of the first
+            let projected = subscriber.project(event).map(|projected| {
+                let text = projected.to_string();
+                (projected, text)
+            });
+            let (sent, sent_text) = projected.as_ref().map_or((event, text), |(projected, text)| (projected, text.as_str()));
+
+            if *is_paused {
+                fan_out.held.insert(subscriber.session.id, (subscriber.session.clone(), (*topic).clone(), sent.clone()));
+                continue;
+            }
+            // The text is compared before any seq is added, so repeats still match
+            if dedup && !subscriber.is_new(sent_text) {
+                tracing::debug!("Dropping repeated event for session {}", subscriber.session.id);
+                continue;
+            }
+            // The paused lock is held across sends, so each session's frames are
+            // built and queued in the same order
+            match subscriber.session.send_event(topic, sent, sent_text) {
+                Ok(()) => fan_out.delivered += 1,
+                Err(e) => {
+                    tracing::debug!("Failed to deliver event to session {}: {}", subscriber.session.id, e);
+                    self.dead_letter(topics, event, DeadLetterReason::SendFailed {
+                        session_id: subscriber.session.id,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Holds the event for the sessions no chunk found an active subscription
+    /// for, once every chunk has been dispatched.
+    fn hold_for_paused(&self, topics: &[Subscription], event: &serde_json::Value, fan_out: &mut FanOut) {
+        let mut paused = self.paused.lock().unwrap();
+        for (session_id, (session, topic, sent)) in fan_out.held.drain() {
+            fan_out.served.insert(session_id);
+            match paused.get_mut(&session_id).and_then(|held| held.get_mut(&topic)) {
+                Some(held) => held.hold(sent),
+                // Resumed between chunks, so it is sent like any other
+                None => match session.send_event(&topic, &sent, &sent.to_string()) {
+                    Ok(()) => fan_out.delivered += 1,
+                    Err(e) => {
+                        tracing::debug!("Failed to deliver event to session {}: {}", session_id, e);
+                        self.dead_letter(topics, event, DeadLetterReason::SendFailed {
+                            session_id,
+                            error: e.to_string(),
+                        });
+                    }
+                },
             }
         }
     }
 
+    /// Dispatches an `invoice.*` event to subscribers of the invoice, of the
+    /// account it belongs to, and of each of its tags.
+    /// An invoice in a status outside the known set is dispatched or rejected
+    /// according to the unknown status policy.
+    pub async fn dispatch_invoice_event(&self, event_type: &str, invoice: &Invoice) -> usize {
+        let topics = invoice_topics(invoice);
+        let event = json!({
+            "type": event_type,
+            "data": self.invoice_data(invoice)
+        });
+
+        if !self.known_statuses.read().unwrap().contains(&invoice.status) {
+            match *self.unknown_status_policy.read().unwrap() {
+                UnknownStatusPolicy::PassThrough => {
+                    tracing::debug!("Passing through unknown status '{}' for {}", invoice.status, invoice.uid);
+                }
+                UnknownStatusPolicy::Reject => {
+                    tracing::warn!("Rejecting {} for {}: unknown status '{}'", event_type, invoice.uid, invoice.status);
+                    self.dead_letter(&topics, &event, DeadLetterReason::UnknownStatus {
+                        status: invoice.status.clone(),
+                    });
+                    return 0;
+                }
+            }
+        }
+        self.dispatch_to_topics(&topics, &event).await
+    }
+
+    /// Dispatches `invoice.confirmation` for a payment still short of the
+    /// confirmations its currency requires, to the same topics as other invoice events.
+    pub async fn dispatch_confirmation_event(&self, invoice: &Invoice, confirmations: u32, required: u32) -> usize {
+        self.dispatch_to_topics(&invoice_topics(invoice), &json!({
+            "type": "invoice.confirmation",
+            "data": self.invoice_data(invoice),
+            "confirmation": {
+                "count": confirmations,
+                "required": required
+            }
+        })).await
+    }
+
+    /// Sends an `account.event` to subscribers of the account and of the given
+    /// invoices, which the caller has resolved as belonging to that account.
+    pub async fn dispatch_account_event(&self, account_id: i64, invoice_uids: &[String], event: &serde_json::Value) -> usize {
+        let mut topics = vec![Subscription {
+            sub_type: "account".to_string(),
+            id: account_id.to_string(),
+        }];
+        topics.extend(invoice_uids.iter().map(|uid| Subscription {
+            sub_type: "invoice".to_string(),
+            id: uid.clone(),
+        }));
+
+        self.dispatch_to_topics(&topics, &json!({
+            "type": "account.event",
+            "data": {
+                "account_id": account_id,
+                "event": event
+            }
+        })).await
+    }
+
+    /// Ids of every topic of `sub_type` that has at least one subscriber.
+    pub async fn subscribed_ids(&self, sub_type: &str) -> Vec<String> {
+        self.subscriptions.read().await
+            .iter()
+            .filter(|(subscription, subscribers)| subscription.sub_type == sub_type && !subscribers.is_empty())
+            .map(|(subscription, _)| subscription.id.clone())
+            .collect()
+    }
+
+    /// Every session's subscriptions with their subscription ids.
+    pub async fn subscriptions_by_session(&self) -> HashMap<Uuid, Vec<(Subscription, String)>> {
+        let mut by_session: HashMap<Uuid, Vec<(Subscription, String)>> = HashMap::new();
+        for (subscription_id, (session_id, subscription)) in self.subscription_ids.read().await.iter() {
+            by_session.entry(*session_id)
+                .or_default()
+                .push((subscription.clone(), subscription_id.clone()));
+        }
+        by_session
+    }
+
     pub async fn get_subscribers(&self, subscription: &Subscription) -> HashSet<Uuid> {
         self.subscriptions
             .read()
             .await
             .get(subscription)
-            .cloned()
+            .map(|subscribers| subscribers.keys().cloned().collect())
             .unwrap_or_default()
     }
-} 
\ No newline at end of file
+}
+
+/// Views of the dispatcher's internal state for assertions in tests.
+#[cfg(test)]
+impl EventDispatcher {
+    /// Every topic with at least one subscriber, sorted.
+    pub async fn topics(&self) -> Vec<(String, String)> {
+        let mut topics: Vec<(String, String)> = self.subscriptions.read().await
+            .iter()
+            .filter(|(_, subscribers)| !subscribers.is_empty())
+            .map(|(topic, _)| (topic.sub_type.clone(), topic.id.clone()))
+            .collect();
+        topics.sort();
+        topics
+    }
+
+    pub async fn subscriber_count(&self, sub_type: &str, id: &str) -> usize {
+        self.get_subscribers(&Subscription {
+            sub_type: sub_type.to_string(),
+            id: id.to_string(),
+        }).await.len()
+    }
+
+    /// A session's topics by subscription id, sorted.
+    pub async fn session_subscriptions(&self, session_id: Uuid) -> Vec<(String, String, String)> {
+        let mut subscriptions: Vec<(String, String, String)> = self.subscription_ids.read().await
+            .iter()
+            .filter(|(_, (owner, _))| *owner == session_id)
+            .map(|(subscription_id, (_, topic))| (subscription_id.clone(), topic.sub_type.clone(), topic.id.clone()))
+            .collect();
+        subscriptions.sort();
+        subscriptions
+    }
+
+    /// Number of sessions with at least one paused subscription.
+    pub fn paused_sessions(&self) -> usize {
+        self.paused.lock().unwrap().len()
+    }
+}
+
+/// Topics that receive an invoice's events. Tags are scoped to the account as
+/// `"<account_id>:<tag>"` so merchants using the same tag don't see each other's invoices.
+fn invoice_topics(invoice: &Invoice) -> Vec<Subscription> {
+    let mut topics = vec![
+        Subscription {
+            sub_type: "invoice".to_string(),
+            id: invoice.uid.clone(),
+        },
+        Subscription {
+            sub_type: "account".to_string(),
+            id: invoice.account_id.to_string(),
+        },
+    ];
+    topics.extend(invoice.tags.iter().map(|tag| Subscription {
+        sub_type: "tag".to_string(),
+        id: format!("{}:{}", invoice.account_id, tag),
+    }));
+    topics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    fn test_session() -> Session {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        Session::new(Uuid::new_v4(), sender)
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_by_subscription_id() {
+        let dispatcher = EventDispatcher::new();
+        let session = test_session();
+        let subscription = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+
+        let subscription_id = dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        assert!(subscription_id.starts_with("sub_"));
+        assert_eq!(dispatcher.subscribe(session.clone(), "invoice", "inv_123").await, subscription_id);
+        assert!(dispatcher.get_subscribers(&subscription).await.contains(&session.id));
+
+        // Another session cannot use the id
+        assert!(dispatcher.unsubscribe_by_id(&test_session(), &subscription_id).await.is_none());
+
+        let removed = dispatcher.unsubscribe_by_id(&session, &subscription_id).await;
+        assert_eq!(removed, Some(subscription.clone()));
+        assert!(dispatcher.get_subscribers(&subscription).await.is_empty());
+        assert!(dispatcher.unsubscribe_by_id(&session, &subscription_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_account_subscribers_receive_invoice_events_once() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let invoice = test_support::invoice("inv_123", "paid");
+
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await, 1);
+
+        // Also subscribing to the invoice itself must not duplicate the event
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await, 1);
+
+        let mut received = 0;
+        while let Ok(WsMessage::Text(text)) = receiver.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(event["type"], "invoice.paid");
+            assert_eq!(event["data"]["uid"], "inv_123");
+            received += 1;
+        }
+        assert_eq!(received, 2);
+
+        dispatcher.remove_session(session.id).await;
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_events_can_carry_decimal_string_amounts() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "invoice", "inv_123").await;
+        let invoice = Invoice { amount: 10, ..test_support::invoice("inv_123", "paid") };
+        let amount = |receiver: &mut futures::channel::mpsc::UnboundedReceiver<WsMessage>| {
+            let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+                panic!("expected an event");
+            };
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()["data"]["amount"].clone()
+        };
+
+        dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await;
+        assert_eq!(amount(&mut receiver), json!(10));
+
+        dispatcher.set_decimal_invoice_amounts(Some(CurrencyTable::default()));
+        dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await;
+        assert_eq!(amount(&mut receiver), json!("0.10"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_invoice_status_follows_policy() {
+        let dispatcher = EventDispatcher::new();
+        let sink = Arc::new(CollectingSink::default());
+        dispatcher.set_dead_letter_sink(sink.clone());
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "invoice", "inv_123").await;
+        let invoice = test_support::invoice("inv_123", "refunded");
+
+        // Passed through by default
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.updated", &invoice).await, 1);
+        let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+            panic!("expected an event");
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap()["data"]["status"], "refunded");
+
+        let known = KNOWN_INVOICE_STATUSES.iter().map(|status| status.to_string()).collect();
+        dispatcher.set_unknown_status_policy(known, UnknownStatusPolicy::Reject);
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.updated", &invoice).await, 0);
+        assert!(receiver.try_recv().is_err());
+        let letters = sink.0.lock().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].reason, DeadLetterReason::UnknownStatus { status: "refunded".to_string() });
+    }
+
+    #[tokio::test]
+    async fn test_tag_subscribers_receive_tagged_invoice_events() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(session.clone(), "tag", "42:pos").await;
+
+        let invoice = |uid: &str, account_id: i64, tags: &[&str]| Invoice {
+            account_id,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..test_support::invoice(uid, "unpaid")
+        };
+
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.created", &invoice("inv_pos", 42, &["pos", "store-1"])).await, 1);
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.created", &invoice("inv_online", 42, &["online"])).await, 0);
+        // Same tag on another account's invoice
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.created", &invoice("inv_other", 43, &["pos"])).await, 0);
+
+        match receiver.try_recv() {
+            Ok(WsMessage::Text(text)) => {
+                let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(event["data"]["uid"], "inv_pos");
+            }
+            other => panic!("expected one event, got {:?}", other),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[derive(Default)]
+    struct CollectingSink(std::sync::Mutex<Vec<DeadLetter>>);
+
+    impl DeadLetterSink for CollectingSink {
+        fn record(&self, letter: DeadLetter) {
+            self.0.lock().unwrap().push(letter);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_undeliverable_events_reach_dead_letter_sink() {
+        let dispatcher = EventDispatcher::new();
+        let sink = Arc::new(CollectingSink::default());
+        dispatcher.set_dead_letter_sink(sink.clone());
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let event = json!({ "type": "invoice.paid", "data": { "uid": "inv_123" } });
+
+        assert_eq!(dispatcher.dispatch(&topic, &event).await, 0);
+
+        // A subscriber whose connection is gone
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        drop(receiver);
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        assert_eq!(dispatcher.dispatch(&topic, &event).await, 0);
+
+        let letters = sink.0.lock().unwrap();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].reason, DeadLetterReason::NoSubscribers);
+        assert_eq!(letters[0].topics, vec![topic.clone()]);
+        assert_eq!(letters[0].event, event);
+        assert!(matches!(&letters[1].reason, DeadLetterReason::SendFailed { session_id, .. } if *session_id == session.id));
+    }
+
+    #[tokio::test]
+    async fn test_account_event_reaches_account_and_invoice_subscribers_once() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut both_rx) = futures::channel::mpsc::unbounded();
+        let both = Session::new(Uuid::new_v4(), sender);
+        let (sender, mut invoice_rx) = futures::channel::mpsc::unbounded();
+        let invoice_only = Session::new(Uuid::new_v4(), sender);
+        let (sender, mut other_rx) = futures::channel::mpsc::unbounded();
+        let other_account = Session::new(Uuid::new_v4(), sender);
+
+        dispatcher.subscribe(both.clone(), "account", "42").await;
+        dispatcher.subscribe(both.clone(), "invoice", "inv_1").await;
+        dispatcher.subscribe(invoice_only.clone(), "invoice", "inv_2").await;
+        dispatcher.subscribe(other_account.clone(), "invoice", "inv_other").await;
+
+        let mut subscribed = dispatcher.subscribed_ids("invoice").await;
+        subscribed.sort();
+        assert_eq!(subscribed, vec!["inv_1", "inv_2", "inv_other"]);
+
+        let invoice_uids = vec!["inv_1".to_string(), "inv_2".to_string()];
+        let event = json!({ "kind": "settings.updated" });
+        assert_eq!(dispatcher.dispatch_account_event(42, &invoice_uids, &event).await, 2);
+
+        for receiver in [&mut both_rx, &mut invoice_rx] {
+            let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+                panic!("expected the account event");
+            };
+            let received: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(received["type"], "account.event");
+            assert_eq!(received["data"]["account_id"], 42);
+            assert_eq!(received["data"]["event"], event);
+            assert!(receiver.try_recv().is_err());
+        }
+        assert!(other_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_large_account_event_lets_subscribes_through_between_chunks() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.set_max_topics_per_dispatch(10);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let invoice_uids: Vec<String> = (0..1000).map(|i| format!("inv_{}", i)).collect();
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+        for uid in invoice_uids.iter().step_by(100) {
+            dispatcher.subscribe(session.clone(), "invoice", uid).await;
+        }
+        let other = test_session();
+
+        let finished = AtomicBool::new(false);
+        let (delivered, subscribed_before_finish) = tokio::join!(
+            async {
+                let delivered = dispatcher.dispatch_account_event(42, &invoice_uids, &json!({ "type": "notice" })).await;
+                finished.store(true, Ordering::SeqCst);
+                delivered
+            },
+            async {
+                dispatcher.subscribe(other.clone(), "invoice", "inv_5").await;
+                !finished.load(Ordering::SeqCst)
+            }
+        );
+
+        assert!(subscribed_before_finish);
+        // Reached through the account and ten invoices, the session still gets one copy
+        assert_eq!(delivered, 1);
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_paused_subscription_withholds_events_until_resumed() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let event = |n: u32| json!({ "type": "invoice.updated", "data": { "n": n } });
+
+        assert!(!dispatcher.pause(&session, "invoice", "inv_123", 2).await);
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        assert!(dispatcher.pause(&session, "invoice", "inv_123", 2).await);
+
+        for n in 1..=3 {
+            assert_eq!(dispatcher.dispatch(&topic, &event(n)).await, 0);
+        }
+        assert!(receiver.try_recv().is_err());
+
+        // The oldest event is dropped past the buffer size
+        assert_eq!(dispatcher.resume(&session, "invoice", "inv_123"), Some((2, 1)));
+        for n in 2..=3 {
+            let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+                panic!("expected held event {}", n);
+            };
+            let received: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(received, event(n));
+        }
+
+        assert_eq!(dispatcher.dispatch(&topic, &event(4)).await, 1);
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(dispatcher.resume(&session, "invoice", "inv_123"), None);
+    }
+
+    #[tokio::test]
+    async fn test_active_subscription_delivers_past_a_paused_one() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let invoice_topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let account_topic = Subscription {
+            sub_type: "account".to_string(),
+            id: "42".to_string(),
+        };
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+        dispatcher.pause(&session, "invoice", "inv_123", 10).await;
+
+        let event = json!({ "type": "invoice.paid" });
+        assert_eq!(dispatcher.dispatch_to_topics(&[invoice_topic, account_topic], &event).await, 1);
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(dispatcher.resume(&session, "invoice", "inv_123"), Some((0, 0)));
+    }
+
+    #[tokio::test]
+    async fn test_active_subscription_in_a_later_chunk_delivers_past_a_paused_one() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.set_max_topics_per_dispatch(1);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let invoice_topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let account_topic = Subscription {
+            sub_type: "account".to_string(),
+            id: "42".to_string(),
+        };
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+        dispatcher.pause(&session, "invoice", "inv_123", 10).await;
+
+        let event = json!({ "type": "invoice.paid" });
+        assert_eq!(dispatcher.dispatch_to_topics(&[invoice_topic.clone(), account_topic], &event).await, 1);
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(dispatcher.resume(&session, "invoice", "inv_123"), Some((0, 0)));
+
+        // With no active subscription in any chunk, the event is held
+        dispatcher.unsubscribe(session.clone(), "account", "42").await;
+        dispatcher.pause(&session, "invoice", "inv_123", 10).await;
+        assert_eq!(dispatcher.dispatch_to_topics(&[invoice_topic], &event).await, 0);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(dispatcher.resume(&session, "invoice", "inv_123"), Some((1, 0)));
+    }
+
+    #[tokio::test]
+    async fn test_session_order_numbers_events_across_topics() {
+        let dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.delivery_order = crate::session::DeliveryOrder::Session;
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+
+        // Both topics fire at once
+        let dispatches = ["invoice", "account"].map(|sub_type| {
+            let dispatcher = dispatcher.clone();
+            let id = if sub_type == "invoice" { "inv_123" } else { "42" };
+            tokio::spawn(async move {
+                let topic = Subscription {
+                    sub_type: sub_type.to_string(),
+                    id: id.to_string(),
+                };
+                for n in 0..5 {
+                    dispatcher.dispatch(&topic, &json!({ "type": sub_type, "n": n })).await;
+                }
+            })
+        });
+        for dispatch in dispatches {
+            dispatch.await.unwrap();
+        }
+
+        let mut seqs = Vec::new();
+        let mut per_topic: HashMap<String, Vec<u64>> = HashMap::new();
+        while let Ok(WsMessage::Text(text)) = receiver.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            seqs.push(event["seq"].as_u64().unwrap());
+            per_topic.entry(event["type"].as_str().unwrap().to_string())
+                .or_default()
+                .push(event["n"].as_u64().unwrap());
+        }
+        assert_eq!(seqs, (1..=10).collect::<Vec<u64>>());
+        assert_eq!(per_topic["invoice"], vec![0, 1, 2, 3, 4]);
+        assert_eq!(per_topic["account"], vec![0, 1, 2, 3, 4]);
+
+        // Sessions on the default policy get events unchanged
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let other = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(other, "account", "42").await;
+        let topic = Subscription {
+            sub_type: "account".to_string(),
+            id: "42".to_string(),
+        };
+        dispatcher.dispatch(&topic, &json!({ "type": "account" })).await;
+        let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+            panic!("expected an event");
+        };
+        assert_eq!(text, json!({ "type": "account" }).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_state_after_subscribe_and_unsubscribe() {
+        let dispatcher = EventDispatcher::new();
+        let first = test_session();
+        let second = test_session();
+
+        let invoice_id = dispatcher.subscribe(first.clone(), "invoice", "inv_123").await;
+        let account_id = dispatcher.subscribe(first.clone(), "account", "42").await;
+        dispatcher.subscribe(second.clone(), "invoice", "inv_123").await;
+        dispatcher.pause(&first, "account", "42", 10).await;
+
+        assert_eq!(dispatcher.topics().await, vec![
+            ("account".to_string(), "42".to_string()),
+            ("invoice".to_string(), "inv_123".to_string()),
+        ]);
+        assert_eq!(dispatcher.subscriber_count("invoice", "inv_123").await, 2);
+        let mut expected = vec![
+            (invoice_id.clone(), "invoice".to_string(), "inv_123".to_string()),
+            (account_id, "account".to_string(), "42".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(dispatcher.session_subscriptions(first.id).await, expected);
+        assert_eq!(dispatcher.paused_sessions(), 1);
+
+        dispatcher.unsubscribe(first.clone(), "account", "42").await;
+        assert_eq!(dispatcher.subscriber_count("account", "42").await, 0);
+        assert_eq!(dispatcher.paused_sessions(), 0);
+        assert_eq!(dispatcher.session_subscriptions(first.id).await, vec![
+            (invoice_id, "invoice".to_string(), "inv_123".to_string()),
+        ]);
+
+        dispatcher.remove_session(second.id).await;
+        assert_eq!(dispatcher.subscriber_count("invoice", "inv_123").await, 1);
+        assert!(dispatcher.session_subscriptions(second.id).await.is_empty());
+
+        dispatcher.remove_session(first.id).await;
+        assert!(dispatcher.topics().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_session_does_not_delay_others() {
+        let dispatcher = Arc::new(EventDispatcher::new());
+        // Nothing ever reads the stalled session's frames
+        let (stalled_sender, _stalled_receiver) = futures::channel::mpsc::unbounded();
+        let stalled = Session::new(Uuid::new_v4(), stalled_sender);
+        let (fast_sender, mut fast_receiver) = futures::channel::mpsc::unbounded();
+        let fast = Session::new(Uuid::new_v4(), fast_sender);
+        dispatcher.subscribe(stalled.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(fast, "invoice", "inv_123").await;
+
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        for n in 0..1000 {
+            let event = json!({ "type": "invoice.updated", "n": n });
+            let delivered = tokio::time::timeout(
+                std::time::Duration::from_millis(100),
+                dispatcher.dispatch(&topic, &event),
+            ).await.expect("dispatch waited on the stalled session");
+            assert_eq!(delivered, 2);
+
+            let Ok(WsMessage::Text(text)) = fast_receiver.try_recv() else {
+                panic!("fast session did not receive event {} right away", n);
+            };
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(), event);
+        }
+        assert_eq!(stalled.send_queue.queued(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_filter_change_keeps_the_subscription_without_a_gap() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.delivery_order = crate::session::DeliveryOrder::Session;
+        let topic = Subscription {
+            sub_type: "account".to_string(),
+            id: "42".to_string(),
+        };
+        let paid_only = SubscriptionFilter {
+            events: vec!["invoice.paid".to_string()],
+            statuses: Vec::new(),
+        };
+        let subscription_id = dispatcher.subscribe_filtered(session.clone(), "account", "42", paid_only).await;
+
+        let paid = json!({ "type": "invoice.paid", "data": { "status": "paid" } });
+        let updated = json!({ "type": "invoice.updated", "data": { "status": "unpaid" } });
+        dispatcher.dispatch(&topic, &paid).await;
+        assert_eq!(dispatcher.dispatch(&topic, &updated).await, 0);
+
+        // Widen the filter mid-stream
+        assert!(dispatcher.update_filter(&session, "account", "42", SubscriptionFilter::default()).await);
+        dispatcher.dispatch(&topic, &updated).await;
+        dispatcher.dispatch(&topic, &paid).await;
+        assert_eq!(dispatcher.session_subscriptions(session.id).await[0].0, subscription_id);
+
+        let mut received = Vec::new();
+        while let Ok(WsMessage::Text(text)) = receiver.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            received.push((event["seq"].as_u64().unwrap(), event["type"].as_str().unwrap().to_string()));
+        }
+        assert_eq!(received, vec![
+            (1, "invoice.paid".to_string()),
+            (2, "invoice.updated".to_string()),
+            (3, "invoice.paid".to_string()),
+        ]);
+
+        assert!(!dispatcher.update_filter(&test_session(), "account", "42", SubscriptionFilter::default()).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_racing_subscribe_and_unsubscribe_leave_consistent_state() {
+        let dispatcher = Arc::new(EventDispatcher::new());
+        let session = test_session();
+
+        let tasks: Vec<_> = (0..64).map(|n| {
+            let dispatcher = dispatcher.clone();
+            let session = session.clone();
+            tokio::spawn(async move {
+                match n % 4 {
+                    0 => { dispatcher.subscribe(session, "invoice", "inv_123").await; }
+                    1 => dispatcher.unsubscribe(session, "invoice", "inv_123").await,
+                    2 => { dispatcher.pause(&session, "invoice", "inv_123", 10).await; }
+                    _ => { dispatcher.resume(&session, "invoice", "inv_123"); }
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Whatever order they ran in, every index agrees with the others
+        let subscribed = dispatcher.subscriber_count("invoice", "inv_123").await;
+        assert!(subscribed <= 1);
+        assert_eq!(dispatcher.session_subscriptions(session.id).await.len(), subscribed);
+        if subscribed == 0 {
+            assert_eq!(dispatcher.paused_sessions(), 0);
+        }
+
+        // and a final unsubscribe always ends with nothing left
+        dispatcher.unsubscribe(session.clone(), "invoice", "inv_123").await;
+        assert!(dispatcher.topics().await.is_empty());
+        assert!(dispatcher.session_subscriptions(session.id).await.is_empty());
+        assert_eq!(dispatcher.paused_sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recent_events_returns_the_latest_in_order() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.set_recent_events_capacity(4);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let event = |n: u32| json!({ "type": "invoice.updated", "data": { "n": n } });
+
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 10).await, None);
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        for n in 1..=6 {
+            dispatcher.dispatch(&topic, &event(n)).await;
+        }
+
+        // Only the buffer's worth is kept
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 10).await, Some(vec![event(3), event(4), event(5), event(6)]));
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 2).await, Some(vec![event(5), event(6)]));
+
+        // Reading them doesn't send anything
+        let mut live = 0;
+        while receiver.try_recv().is_ok() {
+            live += 1;
+        }
+        assert_eq!(live, 6);
+
+        // The buffer goes with the topic's last subscriber
+        dispatcher.unsubscribe(session.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 10).await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_paid_event_without_subscribers_is_kept_for_later_clients() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.set_missed_event_retention(Duration::from_secs(60));
+        let topics = [
+            Subscription { sub_type: "invoice".to_string(), id: "inv_123".to_string() },
+            Subscription { sub_type: "account".to_string(), id: "42".to_string() },
+        ];
+        let paid = json!({ "type": "invoice.paid", "data": { "uid": "inv_123", "status": "paid" } });
+
+        assert_eq!(dispatcher.dispatch_to_topics(&topics, &json!({ "type": "invoice.updated" })).await, 0);
+        assert_eq!(dispatcher.dispatch_to_topics(&topics, &paid).await, 0);
+
+        // A client connecting afterwards can still fetch it
+        let session = test_session();
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        dispatcher.subscribe(session.clone(), "account", "42").await;
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 10).await, Some(vec![paid.clone()]));
+        assert_eq!(dispatcher.recent_events(&session, "account", "42", 10).await, Some(Vec::new()));
+
+        dispatcher.set_missed_event_retention(Duration::ZERO);
+        assert_eq!(dispatcher.recent_events(&session, "invoice", "inv_123", 10).await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_meta_subscribers_see_other_sessions_subscribe() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.set_subscription_event_rate(2);
+        let (sender, mut admin_receiver) = futures::channel::mpsc::unbounded();
+        let admin = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(admin.clone(), META_TOPIC.0, META_TOPIC.1).await;
+        let client = test_session();
+
+        let subscription_id = dispatcher.subscribe(client.clone(), "invoice", "inv_123").await;
+        let Ok(WsMessage::Text(text)) = admin_receiver.try_recv() else {
+            panic!("expected a meta event");
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["type"], "subscription.added");
+        assert_eq!(event["data"]["session_id"], client.id.to_string());
+        assert_eq!(event["data"]["subscription_id"], subscription_id.as_str());
+        assert_eq!(event["data"]["topic"], json!({ "type": "invoice", "id": "inv_123" }));
+
+        dispatcher.remove_session(client.id).await;
+        let Ok(WsMessage::Text(text)) = admin_receiver.try_recv() else {
+            panic!("expected a meta event");
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap()["type"], "subscription.removed");
+
+        // Over the rate, announcements are dropped
+        dispatcher.subscribe(client.clone(), "invoice", "inv_456").await;
+        assert!(admin_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_projected_subscription_receives_only_requested_fields() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let (full_sender, mut full_receiver) = futures::channel::mpsc::unbounded();
+        let full = Session::new(Uuid::new_v4(), full_sender);
+        let fields = vec!["uid".to_string(), "status".to_string()];
+        dispatcher.subscribe_projected(session.clone(), "invoice", "inv_123", SubscriptionFilter::default(), Some(fields)).await;
+        dispatcher.subscribe(full.clone(), "invoice", "inv_123").await;
+
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        dispatcher.dispatch(&topic, &json!({
+            "type": "invoice.paid",
+            "data": { "uid": "inv_123", "status": "paid", "amount": 1000, "memo": "coffee" }
+        })).await;
+
+        let WsMessage::Text(text) = receiver.try_recv().unwrap() else {
+            panic!("expected a text frame");
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event, json!({ "type": "invoice.paid", "data": { "uid": "inv_123", "status": "paid" } }));
+
+        // Other subscribers of the topic still get the whole event
+        let WsMessage::Text(text) = full_receiver.try_recv().unwrap() else {
+            panic!("expected a text frame");
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["data"]["memo"], "coffee");
+
+        assert!(crate::types::validate_projection(&["uid".to_string()]).is_ok());
+        assert!(crate::types::validate_projection(&["uuid".to_string()]).is_err());
+        assert!(crate::types::validate_projection(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_identical_consecutive_events_are_coalesced_when_enabled() {
+        let dispatcher = EventDispatcher::new();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(session.clone(), "invoice", "inv_123").await;
+        let topic = Subscription {
+            sub_type: "invoice".to_string(),
+            id: "inv_123".to_string(),
+        };
+        let updated = json!({ "type": "invoice.updated", "data": { "uid": "inv_123", "status": "unpaid" } });
+        let paid = json!({ "type": "invoice.paid", "data": { "uid": "inv_123", "status": "paid" } });
+        let received = |receiver: &mut futures::channel::mpsc::UnboundedReceiver<WsMessage>| {
+            let mut received = 0;
+            while receiver.try_recv().is_ok() {
+                received += 1;
+            }
+            received
+        };
+
+        // Off by default
+        dispatcher.dispatch(&topic, &updated).await;
+        dispatcher.dispatch(&topic, &updated).await;
+        assert_eq!(received(&mut receiver), 2);
+
+        dispatcher.set_dedup_identical_events(true);
+        dispatcher.dispatch(&topic, &paid).await;
+        assert_eq!(dispatcher.dispatch(&topic, &paid).await, 0);
+        assert_eq!(received(&mut receiver), 1);
+
+        // Only an immediate repeat is dropped
+        dispatcher.dispatch(&topic, &updated).await;
+        dispatcher.dispatch(&topic, &paid).await;
+        assert_eq!(received(&mut receiver), 2);
+    }
+}
@@ -28,6 +28,8 @@ pub struct CreateInvoiceRequest {
     location_id: Option<String>,
     register_id: Option<String>,
     required_fee_rate: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -58,7 +60,7 @@ impl HttpServer {
             .route("/api/v1/prices", get({
                 let supabase = supabase.clone();
                 move |_: ()| async move {
-                    match supabase.list_prices().await {
+                    match supabase.list_prices(None).await {
                         Ok(prices) => Ok(Json(PricesResponse { prices })),
                         Err(e) => {
                             tracing::error!("Error listing prices: {}", e);
@@ -74,6 +76,7 @@ impl HttpServer {
                 move |Path(invoice_id): Path<String>| async move {
                     tracing::info!("Fetching invoice with id: {}", invoice_id);
                     match supabase.get_invoice(&invoice_id, true).await {
+                        Ok(Some((invoice, _))) if invoice.is_deleted() => Err(StatusCode::GONE),
                         Ok(Some(result)) => {
                             tracing::info!("Invoice fetched successfully: {:?}", result);
                             Ok(Json(InvoiceResponse { invoice: result.0, payment_options: result.1 }))
@@ -93,7 +96,8 @@ impl HttpServer {
                     payload.account_id,  // TODO: Get real account_id
                     payload.webhook_url,
                     payload.redirect_url,
-                    payload.memo
+                    payload.memo,
+                    payload.tags
                 ).await {
                     Ok(response) => {
                         let data = response.as_object().unwrap();
@@ -1,18 +1,25 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::event_bus::StoreEvent;
 use crate::supabase::SupabaseClient;
 use crate::types::{Invoice, PaymentOption};
 use serde_json::json;
 use chrono::Utc;
 use crate::payment::generate_uid;
+use crate::currency::{AmountRepresentation, CurrencyTable};
+use crate::uri::payment_uri;
 
 pub async fn create_invoice(
     supabase: &SupabaseClient,
     amount: i64,
     currency: &str,
-    account_id: i32,
+    account_id: i64,
     webhook_url: Option<String>,
     redirect_url: Option<String>,
     memo: Option<String>,
-) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    tags: Vec<String>,
+) -> anyhow::Result<serde_json::Value> {
     let now = Utc::now().to_rfc3339();
     let invoice_uid = format!("inv_{}", generate_uid());
 
@@ -20,7 +27,7 @@ pub async fn create_invoice(
         "uid": invoice_uid,
         "amount": amount,
         "currency": currency,
-        "account_id": account_id as i64,
+        "account_id": account_id,
         "status": "unpaid",
         "createdAt": now,
         "updatedAt": now,
@@ -37,16 +44,260 @@ pub async fn create_invoice(
     if let Some(text) = &memo {
         data["memo"] = json!(text);
     }
+    if !tags.is_empty() {
+        data["tags"] = json!(tags);
+    }
 
     // Create invoice in Supabase
     let response = supabase.create_invoice(
         amount,
         currency,
-        account_id as i64,
+        account_id,
         webhook_url,
         redirect_url,
-        memo
+        memo,
+        tags
     ).await?;
 
     Ok(response)
-} 
\ No newline at end of file
+}
+
+/// Adds `payment_uris` to a created invoice's response, one per payment option
+/// that has a standard wallet URI, so clients can show them without a fetch.
+pub fn with_payment_uris(mut created: serde_json::Value, currencies: &CurrencyTable, representation: AmountRepresentation) -> serde_json::Value {
+    let options: Vec<PaymentOption> = serde_json::from_value(created["payment_options"].clone()).unwrap_or_default();
+    let uris: Vec<serde_json::Value> = options.iter()
+        .filter_map(|option| {
+            let uri = payment_uri(&option.currency, &option.chain, &option.address, option.amount, currencies)?;
+            Some(json!({
+                "currency": option.currency,
+                "chain": option.chain,
+                "address": option.address,
+                "amount": option.amount,
+                "uri": uri
+            }))
+        })
+        .collect();
+    created["payment_uris"] = json!(uris);
+    created["payable_currencies"] = json!(payable_currencies(&options));
+    created["invoice"] = with_decimal_amount(created["invoice"].take(), currencies, representation);
+    created
+}
+
+/// The currencies an invoice can be paid in, once each in the order of its
+/// payment options, e.g. USDC offered on two chains is listed once.
+pub fn payable_currencies(options: &[PaymentOption]) -> Vec<String> {
+    let mut currencies: Vec<String> = Vec::new();
+    for option in options {
+        if !currencies.contains(&option.currency) {
+            currencies.push(option.currency.clone());
+        }
+    }
+    currencies
+}
+
+/// Adds `amount_decimal`, the amount in major units as a string such as
+/// `"0.10"`, and the currency's `decimals` next to an invoice's minor-unit
+/// `amount`. With `DecimalString` the string replaces `amount` instead.
+/// Invoices in currencies of unknown precision are left as they are.
+pub fn with_decimal_amount(mut invoice: serde_json::Value, currencies: &CurrencyTable, representation: AmountRepresentation) -> serde_json::Value {
+    let (Some(amount), Some(currency)) = (invoice["amount"].as_i64(), invoice["currency"].as_str()) else {
+        return invoice;
+    };
+    if let (Some(decimal), Some(decimals)) = (currencies.format_minor_units(amount, currency), currencies.decimals(currency)) {
+        match representation {
+            AmountRepresentation::MinorUnits => invoice["amount_decimal"] = json!(decimal),
+            AmountRepresentation::DecimalString => invoice["amount"] = json!(decimal),
+        }
+        invoice["decimals"] = json!(decimals);
+    }
+    invoice
+}
+
+/// Resolves the account an invoice should be created for. Sessions create for
+/// their own account unless one is given; only admins may name another account.
+/// Returns None when the requested account is invalid or not permitted.
+pub fn resolve_invoice_account(session_account_id: i32, requested: Option<i64>, is_admin: bool) -> Option<i64> {
+    match requested {
+        None => Some(session_account_id as i64),
+        Some(id) if id <= 0 => None,
+        Some(id) if id == session_account_id as i64 || is_admin => Some(id),
+        Some(_) => None,
+    }
+}
+
+/// Marks a test-mode invoice paid after `delay`, so integrators can exercise
+/// payment handling without funds. Subscribers get `invoice.paid` via the event bus.
+pub fn schedule_test_payment(supabase: Arc<SupabaseClient>, invoice: Invoice, delay: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        match supabase.mark_paid(&invoice.uid).await {
+            Ok(()) => tracing::info!("Auto-paid test invoice {}", invoice.uid),
+            Err(e) => tracing::error!("Failed to auto-pay test invoice {}: {}", invoice.uid, e),
+        }
+    });
+}
+
+/// Waits for the invoice to be paid or cancelled, returning it in its final
+/// state, or None if `timeout` elapses first. Subscribe `events` before
+/// checking the invoice's current status so no change is missed in between.
+pub async fn wait_for_settlement(
+    mut events: broadcast::Receiver<StoreEvent>,
+    uid: &str,
+    timeout: Duration,
+) -> Option<Invoice> {
+    let wait = async {
+        loop {
+            match events.recv().await {
+                Ok(StoreEvent::InvoiceStatusChanged(invoice))
+                    if invoice.uid == uid && matches!(invoice.status.as_str(), "paid" | "cancelled") =>
+                {
+                    return Some(invoice);
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, wait).await.ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{AmountInput, AmountPrecisionPolicy};
+    use crate::event_bus::EventBus;
+    use crate::test_support::invoice;
+
+    #[tokio::test]
+    async fn test_wait_for_settlement_returns_on_payment() {
+        let bus = EventBus::default();
+        let waiter = tokio::spawn(wait_for_settlement(bus.subscribe(), "inv_123", Duration::from_secs(1)));
+
+        bus.publish(StoreEvent::InvoiceStatusChanged(invoice("inv_other", "paid")));
+        bus.publish(StoreEvent::InvoiceStatusChanged(invoice("inv_123", "unpaid")));
+        bus.publish(StoreEvent::InvoiceStatusChanged(invoice("inv_123", "paid")));
+
+        let paid = waiter.await.unwrap().expect("payment was not observed");
+        assert_eq!(paid.uid, "inv_123");
+        assert_eq!(paid.status, "paid");
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_settlement_times_out() {
+        let bus = EventBus::default();
+        let result = wait_for_settlement(bus.subscribe(), "inv_123", Duration::from_millis(20)).await;
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_resolve_invoice_account() {
+        assert_eq!(resolve_invoice_account(7, None, false), Some(7));
+        assert_eq!(resolve_invoice_account(7, Some(7), false), Some(7));
+
+        // Nonexistent ids are rejected before hitting the store
+        assert_eq!(resolve_invoice_account(7, Some(0), false), None);
+        assert_eq!(resolve_invoice_account(7, Some(-3), true), None);
+
+        // Another account's id needs admin access
+        assert_eq!(resolve_invoice_account(7, Some(8), false), None);
+        assert_eq!(resolve_invoice_account(7, Some(8), true), Some(8));
+    }
+
+    #[test]
+    fn test_created_invoice_carries_payment_uris() {
+        let option = |currency: &str, address: &str, amount: i64| json!({
+            "invoice_uid": "inv_123",
+            "currency": currency,
+            "chain": currency,
+            "amount": amount,
+            "address": address,
+            "outputs": [{ "address": address, "amount": amount }],
+            "uri": format!("anypay:{}_inv_123", currency.to_lowercase()),
+            "fee": 0,
+            "createdAt": "2024-01-01T12:00:00Z",
+            "updatedAt": "2024-01-01T12:00:00Z",
+            "expires": "2024-01-01T12:15:00Z"
+        });
+        let created = json!({
+            "invoice": invoice("inv_123", "unpaid"),
+            "payment_options": [
+                option("BTC", "1BoatSLRHtKNngkdXEeobR76b53LETtpyT", 2500),
+                option("XRP", "rDsbeomae4FXwgQTJp9Rs64Qg9vDiTCdBv", 1000000)
+            ]
+        });
+
+        let data = with_payment_uris(created, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert_eq!(data["payment_uris"], json!([{
+            "currency": "BTC",
+            "chain": "BTC",
+            "address": "1BoatSLRHtKNngkdXEeobR76b53LETtpyT",
+            "amount": 2500,
+            "uri": "bitcoin:1BoatSLRHtKNngkdXEeobR76b53LETtpyT?amount=0.00002500"
+        }]));
+        assert_eq!(data["payment_options"].as_array().unwrap().len(), 2);
+        assert_eq!(data["payable_currencies"], json!(["BTC", "XRP"]));
+        assert_eq!(data["invoice"]["amount_decimal"], "10.00");
+    }
+
+    #[test]
+    fn test_multi_coin_invoice_lists_each_payable_currency_once() {
+        let option = |currency: &str, chain: &str| -> PaymentOption {
+            serde_json::from_value(json!({
+                "invoice_uid": "inv_123",
+                "currency": currency,
+                "chain": chain,
+                "amount": 1000,
+                "address": "addr",
+                "outputs": [],
+                "uri": "",
+                "fee": 0,
+                "createdAt": "2024-01-01T12:00:00Z",
+                "updatedAt": "2024-01-01T12:00:00Z",
+                "expires": "2024-01-01T12:15:00Z"
+            })).unwrap()
+        };
+        let options = [option("BTC", "BTC"), option("USDC", "ETH"), option("XRP", "XRP"), option("USDC", "SOL")];
+
+        assert_eq!(payable_currencies(&options), vec!["BTC", "USDC", "XRP"]);
+        assert!(payable_currencies(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_invoice_amounts_come_in_minor_and_major_units() {
+        let currencies = CurrencyTable::default();
+        let priced = |amount: i64, currency: &str| {
+            with_decimal_amount(json!({ "uid": "inv_123", "amount": amount, "currency": currency }), &currencies, AmountRepresentation::MinorUnits)
+        };
+
+        let usd = priced(10, "USD");
+        assert_eq!((usd["amount"].clone(), usd["amount_decimal"].clone(), usd["decimals"].clone()), (json!(10), json!("0.10"), json!(2)));
+        let btc = priced(2500, "BTC");
+        assert_eq!((btc["amount"].clone(), btc["amount_decimal"].clone(), btc["decimals"].clone()), (json!(2500), json!("0.00002500"), json!(8)));
+        let jpy = priced(500, "JPY");
+        assert_eq!((jpy["amount"].clone(), jpy["amount_decimal"].clone(), jpy["decimals"].clone()), (json!(500), json!("500"), json!(0)));
+
+        let unknown = priced(500, "XYZ");
+        assert!(unknown.get("amount_decimal").is_none());
+        assert_eq!(unknown["amount"], 500);
+    }
+
+    #[test]
+    fn test_decimal_string_amounts_survive_the_round_trip_exactly() {
+        let currencies = CurrencyTable::default();
+        let round_trip = |amount: &str, currency: &str| {
+            let minor = currencies.to_minor_units(&AmountInput::Decimal(amount.to_string()), currency, AmountPrecisionPolicy::Reject).unwrap();
+            let invoice = json!({ "uid": "inv_123", "amount": minor, "currency": currency });
+            with_decimal_amount(invoice, &currencies, AmountRepresentation::DecimalString)
+        };
+
+        assert_eq!(round_trip("0.10", "USD")["amount"], "0.10");
+        assert_eq!(round_trip("0.30", "USD")["amount"], "0.30");
+        assert_eq!(round_trip("0.1", "ETH")["amount"], "0.100000000000000000");
+        let usd = round_trip("0.10", "USD");
+        assert!(usd.get("amount_decimal").is_none());
+        assert_eq!(usd["decimals"], 2);
+    }
+}
@@ -18,4 +18,18 @@ pub mod wallet;
 pub mod client;
 pub mod cards;
 pub mod blockbook;
-pub mod confirmations;
\ No newline at end of file
+pub mod confirmations;
+pub mod config;
+pub mod envelope;
+pub mod event_bus;
+pub mod rate_limit;
+pub mod currency;
+pub mod snapshot;
+pub mod dead_letter;
+pub mod authorization;
+pub mod log_stream;
+pub mod uid;
+pub mod selftest;
+pub mod clock;
+#[cfg(test)]
+mod test_support;
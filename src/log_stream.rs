@@ -0,0 +1,207 @@
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use crate::event_dispatcher::EventDispatcher;
+use crate::rate_limit::TokenBucket;
+use crate::types::Subscription;
+
+/// Levels a `logs` subscription can ask for, from least to most verbose.
+const LEVELS: [Level; 5] = [Level::ERROR, Level::WARN, Level::INFO, Level::DEBUG, Level::TRACE];
+
+/// Modules whose log lines are never streamed. Delivering a line runs through
+/// them, so streaming their output would feed back into itself.
+const SILENCED_MODULES: [&str; 4] = ["log_stream", "event_dispatcher", "dead_letter", "session"];
+
+/// A log line as delivered to `logs` subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    pub timestamp: String,
+}
+
+/// Carries log lines captured by a `LogStreamLayer` to the dispatcher.
+#[derive(Clone)]
+pub struct LogStream {
+    sender: broadcast::Sender<LogLine>,
+}
+
+impl LogStream {
+    pub fn new(capacity: usize) -> Self {
+        LogStream {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// A tracing layer capturing events up to `max_level`, at most
+    /// `max_per_second` of them. Lines over the rate are dropped.
+    pub fn layer(&self, max_level: Level, max_per_second: u32) -> LogStreamLayer {
+        let max_per_second = max_per_second.max(1);
+        LogStreamLayer {
+            sender: self.sender.clone(),
+            max_level,
+            limiter: Mutex::new(TokenBucket::new(max_per_second, Duration::from_secs(1) / max_per_second)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for LogStream {
+    fn default() -> Self {
+        LogStream::new(1024)
+    }
+}
+
+pub struct LogStreamLayer {
+    sender: broadcast::Sender<LogLine>,
+    max_level: Level,
+    limiter: Mutex<TokenBucket>,
+}
+
+impl<S: Subscriber> Layer<S> for LogStreamLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > self.max_level || is_silenced(metadata.target()) || self.sender.receiver_count() == 0 {
+            return;
+        }
+        if self.limiter.lock().unwrap().try_acquire().is_err() {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let _ = self.sender.send(LogLine {
+            level: metadata.level().as_str().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+fn is_silenced(target: &str) -> bool {
+    target.split("::").any(|module| SILENCED_MODULES.contains(&module))
+}
+
+/// Whether `id` names a level a `logs` subscription can use, e.g. `"warn"`.
+pub fn is_level(id: &str) -> bool {
+    LEVELS.iter().any(|level| level_id(level) == id)
+}
+
+fn level_id(level: &Level) -> String {
+    level.as_str().to_lowercase()
+}
+
+/// `logs` topics that receive a line: every level at least as verbose as the line's.
+fn log_topics(line: &LogLine) -> Vec<Subscription> {
+    LEVELS.iter()
+        .skip_while(|level| level.as_str() != line.level)
+        .map(|level| Subscription {
+            sub_type: "logs".to_string(),
+            id: level_id(level),
+        })
+        .collect()
+}
+
+/// Dispatches captured log lines to `logs` subscribers until the stream is closed.
+pub async fn forward_to_subscribers(mut lines: broadcast::Receiver<LogLine>, event_dispatcher: Arc<EventDispatcher>) {
+    loop {
+        match lines.recv().await {
+            Ok(line) => {
+                let mut topics = Vec::new();
+                for topic in log_topics(&line) {
+                    if !event_dispatcher.get_subscribers(&topic).await.is_empty() {
+                        topics.push(topic);
+                    }
+                }
+                // Lines nobody asked for are not dead letters
+                if !topics.is_empty() {
+                    event_dispatcher.dispatch_to_topics(&topics, &json!({
+                        "type": "log",
+                        "data": line
+                    })).await;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tracing_subscriber::layer::SubscriberExt;
+    use uuid::Uuid;
+    use crate::session::Session;
+
+    #[tokio::test]
+    async fn test_log_lines_reach_logs_subscribers() {
+        let stream = LogStream::default();
+        let dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        dispatcher.subscribe(session, "logs", "info").await;
+        let forwarder = tokio::spawn(forward_to_subscribers(stream.subscribe(), dispatcher.clone()));
+
+        let subscriber = tracing_subscriber::registry().with(stream.layer(Level::INFO, 100));
+        tracing::subscriber::with_default(subscriber, || {
+            // This module's own lines are silenced, so log as another one
+            tracing::debug!(target: "anypay::server", "below the subscribed level");
+            tracing::warn!(target: "anypay::server", invoice = "inv_123", "payment looks late");
+        });
+
+        let WsMessage::Text(text) = receiver.next().await.unwrap() else {
+            panic!("expected a text frame");
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["type"], "log");
+        assert_eq!(event["data"]["level"], "WARN");
+        assert_eq!(event["data"]["message"], "payment looks late invoice=\"inv_123\"");
+        assert!(receiver.try_recv().is_err());
+
+        forwarder.abort();
+    }
+
+    #[test]
+    fn test_log_topics_include_more_verbose_levels() {
+        let line = LogLine {
+            level: "WARN".to_string(),
+            target: "anypay::server".to_string(),
+            message: String::new(),
+            timestamp: String::new(),
+        };
+        let ids: Vec<String> = log_topics(&line).into_iter().map(|topic| topic.id).collect();
+        assert_eq!(ids, vec!["warn", "info", "debug", "trace"]);
+        assert!(is_level("debug"));
+        assert!(!is_level("verbose"));
+        assert!(is_silenced("anypay::event_dispatcher"));
+    }
+}
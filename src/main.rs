@@ -15,6 +15,19 @@ mod ethereum;
 mod uri;
 mod blockbook;
 mod confirmations;
+mod envelope;
+mod event_bus;
+mod rate_limit;
+mod currency;
+mod snapshot;
+mod dead_letter;
+mod authorization;
+mod log_stream;
+mod uid;
+mod selftest;
+mod clock;
+#[cfg(test)]
+mod test_support;
 use std::sync::Arc;
 use std::net::SocketAddr;
 
@@ -27,13 +40,21 @@ use xrpl::XRPLClient;
 use config::Config;
 use ethereum::EthereumClient;
 use anyhow::Result;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
 
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    // Initialize logging. Lines also feed admin `logs` subscriptions.
+    let log_stream = log_stream::LogStream::default();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(log_stream.layer(tracing::Level::INFO, 50))
+        .init();
 
     // Load configuration
     let config = Config::from_env()?;
@@ -65,7 +86,9 @@ async fn main() -> Result<()> {
         &config.supabase_url,
         &config.supabase_anon_key,
         &config.supabase_service_role_key,
-    );
+    ).with_config(config::ServerConfig::from_env()?)
+        .with_store_events(supabase.events().clone())
+        .with_log_stream(log_stream);
     
     let http_server = http::HttpServer::new(supabase);
     let http_app = http_server.router();
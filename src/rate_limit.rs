@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket holding up to `capacity` tokens, refilled one token per
+/// `refill_interval`. Each inbound message spends one token.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: u32,
+    refill_interval: Duration,
+    tokens: u32,
+    // Start of the refill interval currently in progress
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        Self::new_at(capacity, refill_interval, Instant::now())
+    }
+
+    fn new_at(capacity: u32, refill_interval: Duration, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            refill_interval,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Spends a token, or returns how long until the next one is available.
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            return Ok(());
+        }
+
+        Err(self.next_token_in(now))
+    }
+
+    /// Time remaining until the bucket gains its next token.
+    pub fn next_token_in(&self, now: Instant) -> Duration {
+        if self.tokens >= self.capacity {
+            return Duration::ZERO;
+        }
+        (self.last_refill + self.refill_interval).saturating_duration_since(now)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.refill_interval.is_zero() {
+            self.tokens = self.capacity;
+            return;
+        }
+        if self.tokens >= self.capacity {
+            self.last_refill = now;
+            return;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let earned = (elapsed.as_nanos() / self.refill_interval.as_nanos()) as u32;
+        if earned > 0 {
+            self.tokens = self.tokens.saturating_add(earned).min(self.capacity);
+            self.last_refill += self.refill_interval * earned;
+        }
+    }
+}
+
+/// A bucket shared by every session of one account.
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Hands out one token bucket per account, so an account's connections draw
+/// on a single budget for requests that reach the store.
+#[derive(Debug)]
+pub struct AccountRateLimits {
+    burst: u32,
+    refill_interval: Duration,
+    buckets: Mutex<HashMap<i32, SharedBucket>>,
+}
+
+impl AccountRateLimits {
+    /// A `burst` of 0 disables account limits.
+    pub fn new(burst: u32, refill_interval: Duration) -> Self {
+        AccountRateLimits {
+            burst,
+            refill_interval,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The account's bucket, created on first use. Buckets no session holds
+    /// any more are dropped along the way.
+    pub fn bucket(&self, account_id: i32) -> Option<SharedBucket> {
+        if self.burst == 0 {
+            return None;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|id, bucket| *id == account_id || Arc::strong_count(bucket) > 1);
+        Some(buckets.entry(account_id)
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(self.burst, self.refill_interval))))
+            .clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after_matches_refill_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(200);
+        let mut bucket = TokenBucket::new_at(2, interval, start);
+
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert!(bucket.try_acquire_at(start).is_ok());
+        assert_eq!(bucket.try_acquire_at(start), Err(interval));
+
+        // Part way through the interval only the remainder is reported
+        let later = start + Duration::from_millis(150);
+        assert_eq!(bucket.try_acquire_at(later), Err(Duration::from_millis(50)));
+
+        // Once the interval has passed a token is available again
+        assert!(bucket.try_acquire_at(start + interval).is_ok());
+        assert_eq!(bucket.try_acquire_at(start + interval), Err(interval));
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::event_bus::StoreEvent;
+use crate::supabase::SupabaseClient;
+
+/// How long the event step waits for the created invoice to be announced.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Amount of the throwaway invoice, in minor units of `SELFTEST_CURRENCY`.
+const SELFTEST_AMOUNT: i64 = 100;
+const SELFTEST_CURRENCY: &str = "USD";
+
+/// Outcome of one step of the self-test.
+#[derive(Debug, Serialize)]
+pub struct StepReport {
+    pub step: &'static str,
+    pub passed: bool,
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl StepReport {
+    fn new(step: &'static str, started: Instant, result: anyhow::Result<()>) -> Self {
+        StepReport {
+            step,
+            passed: result.is_ok(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelftestReport {
+    pub passed: bool,
+    /// The invoice the run created, if it got that far.
+    pub invoice_uid: Option<String>,
+    pub steps: Vec<StepReport>,
+}
+
+impl SelftestReport {
+    fn finish(invoice_uid: Option<String>, steps: Vec<StepReport>) -> Self {
+        SelftestReport {
+            passed: steps.iter().all(|step| step.passed),
+            invoice_uid,
+            steps,
+        }
+    }
+}
+
+/// Creates an invoice for `account_id`, fetches it back, waits for its
+/// creation event and cancels it, reporting each step. The invoice is
+/// cancelled even when the steps before fail, so runs don't leave payable
+/// invoices behind.
+pub async fn run(supabase: &SupabaseClient, account_id: i64) -> SelftestReport {
+    // Subscribed before creating, so the invoice's event can't be missed
+    let mut events = supabase.events().subscribe();
+    let mut steps = Vec::new();
+
+    let started = Instant::now();
+    let created = supabase.create_invoice(
+        SELFTEST_AMOUNT,
+        SELFTEST_CURRENCY,
+        account_id,
+        None,
+        None,
+        Some("selftest".to_string()),
+        Vec::new(),
+    ).await.and_then(|created| {
+        created["invoice"]["uid"].as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("Created invoice has no uid"))
+    });
+    let uid = match created {
+        Ok(uid) => {
+            steps.push(StepReport::new("create", started, Ok(())));
+            uid
+        }
+        Err(e) => {
+            steps.push(StepReport::new("create", started, Err(e)));
+            return SelftestReport::finish(None, steps);
+        }
+    };
+
+    let started = Instant::now();
+    let fetched = match supabase.get_invoice_record(&uid).await {
+        Ok(Some(invoice)) if invoice.account_id == account_id => Ok(()),
+        Ok(Some(invoice)) => Err(anyhow!("Invoice {} belongs to account {}", uid, invoice.account_id)),
+        Ok(None) => Err(anyhow!("Invoice {} not found", uid)),
+        Err(e) => Err(e),
+    };
+    steps.push(StepReport::new("fetch", started, fetched));
+
+    let started = Instant::now();
+    let announced = tokio::time::timeout(EVENT_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(StoreEvent::InvoiceCreated(invoice)) if invoice.uid == uid => return Ok(()),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Err(anyhow!("Event bus closed")),
+            }
+        }
+    }).await.unwrap_or_else(|_| Err(anyhow!("No event for invoice {} within {:?}", uid, EVENT_TIMEOUT)));
+    steps.push(StepReport::new("event", started, announced));
+
+    let started = Instant::now();
+    let cancelled = supabase.cancel_invoice(&uid, account_id as i32).await;
+    steps.push(StepReport::new("cancel", started, cancelled));
+
+    tracing::info!("Selftest against account {} finished with invoice {}", account_id, uid);
+    SelftestReport::finish(Some(uid), steps)
+}
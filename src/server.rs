@@ -1,29 +1,91 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio_tungstenite::{
     accept_hdr_async,
     tungstenite::handshake::server::{Request, Response, ErrorResponse},
+    tungstenite::http::StatusCode,
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame},
+    tungstenite::error::ProtocolError,
+    tungstenite::Message as WsMessage,
+    tungstenite,
 };
-use futures::{StreamExt, SinkExt};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::{FutureExt, Sink, StreamExt, SinkExt};
+use std::io::ErrorKind;
+use std::panic::AssertUnwindSafe;
 use uuid::Uuid;
 use serde_json::json;
+use tracing::Instrument;
 
-use crate::event_dispatcher::EventDispatcher;
+use crate::authorization::{self, AuthorizationCache};
+use crate::config::ServerConfig;
+use crate::currency::{AmountRepresentation, CurrencyTable};
+use crate::dead_letter::{DeadLetterSink, LogDeadLetterSink};
+use crate::event_dispatcher::{EventDispatcher, META_TOPIC};
 use crate::payment_options::create_payment_options;
-use crate::session::Session;
-use crate::types::Message;
-use crate::supabase::SupabaseClient;
+use crate::envelope;
+use crate::event_bus::{self, EventBus};
+use crate::log_stream::{self, LogStream};
+use crate::session::{ConnectOptions, ConnectionState, EventBatch, SendQueue, Session, SlowReaderAction};
+use crate::snapshot::ServerSnapshot;
+use crate::types::{validate_projection, Invoice, InvoiceView, Message, PaymentOption, Subscription};
+use crate::supabase::{is_valid_trace_id, SupabaseClient, SupabaseError, TRACE_ID, TRACE_ID_HEADER};
+#[cfg(feature = "quotes")]
 use crate::prices::{ConversionRequest, convert};
 use crate::invoices;
+use crate::selftest;
+use crate::rate_limit::{AccountRateLimits, SharedBucket, TokenBucket};
 use anyhow::Result;
 
+/// Most topics a single `subscribe_many` can name.
+const MAX_SUBSCRIBE_MANY: usize = 100;
+
+/// How long a client-requested close waits for queued responses to be written.
+const CLOSE_FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct AnypayEventsServer {
     event_dispatcher: Arc<EventDispatcher>,
     sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
     addr: String,
     supabase: Arc<SupabaseClient>,
+    shutdown: Arc<watch::Sender<bool>>,
+    config: Arc<ServerConfig>,
+    authorization: Arc<AuthorizationCache>,
+    account_limits: Arc<AccountRateLimits>,
+    create_limit: Option<SharedBucket>,
+    log_stream: Option<LogStream>,
+}
+
+/// Signals a running `AnypayEventsServer` to stop accepting connections and
+/// close the ones it has open.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(&self) {
+        let _ = self.0.subscribe().wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// The server-wide state each connection is handled with.
+#[derive(Clone)]
+struct ConnectionContext {
+    event_dispatcher: Arc<EventDispatcher>,
+    sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
+    supabase: Arc<SupabaseClient>,
+    config: Arc<ServerConfig>,
+    authorization: Arc<AuthorizationCache>,
+    account_limits: Arc<AccountRateLimits>,
+    create_limit: Option<SharedBucket>,
 }
 
 impl AnypayEventsServer {
@@ -33,74 +95,471 @@ impl AnypayEventsServer {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             addr: addr.to_string(),
             supabase: Arc::new(SupabaseClient::new(supabase_url, supabase_anon_key, supabase_service_role_key)),
+            shutdown: Arc::new(watch::channel(false).0),
+            config: Arc::new(ServerConfig::default()),
+            authorization: Arc::new(AuthorizationCache::new(ServerConfig::default().authorization_cache_ttl)),
+            account_limits: Arc::new(AccountRateLimits::new(0, std::time::Duration::ZERO)),
+            create_limit: None,
+            log_stream: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        if config.dead_letter_log {
+            self.event_dispatcher.set_dead_letter_sink(Arc::new(LogDeadLetterSink));
         }
+        self.event_dispatcher.set_recent_events_capacity(config.recent_events_buffer);
+        self.event_dispatcher.set_dedup_identical_events(config.dedup_identical_events);
+        self.event_dispatcher.set_missed_event_retention(config.missed_event_retention);
+        self.event_dispatcher.set_subscription_event_rate(config.subscription_events_per_second);
+        self.event_dispatcher.set_max_topics_per_dispatch(config.max_topics_per_dispatch);
+        self.event_dispatcher.set_unknown_status_policy(config.known_invoice_statuses.clone(), config.unknown_status_policy);
+        self.event_dispatcher.set_decimal_invoice_amounts(
+            (config.amount_representation == AmountRepresentation::DecimalString).then(|| config.currencies.clone())
+        );
+        self.authorization = Arc::new(AuthorizationCache::new(config.authorization_cache_ttl));
+        self.account_limits = Arc::new(AccountRateLimits::new(config.account_rate_limit_burst, config.account_rate_limit_refill));
+        self.create_limit = (config.create_rate_limit_burst > 0).then(|| {
+            Arc::new(std::sync::Mutex::new(TokenBucket::new(config.create_rate_limit_burst, config.create_rate_limit_refill)))
+        });
+        self.supabase = Arc::new((*self.supabase).clone()
+            .with_uid_scheme(config.invoice_uid_scheme.clone())
+            .with_backoff(config.store_backoff)
+            .with_schema_drift_tolerance(config.tolerate_schema_drift));
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Routes undeliverable events to a custom sink, replacing any configured one.
+    pub fn with_dead_letter_sink(self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.event_dispatcher.set_dead_letter_sink(sink);
+        self
+    }
+
+    /// Dispatches the changes published on `events`, typically the bus of the
+    /// client that payment watchers write through, instead of only this server's own writes.
+    pub fn with_store_events(mut self, events: EventBus) -> Self {
+        self.supabase = Arc::new((*self.supabase).clone().with_events(events));
+        self
+    }
+
+    /// Streams lines captured by the stream's tracing layer to admin `logs` subscribers.
+    pub fn with_log_stream(mut self, log_stream: LogStream) -> Self {
+        self.log_stream = Some(log_stream);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Captures the connected sessions and their subscriptions.
+    pub async fn snapshot(&self) -> ServerSnapshot {
+        ServerSnapshot::capture(&*self.sessions.read().await, &self.event_dispatcher).await
     }
 
     pub async fn run(&self) -> Result<()> {
         let listener = TcpListener::bind(&self.addr).await?;
         tracing::info!("WebSocket server listening on: {}", self.addr);
+        self.serve(listener).await
+    }
+
+    /// Accepts connections on `listener` until shutdown, then drains them.
+    async fn serve(&self, listener: TcpListener) -> Result<()> {
+        let mut shutdown = self.shutdown.subscribe();
+
+        // Store writes published on this server's bus reach subscribers from here
+        let bridge = tokio::spawn(event_bus::forward_to_dispatcher(
+            self.supabase.events().subscribe(),
+            self.event_dispatcher.clone(),
+        ));
+        let invalidation = tokio::spawn(authorization::invalidate_on_changes(
+            self.supabase.events().subscribe(),
+            self.authorization.clone(),
+        ));
+        let logs = self.log_stream.as_ref().map(|log_stream| tokio::spawn(
+            log_stream::forward_to_subscribers(log_stream.subscribe(), self.event_dispatcher.clone())
+        ));
+
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        tracing::error!("Failed to accept connection: {}", e);
+                        break;
+                    }
+                },
+                _ = shutdown.changed() => {
+                    tracing::info!("WebSocket server shutting down");
+                    break;
+                }
+            };
 
-        while let Ok((stream, addr)) = listener.accept().await {
             tracing::info!("New connection from: {}", addr);
             
-            let event_dispatcher = self.event_dispatcher.clone();
-            let sessions = self.sessions.clone();
-            let supabase = self.supabase.clone();
+            let context = self.connection_context();
+            let shutdown = self.shutdown.subscribe();
             
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(stream, event_dispatcher, sessions, supabase).await {
+            // Reap finished connections so the set only holds live ones
+            while let Some(finished) = connections.try_join_next() {
+                if let Err(e) = finished {
+                    if e.is_panic() {
+                        tracing::error!("Connection task panicked: {}", panic_message(&*e.into_panic()));
+                    }
+                }
+            }
+            connections.spawn(async move {
+                if let Err(e) = Self::handle_connection(stream, context, shutdown).await {
                     tracing::error!("Error handling connection: {}", e);
                 }
             });
         }
 
+        self.drain(connections).await;
+        bridge.abort();
+        invalidation.abort();
+        if let Some(logs) = logs {
+            logs.abort();
+        }
         Ok(())
     }
 
-    async fn handle_message(
-        message: Message,
-        session: &Session,
-        event_dispatcher: &Arc<EventDispatcher>,
-        supabase: &Arc<SupabaseClient>,
-    ) -> serde_json::Value {
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            event_dispatcher: self.event_dispatcher.clone(),
+            sessions: self.sessions.clone(),
+            supabase: self.supabase.clone(),
+            config: self.config.clone(),
+            authorization: self.authorization.clone(),
+            account_limits: self.account_limits.clone(),
+            create_limit: self.create_limit.clone(),
+        }
+    }
+
+    /// Waits for connections to close after shutdown, then aborts whatever is
+    /// still open once the drain timeout passes and forgets its sessions.
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        let drained = tokio::time::timeout(self.config.shutdown_drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        }).await;
+        if drained.is_ok() {
+            return;
+        }
+
+        tracing::warn!("{} connections still open after {:?}, closing them", connections.len(), self.config.shutdown_drain_timeout);
+        connections.shutdown().await;
+        let remaining: Vec<Uuid> = self.sessions.write().await.drain().map(|(id, _)| id).collect();
+        for session_id in remaining {
+            self.event_dispatcher.remove_session(session_id).await;
+        }
+    }
+
+    async fn handle_message(message: Message, session: &Session, context: &ConnectionContext) -> serde_json::Value {
+        let ConnectionContext { event_dispatcher, sessions, supabase, config, .. } = context;
         println!("message in handle message: {:?}", message);
+        let message = session.context.apply(message);
+        if message.uses_backend() {
+            if let Err(retry_after) = session.acquire_account_token() {
+                return Self::account_rate_limited(retry_after);
+            }
+        }
         match message {
-            Message::Subscribe { sub_type, id } => {
-                event_dispatcher.subscribe(session.clone(), &sub_type, &id).await;
-                json!({
+            Message::Subscribe { sub_type, id, filter, fields, view } => {
+                // Shed new fan-out load; existing subscriptions keep being served
+                let depth = supabase.events().depth();
+                if config.dispatch_queue_high_water_mark > 0 && depth >= config.dispatch_queue_high_water_mark {
+                    tracing::warn!("Refusing subscription from session {}: {} events waiting to be dispatched", session.id, depth);
+                    return Self::overloaded(config.overload_retry_after);
+                }
+
+                // Ids key the dispatcher's maps, so long ones are refused before anything is stored
+                if config.max_subscription_id_length > 0 && id.len() > config.max_subscription_id_length {
+                    return json!({
+                        "status": "error",
+                        "code": "ID_TOO_LONG",
+                        "message": format!("Subscription id is {} bytes, at most {} are allowed", id.len(), config.max_subscription_id_length)
+                    });
+                }
+
+                if let Some(fields) = &fields {
+                    if let Err(message) = validate_projection(fields) {
+                        return json!({
+                            "status": "error",
+                            "code": "INVALID_FIELDS",
+                            "message": message
+                        });
+                    }
+                    if view == Some(InvoiceView::Compact) {
+                        return json!({
+                            "status": "error",
+                            "code": "INVALID_FIELDS",
+                            "message": "fields can't be combined with the compact view"
+                        });
+                    }
+                }
+                let fields = fields.or_else(|| view.unwrap_or_default().fields());
+
+                if sub_type == "logs" {
+                    if !session.is_admin {
+                        return json!({
+                            "status": "error",
+                            "code": "FORBIDDEN",
+                            "message": "Only admin sessions can subscribe to logs"
+                        });
+                    }
+                    if !log_stream::is_level(&id) {
+                        return json!({
+                            "status": "error",
+                            "message": format!("Unknown log level: {}", id)
+                        });
+                    }
+                } else if sub_type == META_TOPIC.0 {
+                    if !session.is_admin {
+                        return json!({
+                            "status": "error",
+                            "code": "FORBIDDEN",
+                            "message": "Only admin sessions can subscribe to meta topics"
+                        });
+                    }
+                    if id != META_TOPIC.1 || config.subscription_events_per_second == 0 {
+                        return json!({
+                            "status": "error",
+                            "message": format!("Unknown meta topic: {}", id)
+                        });
+                    }
+                } else if config.authorize_subscriptions {
+                    match Self::authorize_subscription(session, &sub_type, &id, context).await {
+                        Ok(true) => {}
+                        Ok(false) => return json!({
+                            "status": "error",
+                            "code": "FORBIDDEN",
+                            "message": format!("Not authorized to subscribe to {} {}", sub_type, id)
+                        }),
+                        Err(e) => return with_retry_hint(json!({
+                            "status": "error",
+                            "message": format!("Error authorizing subscription: {}", e)
+                        }), &e),
+                    }
+                }
+
+                let subscription_id = event_dispatcher
+                    .subscribe_projected(session.clone(), &sub_type, &id, filter.unwrap_or_default(), fields)
+                    .await;
+                let mut response = json!({
                     "status": "success",
-                    "message": format!("Subscribed to {} {}", sub_type, id)
-                })
+                    "message": format!("Subscribed to {} {}", sub_type, id),
+                    "subscription_id": subscription_id
+                });
+                if config.subscriber_counts.shown_to(session) {
+                    // Counted after subscribing, so it includes this session
+                    let topic = Subscription { sub_type, id };
+                    response["subscriber_count"] = json!(event_dispatcher.get_subscribers(&topic).await.len());
+                }
+                response
             }
-            Message::Unsubscribe { sub_type, id } => {
-                event_dispatcher.unsubscribe(session.clone(), &sub_type, &id).await;
+            Message::SubscribeMany { subscriptions, suppress_acks } => {
+                if subscriptions.len() > MAX_SUBSCRIBE_MANY {
+                    return json!({
+                        "status": "error",
+                        "message": format!("At most {} subscriptions per subscribe_many", MAX_SUBSCRIBE_MANY)
+                    });
+                }
+
+                let mut subscribed = 0;
+                let mut failed = Vec::new();
+                let mut acks = Vec::new();
+                for request in subscriptions {
+                    let (sub_type, id) = (request.sub_type.clone(), request.id.clone());
+                    let subscribe = Message::Subscribe {
+                        sub_type: request.sub_type,
+                        id: request.id,
+                        filter: request.filter,
+                        fields: request.fields,
+                        view: request.view,
+                    };
+                    // Each subscription goes through the same checks, and limits, as a lone subscribe
+                    let response = Box::pin(Self::handle_message(subscribe, session, context)).await;
+                    if response["status"] == "success" {
+                        subscribed += 1;
+                    } else {
+                        failed.push(json!({
+                            "type": sub_type,
+                            "id": id,
+                            "code": response["code"],
+                            "message": response["message"]
+                        }));
+                    }
+                    acks.push(response);
+                }
+
+                let mut data = json!({
+                    "subscribed": subscribed,
+                    "failed": failed
+                });
+                if !suppress_acks.unwrap_or(session.suppress_acks) {
+                    data["results"] = json!(acks);
+                }
                 json!({
                     "status": "success",
-                    "message": format!("Unsubscribed from {} {}", sub_type, id)
+                    "data": data
                 })
             }
-            Message::FetchInvoice { id } => {
-                tracing::info!("Fetching invoice with id: {}", id);
-                match supabase.get_invoice(&id, true).await {
-                    Ok(Some(invoice)) => json!({
+            Message::Unsubscribe { sub_type, id, subscription_id } => {
+                match (subscription_id, sub_type, id) {
+                    (Some(subscription_id), _, _) => {
+                        match event_dispatcher.unsubscribe_by_id(session, &subscription_id).await {
+                            Some(subscription) => json!({
+                                "status": "success",
+                                "message": format!("Unsubscribed from {} {}", subscription.sub_type, subscription.id),
+                                "subscription_id": subscription_id
+                            }),
+                            None => json!({
+                                "status": "error",
+                                "message": format!("Unknown subscription_id: {}", subscription_id)
+                            }),
+                        }
+                    }
+                    (None, Some(sub_type), Some(id)) => {
+                        event_dispatcher.unsubscribe(session.clone(), &sub_type, &id).await;
+                        json!({
+                            "status": "success",
+                            "message": format!("Unsubscribed from {} {}", sub_type, id)
+                        })
+                    }
+                    _ => json!({
+                        "status": "error",
+                        "message": "Unsubscribe requires either subscription_id or type and id"
+                    }),
+                }
+            }
+            Message::PauseSubscription { sub_type, id } => {
+                if event_dispatcher.pause(session, &sub_type, &id, config.paused_buffer_size).await {
+                    json!({
+                        "status": "success",
+                        "message": format!("Paused {} {}", sub_type, id)
+                    })
+                } else {
+                    json!({
+                        "status": "error",
+                        "message": format!("Not subscribed to {} {}", sub_type, id)
+                    })
+                }
+            }
+            Message::ResumeSubscription { sub_type, id } => {
+                match event_dispatcher.resume(session, &sub_type, &id) {
+                    Some((delivered, dropped)) => json!({
+                        "status": "success",
+                        "message": format!("Resumed {} {}", sub_type, id),
+                        "delivered": delivered,
+                        "dropped": dropped
+                    }),
+                    None => json!({
+                        "status": "error",
+                        "message": format!("Subscription {} {} is not paused", sub_type, id)
+                    }),
+                }
+            }
+            Message::UpdateSubscriptionFilter { sub_type, id, filter } => {
+                if event_dispatcher.update_filter(session, &sub_type, &id, filter).await {
+                    json!({
+                        "status": "success",
+                        "message": format!("Updated filter for {} {}", sub_type, id)
+                    })
+                } else {
+                    json!({
+                        "status": "error",
+                        "message": format!("Not subscribed to {} {}", sub_type, id)
+                    })
+                }
+            }
+            Message::RecentEvents { sub_type, id, limit } => {
+                let limit = limit.unwrap_or(config.recent_events_buffer).min(config.recent_events_buffer);
+                match event_dispatcher.recent_events(session, &sub_type, &id, limit).await {
+                    Some(events) => json!({
                         "status": "success",
                         "data": {
-                            "invoice": invoice.0,
-                            "payment_options": invoice.1
+                            "type": sub_type,
+                            "id": id,
+                            "events": events
                         }
                     }),
-                    Ok(None) => json!({
+                    None => json!({
                         "status": "error",
-                        "message": "Invoice not found"
+                        "message": format!("Not subscribed to {} {}", sub_type, id)
                     }),
-                    Err(e) => json!({
+                }
+            }
+            Message::FetchInvoice { id, expand, view } => {
+                if session.account_id.is_none() && !config.allow_unauthenticated_fetch {
+                    return json!({
+                        "status": "error",
+                        "code": "UNAUTHORIZED",
+                        "message": "Unauthorized: API key required: See https://www.anypayx.com/developer/websockets/authentication"
+                    });
+                }
+                tracing::info!("Fetching invoice with id: {}", id);
+                let found = if expand.unwrap_or(config.expand_payment_options) {
+                    supabase.get_invoice(&id, true).await
+                        .map(|found| found.map(|(invoice, payment_options)| (invoice, Some(payment_options))))
+                } else {
+                    supabase.get_invoice_record(&id).await
+                        .map(|found| found.map(|invoice| (invoice, None)))
+                };
+                match found {
+                    Ok(found) => {
+                        let mut response = fetch_invoice_response(found, config.report_deleted_invoices, &config.currencies, config.amount_representation);
+                        if let Some(invoice) = response.get_mut("data").and_then(|data| data.get_mut("invoice")) {
+                            view.unwrap_or_default().apply(invoice);
+                        }
+                        response
+                    }
+                    Err(e) => with_retry_hint(json!({
                         "status": "error",
                         "message": format!("Error fetching invoice: {}", e)
-                    }),
+                    }), &e),
                 }
             }
-            Message::CreateInvoice { amount, currency, webhook_url, redirect_url, memo } => {
-                if let Some(account_id) = session.account_id {
+            #[cfg(not(feature = "invoices"))]
+            Message::CreateInvoice { .. } => Self::feature_disabled("create_invoice", "invoices"),
+            #[cfg(feature = "invoices")]
+            Message::CreateInvoice { amount, currency, webhook_url, redirect_url, memo, test, account_id: requested_account, tags } => {
+                if test && !config.test_mode {
+                    return json!({
+                        "status": "error",
+                        "message": "Test invoices are not enabled on this server"
+                    });
+                }
+
+                if let Some(session_account_id) = session.account_id {
+                    let amount = match config.currencies.to_minor_units(&amount, &currency, config.amount_precision) {
+                        Ok(amount) => amount,
+                        Err(message) => return json!({
+                            "status": "error",
+                            "code": "INVALID_AMOUNT",
+                            "message": message
+                        }),
+                    };
+                    let account_id = match invoices::resolve_invoice_account(session_account_id, requested_account, session.is_admin) {
+                        Some(account_id) => account_id,
+                        None => return Self::invalid_account(requested_account),
+                    };
+                    if requested_account.is_some() {
+                        match supabase.find_account(account_id).await {
+                            Ok(Some(_)) => {}
+                            Ok(None) => return Self::invalid_account(requested_account),
+                            Err(e) => return with_retry_hint(with_debug_detail(json!({
+                                "status": "error",
+                                "message": format!("Failed to look up account: {}", e)
+                            }), &e, config.debug_errors), &e),
+                        }
+                    }
+                    // Only creates that would go ahead spend from the address pool's budget
+                    if let Err(retry_after) = session.acquire_create_token() {
+                        return Self::create_rate_limited(retry_after);
+                    }
+
                     println!("account_id in create invoice: {:?}", account_id);
                     match invoices::create_invoice(
                         &supabase,
@@ -109,16 +568,30 @@ impl AnypayEventsServer {
                         account_id,
                         webhook_url,
                         redirect_url,
-                        memo
+                        memo,
+                        tags
                     ).await {
-                        Ok(invoice) => json!({
-                            "status": "success",
-                            "data": invoice
-                        }),
-                        Err(e) => json!({
+                        Ok(invoice) => {
+                            if test {
+                                match serde_json::from_value::<Invoice>(invoice["invoice"].clone()) {
+                                    Ok(created) => invoices::schedule_test_payment(
+                                        supabase.clone(),
+                                        created,
+                                        config.test_payment_delay,
+                                    ),
+                                    Err(e) => tracing::error!("Failed to schedule test payment: {}", e),
+                                }
+                            }
+
+                            json!({
+                                "status": "success",
+                                "data": invoices::with_payment_uris(invoice, &config.currencies, config.amount_representation)
+                            })
+                        }
+                        Err(e) => with_retry_hint(with_debug_detail(json!({
                             "status": "error",
                             "message": format!("Failed to create invoice: {}", e)
-                        })
+                        }), &e, config.debug_errors), &e)
                     }
                 } else {
                     json!({
@@ -127,19 +600,25 @@ impl AnypayEventsServer {
                     })
                 }
             }
-            Message::ListPrices => {
+            #[cfg(not(feature = "quotes"))]
+            Message::ListPrices { .. } => Self::feature_disabled("list_prices", "quotes"),
+            #[cfg(not(feature = "quotes"))]
+            Message::ConvertPrice { .. } => Self::feature_disabled("convert_price", "quotes"),
+            #[cfg(feature = "quotes")]
+            Message::ListPrices { limit } => {
                 tracing::info!("Listing all prices");
-                match supabase.list_prices().await {
+                match supabase.list_prices(Some(config.pagination.limit(limit))).await {
                     Ok(prices) => json!({
                         "status": "success",
                         "data": prices
                     }),
-                    Err(e) => json!({
+                    Err(e) => with_retry_hint(json!({
                         "status": "error",
                         "message": format!("Error fetching prices: {}", e)
-                    }),
+                    }), &e),
                 }
             }
+            #[cfg(feature = "quotes")]
             Message::ConvertPrice { quote_currency, base_currency, quote_value } => {
                 let req = ConversionRequest {
                     quote_currency,
@@ -170,10 +649,10 @@ impl AnypayEventsServer {
                             "status": "success",
                             "message": "Invoice cancelled successfully"
                         }),
-                        Err(e) => json!({
+                        Err(e) => with_retry_hint(json!({
                             "status": "error",
                             "message": e.to_string()
-                        })
+                        }), &e)
                     }
                 } else {
                     json!({
@@ -182,118 +661,2505 @@ impl AnypayEventsServer {
                     })
                 }
             }
-            Message::Ping => {
-                json!({
-                    "type": "pong",
-                    "status": "success",
-                    "timestamp": chrono::Utc::now().timestamp()
-                })
-            },
-        }
-    }
-
-    async fn handle_connection(
-        stream: TcpStream,
-        event_dispatcher: Arc<EventDispatcher>,
-        sessions: Arc<RwLock<HashMap<Uuid, Session>>>,
-        supabase: Arc<SupabaseClient>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
-        let mut session = Session::new(Uuid::new_v4(), sender);
-        let supabase_clone = supabase.clone();
-
-        let ws_stream = accept_hdr_async(stream, |req: &Request, res: Response| {
-            
-            if let Some(auth) = req.headers().get("Authorization") {
-                println!("Authorization: {:?}", auth);
-                if let Ok(auth_str) = auth.to_str() {
-                    println!("Authorization string: {:?}", auth_str);
-                    if auth_str.starts_with("Bearer ") {
-                        let token = auth_str[7..].trim().to_string();
-                        // Store token in session for async validation after handshake
-                        println!("Token: {:?}", token);
-                        session.auth_token = Some(token);
-                    }
+            Message::FetchInvoiceHistory { id, limit } => {
+                match supabase.get_invoice_status_history(&id, config.pagination.limit(limit)).await {
+                    Ok(history) => json!({
+                        "status": "success",
+                        "data": {
+                            "invoice_uid": id,
+                            "history": history
+                        }
+                    }),
+                    Err(e) => with_retry_hint(json!({
+                        "status": "error",
+                        "message": format!("Error fetching invoice history: {}", e)
+                    }), &e),
                 }
             }
-            Ok(res)
-        }).await?;
+            Message::RebroadcastInvoice { id } => {
+                if !session.is_admin {
+                    return json!({
+                        "status": "error",
+                        "message": "Forbidden: admin access required"
+                    });
+                }
 
-        // Validate token after handshake
-        if let Some(token) = &session.auth_token {
-            println!("session.auth_token: {:?}", token);
-            if let Ok(Some(account_id)) = supabase_clone.validate_api_key(token).await {
-                println!("Account ID: {:?}", account_id);
-                session.set_account_id(account_id);
-                tracing::info!("Authenticated session {} for account {}", session.id, account_id);
+                match supabase.get_invoice(&id, true).await {
+                    Ok(Some((invoice, _))) => {
+                        let delivered = event_dispatcher.dispatch_invoice_event("invoice.updated", &invoice).await;
+                        tracing::info!("Rebroadcast invoice {} to {} subscribers", id, delivered);
+                        json!({
+                            "status": "success",
+                            "data": {
+                                "invoice_uid": id,
+                                "subscribers": delivered
+                            }
+                        })
+                    }
+                    Ok(None) => json!({
+                        "status": "error",
+                        "message": "Invoice not found"
+                    }),
+                    Err(e) => with_retry_hint(json!({
+                        "status": "error",
+                        "message": format!("Error fetching invoice: {}", e)
+                    }), &e),
+                }
             }
-        }
-
-        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
-        session.sender = Some(sender).unwrap();
+            Message::WaitForPayment { id, timeout_secs } => {
+                let timeout = timeout_secs
+                    .map(std::time::Duration::from_secs)
+                    .unwrap_or(config.max_wait_for_payment)
+                    .min(config.max_wait_for_payment);
 
-        // Store the session
-        sessions.write().await.insert(session.id, session.clone());
-
-        // Create a flag to track connection state
-        let is_connected = Arc::new(std::sync::atomic::AtomicBool::new(true));
-        let is_connected_clone = is_connected.clone();
+                // Listen before reading the current status so a payment in between isn't missed
+                let events = supabase.events().subscribe();
+                let invoice = match supabase.get_invoice_record(&id).await {
+                    Ok(Some(invoice)) => invoice,
+                    Ok(None) => return json!({
+                        "status": "error",
+                        "message": "Invoice not found"
+                    }),
+                    Err(e) => return with_retry_hint(json!({
+                        "status": "error",
+                        "message": format!("Error fetching invoice: {}", e)
+                    }), &e),
+                };
 
-        // Spawn a task to forward messages from the channel to the websocket
-        let _send_task = tokio::spawn(async move {
-            while let Some(message) = receiver.next().await {
-                if !is_connected_clone.load(std::sync::atomic::Ordering::SeqCst) {
-                    break;
-                }
-                if let Err(e) = ws_sender.send(message).await {
-                    tracing::debug!("Connection closed by client: {}", e);
-                    break;
+                if matches!(invoice.status.as_str(), "paid" | "cancelled") {
+                    return Self::payment_wait_response(Some(invoice), timeout, &config.currencies, config.amount_representation);
                 }
+
+                // Waiting here would hold up every later request on the connection,
+                // so the wait answers on its own once the invoice settles or time runs out
+                let waiting = session.clone();
+                let (currencies, representation) = (config.currencies.clone(), config.amount_representation);
+                tokio::spawn(async move {
+                    let settled = invoices::wait_for_settlement(events, &id, timeout).await;
+                    let response = Self::payment_wait_response(settled, timeout, &currencies, representation);
+                    waiting.stats.record_deferred_response(&response);
+                    if let Err(e) = Self::send_response(&waiting, response) {
+                        tracing::debug!("Failed to send wait_for_payment response, client likely disconnected: {}", e);
+                    }
+                });
+                Self::deferred()
             }
-        });
+            Message::BroadcastAccountEvent { account_id, event } => {
+                if !session.is_admin {
+                    return json!({
+                        "status": "error",
+                        "message": "Forbidden: admin access required"
+                    });
+                }
 
-        // Handle incoming messages
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(msg) => {
-                    if let Ok(text) = msg.to_text() {
-                        println!("text in handle connection: {:?}", text);
-                        let response = match serde_json::from_str::<Message>(text) {
-                            Ok(message) => {
-                                Self::handle_message(
-                                    message,
-                                    &session,
-                                    &event_dispatcher,
-                                    &supabase,
-                                ).await
+                let subscribed = event_dispatcher.subscribed_ids("invoice").await;
+                match supabase.filter_account_invoices(account_id, &subscribed).await {
+                    Ok(invoice_uids) => {
+                        let delivered = event_dispatcher.dispatch_account_event(account_id, &invoice_uids, &event).await;
+                        tracing::info!("Broadcast account event for {} to {} subscribers", account_id, delivered);
+                        json!({
+                            "status": "success",
+                            "data": {
+                                "account_id": account_id,
+                                "subscribers": delivered
                             }
-                            Err(_) => json!({
-                                "status": "error",
-                                "message": "Invalid message format"
-                            })
-                        };
-
-                        if let Err(e) = session.send(tokio_tungstenite::tungstenite::Message::Text(response.to_string().into())) {
-                            tracing::debug!("Failed to send response, client likely disconnected: {}", e);
-                            break;
-                        }
+                        })
                     }
+                    Err(e) => with_retry_hint(json!({
+                        "status": "error",
+                        "message": format!("Error resolving account invoices: {}", e)
+                    }), &e),
                 }
-                Err(e) => {
-                    tracing::debug!("WebSocket error: {}", e);
-                    break;
+            }
+            Message::SetSessionDebug { session_id, enabled } => {
+                if !session.is_admin {
+                    return json!({
+                        "status": "error",
+                        "code": "FORBIDDEN",
+                        "message": "Forbidden: admin access required"
+                    });
                 }
+                let Some(target) = sessions.read().await.get(&session_id).cloned() else {
+                    return json!({
+                        "status": "error",
+                        "code": "NOT_FOUND",
+                        "message": format!("No connected session {}", session_id)
+                    });
+                };
+                target.set_debugging(enabled);
+                tracing::info!("Debug logging {} for session {} by session {}", if enabled { "enabled" } else { "disabled" }, session_id, session.id);
+                json!({
+                    "status": "success",
+                    "data": {
+                        "session_id": session_id,
+                        "enabled": enabled
+                    }
+                })
             }
-        }
-
-        // Mark connection as closed
-        is_connected.store(false, std::sync::atomic::Ordering::SeqCst);
-        
+            Message::SetContext { values } => {
+                match session.context.update(values, config.max_context_entries, config.max_context_bytes) {
+                    Ok(context) => json!({
+                        "status": "success",
+                        "data": context
+                    }),
+                    Err(message) => json!({
+                        "status": "error",
+                        "code": "CONTEXT_TOO_LARGE",
+                        "message": message
+                    }),
+                }
+            }
+            Message::GetContext => json!({
+                "status": "success",
+                "data": session.context.values()
+            }),
+            Message::RevalidateSubscriptions => {
+                Self::revalidate_subscriptions(session, context).await
+            }
+            Message::Selftest => {
+                if !session.is_admin {
+                    return json!({
+                        "status": "error",
+                        "message": "Forbidden: admin access required"
+                    });
+                }
+                if config.selftest_account_id <= 0 {
+                    return json!({
+                        "status": "error",
+                        "message": "Selftest is not configured, set WS_SELFTEST_ACCOUNT_ID to a test account"
+                    });
+                }
+
+                let report = selftest::run(supabase, config.selftest_account_id).await;
+                if report.passed {
+                    json!({
+                        "status": "success",
+                        "data": report
+                    })
+                } else {
+                    json!({
+                        "status": "error",
+                        "code": "SELFTEST_FAILED",
+                        "message": "Selftest failed",
+                        "data": report
+                    })
+                }
+            }
+            // The connection loop sends the Close frame once this response is out
+            Message::Close => json!({
+                "status": "success",
+                "message": "Closing connection"
+            }),
+            // Counts requests answered before this one
+            Message::ConnectionStats => json!({
+                "status": "success",
+                "data": {
+                    "messages_received": session.stats.messages_received(),
+                    "errors": session.stats.errors(),
+                    "events_sent": session.stats.events_sent()
+                }
+            }),
+            Message::ServerTime => {
+                let now = config.clock.now();
+                json!({
+                    "status": "success",
+                    "data": {
+                        "time": now.to_rfc3339(),
+                        "epoch_ms": now.timestamp_millis()
+                    }
+                })
+            }
+            Message::Ping => {
+                json!({
+                    "type": "pong",
+                    "status": "success",
+                    "timestamp": chrono::Utc::now().timestamp()
+                })
+            },
+        }
+    }
+
+    /// Checks each of the session's subscriptions again, as `subscribe` would,
+    /// and drops those whose invoice is gone or that the session may no longer
+    /// hold. Subscriptions that couldn't be checked are kept and reported.
+    async fn revalidate_subscriptions(session: &Session, context: &ConnectionContext) -> serde_json::Value {
+        let ConnectionContext { event_dispatcher, supabase, config, .. } = context;
+        let mut subscriptions = event_dispatcher.subscriptions_by_session().await
+            .remove(&session.id)
+            .unwrap_or_default();
+        subscriptions.sort_by(|(a, _), (b, _)| (&a.sub_type, &a.id).cmp(&(&b.sub_type, &b.id)));
+
+        let (mut kept, mut removed, mut unchecked) = (Vec::new(), Vec::new(), Vec::new());
+        for (topic, subscription_id) in subscriptions {
+            let entry = json!({ "type": topic.sub_type, "id": topic.id, "subscription_id": subscription_id });
+            let invalid = match topic.sub_type.as_str() {
+                "logs" | "meta" => (!session.is_admin).then_some("forbidden"),
+                "invoice" => match supabase.get_invoice_record(&topic.id).await {
+                    Ok(None) => Some("not_found"),
+                    Ok(Some(invoice)) if invoice.is_deleted() => Some("deleted"),
+                    Ok(Some(_)) => None,
+                    Err(e) => {
+                        unchecked.push(json!({ "type": topic.sub_type, "id": topic.id, "message": e.to_string() }));
+                        kept.push(entry);
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+            let invalid = match invalid {
+                None if config.authorize_subscriptions && !matches!(topic.sub_type.as_str(), "logs" | "meta") => {
+                    match Self::authorize_subscription(session, &topic.sub_type, &topic.id, context).await {
+                        Ok(true) => None,
+                        Ok(false) => Some("forbidden"),
+                        Err(e) => {
+                            unchecked.push(json!({ "type": topic.sub_type, "id": topic.id, "message": e.to_string() }));
+                            None
+                        }
+                    }
+                }
+                invalid => invalid,
+            };
+
+            match invalid {
+                Some(reason) => {
+                    event_dispatcher.unsubscribe(session.clone(), &topic.sub_type, &topic.id).await;
+                    tracing::info!("Revalidation dropped {} {} for session {}: {}", topic.sub_type, topic.id, session.id, reason);
+                    let mut entry = entry;
+                    entry["reason"] = json!(reason);
+                    removed.push(entry);
+                }
+                None => kept.push(entry),
+            }
+        }
+
+        json!({
+            "status": "success",
+            "data": {
+                "kept": kept,
+                "removed": removed,
+                "unchecked": unchecked
+            }
+        })
+    }
+
+    /// Whether the session may subscribe to a topic. Invoice ownership is looked
+    /// up in the store and cached; account and tag topics are checked locally.
+    async fn authorize_subscription(session: &Session, sub_type: &str, id: &str, context: &ConnectionContext) -> Result<bool> {
+        let ConnectionContext { supabase, authorization, .. } = context;
+        let account_id = match session.account_id {
+            Some(account_id) => account_id,
+            None => return Ok(false),
+        };
+
+        match sub_type {
+            "invoice" => authorization.is_authorized(account_id, id, || async {
+                Ok(supabase.get_invoice_record(id).await?
+                    .is_some_and(|invoice| invoice.account_id == account_id as i64))
+            }).await,
+            "account" => Ok(id == account_id.to_string()),
+            "tag" => Ok(id.split_once(':').is_some_and(|(account, _)| account == account_id.to_string())),
+            _ => Ok(false),
+        }
+    }
+
+    /// Response for an action whose cargo feature was compiled out.
+    #[cfg_attr(all(feature = "invoices", feature = "quotes"), allow(dead_code))]
+    fn feature_disabled(action: &str, feature: &str) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "FEATURE_DISABLED",
+            "message": format!("{} is not available: this server was built without the '{}' feature", action, feature)
+        })
+    }
+
+    fn overloaded(retry_after: std::time::Duration) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "OVERLOADED",
+            "message": "Server is busy, retry the subscription later",
+            "retry_after_ms": retry_after.as_millis() as u64
+        })
+    }
+
+    fn rate_limited(retry_after: std::time::Duration) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "RATE_LIMITED",
+            "message": "Too many messages",
+            "retry_after_ms": retry_after.as_millis() as u64
+        })
+    }
+
+    #[cfg_attr(not(feature = "invoices"), allow(dead_code))]
+    fn create_rate_limited(retry_after: std::time::Duration) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "CREATE_RATE_LIMITED",
+            "message": "Too many invoices created, retry later",
+            "retry_after_ms": retry_after.as_millis() as u64
+        })
+    }
+
+    fn account_rate_limited(retry_after: std::time::Duration) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "ACCOUNT_RATE_LIMITED",
+            "message": "Too many requests for this account",
+            "retry_after_ms": retry_after.as_millis() as u64
+        })
+    }
+
+    fn payment_wait_response(settled: Option<Invoice>, timeout: std::time::Duration, currencies: &CurrencyTable, representation: AmountRepresentation) -> serde_json::Value {
+        match settled {
+            Some(invoice) if invoice.status == "paid" => json!({
+                "status": "success",
+                "data": invoices::with_decimal_amount(json!(invoice), currencies, representation)
+            }),
+            Some(_) => json!({
+                "status": "error",
+                "code": "INVOICE_CANCELLED",
+                "message": "Invoice was cancelled"
+            }),
+            None => json!({
+                "status": "error",
+                "code": "TIMEOUT",
+                "message": format!("Invoice not paid within {} seconds", timeout.as_secs())
+            }),
+        }
+    }
+
+    /// Stands in for the response of a request whose handler sends it later
+    /// itself. Nothing is sent in its place.
+    fn deferred() -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    fn send_response(session: &Session, response: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        let response = envelope::wrap(session.envelope_version, response);
+        if session.is_debugging() {
+            tracing::info!("Session {} sent: {}", session.id, response);
+        }
+        session.send(WsMessage::Text(response.to_string().into()))
+    }
+
+    #[cfg(feature = "invoices")]
+    fn invalid_account(account_id: Option<i64>) -> serde_json::Value {
+        json!({
+            "status": "error",
+            "code": "INVALID_ACCOUNT",
+            "message": format!("Cannot create invoices for account {}", account_id.unwrap_or_default())
+        })
+    }
+
+    /// Handles a connection's messages in order, returning one response per
+    /// request. Runs of read-only messages are handled concurrently; any other
+    /// message waits for everything before it, and everything after waits for it.
+    async fn handle_batch(
+        requests: Vec<Result<Message, serde_json::Value>>,
+        session: &Session,
+        context: &ConnectionContext,
+    ) -> Vec<serde_json::Value> {
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut requests = requests.into_iter().peekable();
+
+        while let Some(request) = requests.next() {
+            match request {
+                Err(response) => {
+                    session.stats.record_response(&response);
+                    responses.push(response);
+                }
+                Ok(message) if !message.is_read_only() => {
+                    let response = Self::handle_message(message, session, context).await;
+                    session.stats.record_response(&response);
+                    responses.push(response);
+                }
+                Ok(message) => {
+                    let mut run = vec![message];
+                    while let Some(Ok(next)) = requests.next_if(|request| matches!(request, Ok(next) if next.is_read_only())) {
+                        run.push(next);
+                    }
+
+                    // buffered() keeps responses in request order
+                    let handled: Vec<serde_json::Value> = futures::stream::iter(run)
+                        .map(|message| Self::handle_message(message, session, context))
+                        .buffered(context.config.inbound_batch_concurrency.max(1))
+                        .collect()
+                        .await;
+                    for response in &handled {
+                        session.stats.record_response(response);
+                    }
+                    responses.extend(handled);
+                }
+            }
+        }
+
+        responses
+    }
+
+    fn close_for_shutdown(session: &Session) {
+        tracing::info!("Closing session {} for server shutdown", session.id);
+        let _ = session.send(WsMessage::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "Server shutting down".into(),
+        })));
+    }
+
+    fn close_on_request(session: &Session) {
+        tracing::info!("Closing session {} at the client's request", session.id);
+        let _ = session.send(WsMessage::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "Closed by client".into(),
+        })));
+    }
+
+    fn close_for_panic(session: &Session) {
+        let _ = session.send(WsMessage::Close(Some(CloseFrame {
+            code: CloseCode::Error,
+            reason: "Internal error".into(),
+        })));
+    }
+
+    fn close_for_slow_reader(session: &Session) {
+        tracing::info!("Closing session {} with {} unread replies", session.id, session.send_queue.queued());
+        let _ = session.send(WsMessage::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "Too many unread replies".into(),
+        })));
+    }
+
+    fn close_for_lifetime(session: &Session, lifetime: std::time::Duration) {
+        tracing::info!("Closing session {} after its maximum lifetime of {:?}", session.id, lifetime);
+        let _ = session.send(WsMessage::Close(Some(CloseFrame {
+            code: CloseCode::Again,
+            reason: "Connection lifetime exceeded, reconnect and authenticate again".into(),
+        })));
+    }
+
+    async fn handle_connection(
+        stream: TcpStream,
+        context: ConnectionContext,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let ConnectionContext { event_dispatcher, sessions, supabase, config, account_limits, create_limit, .. } = &context;
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.create_limit = create_limit.clone();
+        let supabase_clone = supabase.clone();
+
+        let mut auto_subscribe = false;
+        let mut last_will = None;
+        let mut trace_id = Uuid::new_v4().to_string();
+        let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+        let ws_stream = accept_hdr_async(stream, |req: &Request, mut res: Response| {
+            check_header_size(req, config.max_handshake_header_bytes)?;
+            if config.require_https {
+                check_forwarded_https(req, peer, &config.trusted_proxies)?;
+            }
+
+            // Reuse the client's trace id when it sends a usable one, and echo it back
+            if let Some(client_trace_id) = req.headers().get(TRACE_ID_HEADER).and_then(|value| value.to_str().ok()) {
+                if is_valid_trace_id(client_trace_id) {
+                    trace_id = client_trace_id.to_string();
+                }
+            }
+            if let Ok(value) = trace_id.parse() {
+                res.headers_mut().insert(TRACE_ID_HEADER, value);
+            }
+
+            let options = ConnectOptions::from_query(req.uri().query());
+            auto_subscribe = options.auto_subscribe;
+            last_will = options.last_will;
+            session.envelope_version = options.envelope_version
+                .unwrap_or(config.default_envelope_version)
+                .min(envelope::LATEST_VERSION);
+            session.delivery_order = options.delivery_order.unwrap_or_default();
+            session.suppress_acks = options.suppress_acks;
+            
+            if let Some(auth) = req.headers().get("Authorization") {
+                println!("Authorization: {:?}", auth);
+                if let Ok(auth_str) = auth.to_str() {
+                    println!("Authorization string: {:?}", auth_str);
+                    if auth_str.starts_with("Bearer ") {
+                        let token = auth_str[7..].trim().to_string();
+                        // Store token in session for async validation after handshake
+                        println!("Token: {:?}", token);
+                        session.auth_token = Some(token);
+                    }
+                }
+            }
+            Ok(res)
+        }).await?;
+
+        let span = tracing::info_span!("connection", session_id = %session.id, trace_id = %trace_id);
+
+        // Validate token after handshake
+        if let Some(token) = &session.auth_token {
+            println!("session.auth_token: {:?}", token);
+            if let Ok(Some(account_id)) = TRACE_ID.scope(trace_id.clone(), supabase_clone.validate_api_key(token)).await {
+                println!("Account ID: {:?}", account_id);
+                session.set_account_id(account_id);
+                session.is_admin = config.admin_account_ids.contains(&account_id);
+                session.account_limit = account_limits.bucket(account_id);
+                tracing::info!("Authenticated session {} for account {}", session.id, account_id);
+            }
+        }
+
+        // A will may only go to a topic the session could subscribe to
+        if let Some(will) = last_will {
+            let allowed = session.is_admin || Self::authorize_subscription(
+                &session, &will.topic.sub_type, &will.topic.id, &context,
+            ).await.unwrap_or(false);
+            if allowed {
+                session.last_will = Some(will);
+            } else {
+                tracing::info!("Ignoring last will for {} {} from session {}", will.topic.sub_type, will.topic.id, session.id);
+            }
+        }
+
+        let (ws_sender, mut ws_receiver) = ws_stream.split();
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        session.sender = Some(sender).unwrap();
+        session.send_queue = SendQueue::new(config.send_queue_high_water_mark);
+        session.event_batch = EventBatch::new(config.event_batch_interval);
+        if let Some(sink) = event_dispatcher.dead_letter_sink() {
+            session.event_batch = session.event_batch.with_dead_letter_sink(sink);
+        }
+        let send_queue = session.send_queue.clone();
+
+        // Store the session
+        sessions.write().await.insert(session.id, session.clone());
+
+        // Subscriptions hold the session's sender, so this must follow the swap above
+        let mut auto_subscribed = false;
+        if let (true, Some(account_id)) = (auto_subscribe, session.account_id) {
+            event_dispatcher.subscribe(session.clone(), "account", &account_id.to_string()).await;
+            tracing::info!("Auto-subscribed session {} to account {}", session.id, account_id);
+            auto_subscribed = true;
+        }
+
+        let connection = ConnectionState::new();
+        let forward_connection = connection.clone();
+
+        // Spawn a task to forward messages from the channel to the websocket
+        let session_id = session.id;
+        let send_task = AbortOnDrop(Some(tokio::spawn(forward_messages(receiver, ws_sender, send_queue, forward_connection, session_id))));
+
+        let mut rate_limit = (config.rate_limit_burst > 0)
+            .then(|| TokenBucket::new(config.rate_limit_burst, config.rate_limit_refill));
+
+        // Only the first frame has a deadline, so idle but active clients are unaffected.
+        // An auto-subscribed session has nothing it needs to send.
+        let mut first_message_deadline = (!config.first_message_timeout.is_zero() && !auto_subscribed)
+            .then(|| tokio::time::Instant::now() + config.first_message_timeout);
+
+        let expires_at = (!config.max_connection_lifetime.is_zero())
+            .then(|| tokio::time::Instant::now() + config.max_connection_lifetime);
+        let expired = async {
+            match expires_at {
+                Some(expires_at) => tokio::time::sleep_until(expires_at).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(expired);
+
+        // Handle incoming messages until the client goes away or the server shuts down.
+        // In-flight requests are abandoned on shutdown so a slow backend call
+        // cannot hold the connection open.
+        // Set when the client goes away without a Close frame
+        let mut dropped = false;
+        let mut close_received = false;
+        loop {
+            let msg = tokio::select! {
+                msg = ws_receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => {
+                        dropped = !close_received;
+                        break;
+                    }
+                },
+                _ = shutdown.changed() => {
+                    Self::close_for_shutdown(&session);
+                    break;
+                }
+                _ = connection.closed() => {
+                    tracing::debug!("Forward task for session {} exited, closing", session.id);
+                    break;
+                }
+                _ = tokio::time::sleep_until(first_message_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if first_message_deadline.is_some() => {
+                    tracing::info!("Session {} sent nothing within {:?}, closing", session.id, config.first_message_timeout);
+                    let _ = session.send(WsMessage::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "No message received".into(),
+                    })));
+                    break;
+                }
+                _ = &mut expired => {
+                    Self::close_for_lifetime(&session, config.max_connection_lifetime);
+                    break;
+                }
+            };
+            first_message_deadline = None;
+
+            if connection.is_closed() {
+                break;
+            }
+
+            // More requests while replies pile up unread: the client isn't reading
+            if config.slow_reader_limit > 0 && session.send_queue.queued() >= config.slow_reader_limit {
+                match config.slow_reader_action {
+                    SlowReaderAction::Close => {
+                        Self::close_for_slow_reader(&session);
+                        break;
+                    }
+                    SlowReaderAction::Pause => {
+                        tracing::info!("Pausing requests from session {} until it reads its replies", session.id);
+                        tokio::select! {
+                            _ = session.send_queue.drained_below(config.slow_reader_limit / 2 + 1) => {}
+                            _ = shutdown.changed() => {
+                                Self::close_for_shutdown(&session);
+                                break;
+                            }
+                            _ = connection.closed() => break,
+                            _ = &mut expired => {
+                                Self::close_for_lifetime(&session, config.max_connection_lifetime);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Take whatever else has already arrived, up to the batch size
+            let mut frames = vec![msg];
+            while frames.len() < config.inbound_batch_size {
+                match ws_receiver.next().now_or_never() {
+                    Some(Some(msg)) => frames.push(msg),
+                    _ => break,
+                }
+            }
+
+            let mut requests = Vec::with_capacity(frames.len());
+            let mut failed = false;
+            for msg in frames {
+                match msg {
+                    Ok(msg) => {
+                        close_received |= msg.is_close();
+                        if let Ok(text) = msg.to_text() {
+                            println!("text in handle connection: {:?}", text);
+                            if session.is_debugging() {
+                                tracing::info!("Session {} received: {}", session.id, text);
+                            }
+                            let retry_after = rate_limit.as_mut().and_then(|bucket| bucket.try_acquire().err());
+                            requests.push(match retry_after {
+                                Some(retry_after) => Err(Self::rate_limited(retry_after)),
+                                None => parse_message(text, config.strict_messages),
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        tracing::debug!("WebSocket error: {}", e);
+                        dropped = !close_received;
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+
+            // Nothing after a close request is handled
+            let closing = requests.iter().position(|request| matches!(request, Ok(Message::Close)));
+            if let Some(position) = closing {
+                requests.truncate(position + 1);
+            }
+
+            let request_count = requests.len();
+            // A panicking handler must not skip the cleanup below
+            let batch = AssertUnwindSafe(Self::handle_batch(requests, &session, &context)).catch_unwind();
+            let responses = tokio::select! {
+                responses = TRACE_ID.scope(trace_id.clone(), batch.instrument(span.clone())) => match responses {
+                    Ok(responses) => responses,
+                    Err(panic) => {
+                        tracing::error!("Request handler panicked for session {}: {}", session.id, panic_message(&*panic));
+                        if config.close_on_handler_panic {
+                            Self::close_for_panic(&session);
+                            break;
+                        }
+                        vec![json!({
+                            "status": "error",
+                            "code": "INTERNAL_ERROR",
+                            "message": "Internal error handling the request"
+                        }); request_count]
+                    }
+                },
+                _ = shutdown.changed() => {
+                    Self::close_for_shutdown(&session);
+                    break;
+                }
+                _ = &mut expired => {
+                    Self::close_for_lifetime(&session, config.max_connection_lifetime);
+                    break;
+                }
+            };
+
+            for response in responses.into_iter().filter(|response| !response.is_null()) {
+                if let Err(e) = Self::send_response(&session, response) {
+                    tracing::debug!("Failed to send response, client likely disconnected: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+            if failed {
+                break;
+            }
+            if closing.is_some() {
+                Self::close_on_request(&session);
+                // Let the responses and the Close frame go out before the forward task is stopped
+                let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, connection.closed()).await;
+                break;
+            }
+        }
+
+        // Mark connection as closed
+        connection.close();
+        
+        if let (true, Some(will)) = (dropped, &session.last_will) {
+            let delivered = event_dispatcher.dispatch(&will.topic, &json!({
+                "type": "session.will",
+                "data": {
+                    "session_id": session.id,
+                    "payload": will.payload
+                }
+            })).await;
+            tracing::info!("Session {} dropped, sent its last will to {} subscribers", session.id, delivered);
+        }
+
         // Clean up session
+        event_dispatcher.remove_session(session.id).await;
         sessions.write().await.remove(&session.id);
         tracing::info!("Connection closed for session: {}", session.id);
+
+        // Left to flush the Close frame on its own
+        send_task.detach();
         
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+/// Adds the store's error detail to a response when the server runs in debug mode.
+#[cfg_attr(not(feature = "invoices"), allow(dead_code))]
+fn with_debug_detail(mut response: serde_json::Value, error: &anyhow::Error, debug: bool) -> serde_json::Value {
+    if debug {
+        if let Some(error) = error.downcast_ref::<SupabaseError>() {
+            response["detail"] = error.detail();
+        }
+    }
+    response
+}
+
+/// Tells the client when to retry a request the store turned away for rate limiting.
+fn with_retry_hint(mut response: serde_json::Value, error: &anyhow::Error) -> serde_json::Value {
+    if let Some(error) = error.downcast_ref::<SupabaseError>().filter(|error| error.is_rate_limited()) {
+        response["code"] = json!("BACKEND_RATE_LIMITED");
+        if let Some(retry_after) = error.retry_after {
+            response["retry_after_ms"] = json!(retry_after.as_millis() as u64);
+        }
+    }
+    response
+}
+
+/// How a connection's forward task ended.
+#[derive(Debug, PartialEq)]
+enum ForwardExit {
+    /// The session stopped sending and the socket was closed cleanly.
+    Finished,
+    /// The client went away before a frame could be written.
+    Disconnected,
+    /// Writing failed for another reason.
+    Failed,
+}
+
+/// Whether a send error only means the client is already gone.
+fn is_disconnect(error: &tungstenite::Error) -> bool {
+    match error {
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => true,
+        tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake | ProtocolError::SendAfterClosing) => true,
+        tungstenite::Error::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Aborts the task when dropped, so a connection aborted mid-way also stops
+/// writing to its socket.
+struct AbortOnDrop<T>(Option<JoinHandle<T>>);
+
+impl<T> AbortOnDrop<T> {
+    fn detach(mut self) {
+        self.0.take();
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.0.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Writes a session's queued frames to its socket until the session stops
+/// sending, the connection closes, or a write fails, then marks the connection
+/// closed so the receive loop stops too.
+async fn forward_messages<S>(
+    mut receiver: UnboundedReceiver<WsMessage>,
+    mut ws_sender: S,
+    send_queue: SendQueue,
+    connection: ConnectionState,
+    session_id: Uuid,
+) -> ForwardExit
+where
+    S: Sink<WsMessage, Error = tungstenite::Error> + Unpin,
+{
+    let mut exit = ForwardExit::Finished;
+    while let Some(message) = receiver.next().await {
+        send_queue.pop();
+        // A queued Close frame is still written after the receive loop has exited
+        let is_close = message.is_close();
+        if connection.is_closed() && !is_close {
+            break;
+        }
+        // send() flushes, so a frame is either fully written or reported as failed
+        if let Err(e) = ws_sender.send(message).await {
+            exit = if is_disconnect(&e) {
+                tracing::debug!("Session {} disconnected before a frame was written: {}", session_id, e);
+                ForwardExit::Disconnected
+            } else {
+                tracing::warn!("Failed to write to session {}: {}", session_id, e);
+                ForwardExit::Failed
+            };
+            break;
+        }
+        if is_close {
+            break;
+        }
+    }
+
+    if exit == ForwardExit::Finished {
+        // Completes the closing handshake unless the client already did
+        if let Err(e) = ws_sender.close().await {
+            if !is_disconnect(&e) {
+                tracing::warn!("Failed to close socket for session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    // Wake the receive loop if the write side finished first
+    connection.close();
+    exit
+}
+
+/// Parses an inbound frame. In strict mode, fields the action doesn't define
+/// are an error naming the field rather than being ignored.
+fn parse_message(text: &str, strict: bool) -> Result<Message, serde_json::Value> {
+    let invalid = || json!({
+        "status": "error",
+        "message": "Invalid message format"
+    });
+    if !strict {
+        return serde_json::from_str(text).map_err(|_| invalid());
+    }
+
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|_| invalid())?;
+    let action = value.get("action").and_then(|action| action.as_str()).unwrap_or_default();
+    let unknown_field = |field: String| json!({
+        "status": "error",
+        "code": "UNKNOWN_FIELD",
+        "message": format!("Unknown field '{}' for {}", field, action),
+        "field": field
+    });
+    if let (Some(object), Some(fields)) = (value.as_object(), Message::fields(action)) {
+        if let Some(field) = object.keys().find(|key| *key != "action" && !fields.contains(&key.as_str())) {
+            return Err(unknown_field(field.clone()));
+        }
+    }
+    // Each of subscribe_many's subscriptions takes the fields of a subscribe
+    if action == "subscribe_many" {
+        let fields = Message::fields("subscribe").unwrap_or_default();
+        let subscriptions = value["subscriptions"].as_array().into_iter().flatten();
+        for (i, subscription) in subscriptions.enumerate() {
+            if let Some(field) = subscription.as_object().and_then(|object| object.keys().find(|key| !fields.contains(&key.as_str()))) {
+                return Err(unknown_field(format!("subscriptions[{}].{}", i, field)));
+            }
+        }
+    }
+    serde_json::from_value(value).map_err(|_| invalid())
+}
+
+/// The `fetch_invoice` response for a lookup result. Soft-deleted invoices are
+/// `GONE` so clients know to stop polling, unless `report_deleted` is off.
+fn fetch_invoice_response(found: Option<(Invoice, Option<Vec<PaymentOption>>)>, report_deleted: bool, currencies: &CurrencyTable, representation: AmountRepresentation) -> serde_json::Value {
+    match found {
+        Some((invoice, _)) if invoice.is_deleted() && report_deleted => json!({
+            "status": "error",
+            "code": "GONE",
+            "message": "Invoice has been deleted",
+            "deleted_at": invoice.deleted_at
+        }),
+        Some((invoice, payment_options)) if !invoice.is_deleted() => {
+            let mut data = json!({ "invoice": invoices::with_decimal_amount(json!(invoice), currencies, representation) });
+            if let Some(payment_options) = payment_options {
+                data["payable_currencies"] = json!(invoices::payable_currencies(&payment_options));
+                data["payment_options"] = json!(payment_options);
+            }
+            json!({
+                "status": "success",
+                "data": data
+            })
+        }
+        _ => json!({
+            "status": "error",
+            "code": "NOT_FOUND",
+            "message": "Invoice not found"
+        }),
+    }
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+/// Refuses handshakes that didn't come through a trusted proxy reporting
+/// `X-Forwarded-Proto: https`. A client could send the header itself, so it is
+/// only believed from `trusted_proxies`.
+fn check_forwarded_https(req: &Request, peer: Option<IpAddr>, trusted_proxies: &HashSet<IpAddr>) -> Result<(), ErrorResponse> {
+    let trusted = peer.is_some_and(|peer| trusted_proxies.contains(&peer));
+    let proto = req.headers().get("X-Forwarded-Proto").and_then(|value| value.to_str().ok());
+    // Proxies in a chain append their own value; the first is the client's
+    let https = proto.and_then(|proto| proto.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+
+    if !(trusted && https) {
+        tracing::warn!("Rejecting handshake from {:?} not forwarded over HTTPS (X-Forwarded-Proto: {:?})", peer, proto);
+        let mut response = ErrorResponse::new(Some("HTTPS required".to_string()));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return Err(response);
+    }
+
+    Ok(())
+}
+
+/// Refuses handshakes whose headers add up to more than `max_bytes`, counted as
+/// they appear on the wire (`name: value\r\n`).
+fn check_header_size(req: &Request, max_bytes: usize) -> Result<(), ErrorResponse> {
+    let size: usize = req.headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+
+    if size > max_bytes {
+        tracing::warn!("Rejecting handshake with {} bytes of headers (limit {})", size, max_bytes);
+        let mut response = ErrorResponse::new(Some("Request header fields too large".to_string()));
+        *response.status_mut() = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        return Err(response);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::test_support::{self, spawn_memory_store, spawn_store, StoreResponse};
+
+    #[test]
+    fn test_oversized_handshake_headers_are_refused() {
+        let request = Request::builder()
+            .uri("/")
+            .header("Authorization", "Bearer key")
+            .body(())
+            .unwrap();
+        assert!(check_header_size(&request, 1024).is_ok());
+
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Padding", "a".repeat(2048))
+            .body(())
+            .unwrap();
+        let response = check_header_size(&request, 1024).unwrap_err();
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_plaintext_proxied_handshake_is_refused_when_https_is_required() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted: HashSet<IpAddr> = [proxy].into_iter().collect();
+        let forwarded = |proto: &str| Request::builder()
+            .uri("/")
+            .header("X-Forwarded-Proto", proto)
+            .body(())
+            .unwrap();
+
+        assert!(check_forwarded_https(&forwarded("https"), Some(proxy), &trusted).is_ok());
+        assert!(check_forwarded_https(&forwarded("https, http"), Some(proxy), &trusted).is_ok());
+
+        let response = check_forwarded_https(&forwarded("http"), Some(proxy), &trusted).unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // The header means nothing from a peer that isn't a trusted proxy
+        let direct: IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(check_forwarded_https(&forwarded("https"), Some(direct), &trusted).is_err());
+        let bare = Request::builder().uri("/").body(()).unwrap();
+        assert!(check_forwarded_https(&bare, Some(proxy), &trusted).is_err());
+    }
+
+    #[test]
+    fn test_store_rate_limit_is_passed_on_with_retry_hint() {
+        let error: anyhow::Error = SupabaseError {
+            status: 429,
+            body: String::new(),
+            retry_after: Some(std::time::Duration::from_secs(2)),
+        }.into();
+        let response = with_retry_hint(json!({ "status": "error", "message": "Error fetching prices" }), &error);
+        assert_eq!(response["code"], "BACKEND_RATE_LIMITED");
+        assert_eq!(response["retry_after_ms"], 2000);
+
+        let other = anyhow::anyhow!("connection refused");
+        let response = with_retry_hint(json!({ "status": "error" }), &other);
+        assert!(response.get("code").is_none());
+    }
+
+    #[test]
+    fn test_supabase_error_detail_only_in_debug_mode() {
+        let error: anyhow::Error = SupabaseError {
+            status: 400,
+            body: json!({ "code": "23502", "message": "null value in column \"currency\"" }).to_string(),
+            retry_after: None,
+        }.into();
+        let response = json!({ "status": "error", "message": "Failed to create invoice" });
+
+        let debug = with_debug_detail(response.clone(), &error, true);
+        assert_eq!(debug["detail"]["code"], "23502");
+        assert_eq!(debug["detail"]["status"], 400);
+
+        let production = with_debug_detail(response.clone(), &error, false);
+        assert!(production.get("detail").is_none());
+    }
+
+    #[test]
+    fn test_typo_in_field_name_in_lenient_and_strict_mode() {
+        let optional_typo = r#"{"action":"create_invoice","amount":1000,"currency":"USD","memoo":"Coffee"}"#;
+        let required_typo = r#"{"action":"create_invoice","amont":1000,"currency":"USD"}"#;
+
+        // Lenient: an optional typo is ignored, a required one fails to parse
+        assert!(matches!(parse_message(optional_typo, false), Ok(Message::CreateInvoice { memo: None, .. })));
+        assert_eq!(parse_message(required_typo, false).unwrap_err()["message"], "Invalid message format");
+
+        let err = parse_message(optional_typo, true).unwrap_err();
+        assert_eq!(err["code"], "UNKNOWN_FIELD");
+        assert_eq!(err["field"], "memoo");
+        assert_eq!(parse_message(required_typo, true).unwrap_err()["message"], "Unknown field 'amont' for create_invoice");
+
+        // Well-formed messages parse the same in both modes
+        let valid = r#"{"action":"create_invoice","amount":1000,"currency":"USD","memo":"Coffee"}"#;
+        assert!(matches!(parse_message(valid, true), Ok(Message::CreateInvoice { memo: Some(_), .. })));
+        assert!(matches!(parse_message(r#"{"action":"ping"}"#, true), Ok(Message::Ping)));
+    }
+
+    #[test]
+    fn test_strict_mode_checks_each_subscription_of_subscribe_many() {
+        let typo = r#"{"action":"subscribe_many","subscriptions":[{"type":"invoice","id":"inv_1"},{"type":"invoice","id":"inv_2","filtr":{}}]}"#;
+
+        assert!(matches!(parse_message(typo, false), Ok(Message::SubscribeMany { .. })));
+        let err = parse_message(typo, true).unwrap_err();
+        assert_eq!(err["code"], "UNKNOWN_FIELD");
+        assert_eq!(err["field"], "subscriptions[1].filtr");
+        assert_eq!(err["message"], "Unknown field 'subscriptions[1].filtr' for subscribe_many");
+
+        let valid = r#"{"action":"subscribe_many","subscriptions":[{"type":"invoice","id":"inv_1","filter":{},"fields":["status"],"view":"full"}]}"#;
+        assert!(matches!(parse_message(valid, true), Ok(Message::SubscribeMany { .. })));
+    }
+
+    #[test]
+    fn test_strict_field_lists_cover_every_serialized_field() {
+        let messages = [
+            json!({ "action": "subscribe", "type": "invoice", "id": "inv_1", "filter": { "events": ["invoice.paid"] }, "fields": ["status"], "view": "full" }),
+            json!({ "action": "subscribe_many", "subscriptions": [{ "type": "invoice", "id": "inv_1" }], "suppress_acks": true }),
+            json!({ "action": "unsubscribe", "type": "invoice", "id": "inv_1", "subscription_id": "sub_1" }),
+            json!({ "action": "pause_subscription", "type": "invoice", "id": "inv_1" }),
+            json!({ "action": "resume_subscription", "type": "invoice", "id": "inv_1" }),
+            json!({ "action": "update_subscription_filter", "type": "invoice", "id": "inv_1", "filter": {} }),
+            json!({ "action": "recent_events", "type": "invoice", "id": "inv_1", "limit": 5 }),
+            json!({ "action": "revalidate_subscriptions" }),
+            json!({ "action": "selftest" }),
+            json!({ "action": "close" }),
+            json!({ "action": "fetch_invoice", "id": "inv_1", "expand": true, "view": "compact" }),
+            json!({ "action": "create_invoice", "amount": 1, "currency": "USD", "webhook_url": "a", "redirect_url": "b",
+                    "memo": "c", "test": true, "account_id": 1, "tags": ["pos"] }),
+            json!({ "action": "list_prices", "limit": 1 }),
+            json!({ "action": "convert_price", "quote_currency": "USD", "base_currency": "BTC", "quote_value": 1.0 }),
+            json!({ "action": "cancel_invoice", "uid": "inv_1" }),
+            json!({ "action": "fetch_invoice_history", "id": "inv_1", "limit": 1 }),
+            json!({ "action": "rebroadcast_invoice", "id": "inv_1" }),
+            json!({ "action": "wait_for_payment", "id": "inv_1", "timeout_secs": 1 }),
+            json!({ "action": "broadcast_account_event", "account_id": 1, "event": {} }),
+            json!({ "action": "set_session_debug", "session_id": Uuid::nil(), "enabled": true }),
+            json!({ "action": "set_context", "values": { "account_id": 1 } }),
+            json!({ "action": "get_context" }),
+            json!({ "action": "connection_stats" }),
+            json!({ "action": "ping" }),
+            json!({ "action": "server_time" }),
+        ];
+
+        for message in messages {
+            let parsed: Message = serde_json::from_value(message.clone()).unwrap();
+            let serialized = serde_json::to_value(&parsed).unwrap();
+            let action = serialized["action"].as_str().unwrap();
+            let fields = Message::fields(action).unwrap();
+            for key in serialized.as_object().unwrap().keys().filter(|key| *key != "action") {
+                assert!(fields.contains(&key.as_str()), "{} is missing {}", action, key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetch_invoice_distinguishes_missing_from_deleted() {
+        let invoice = |deleted_at: Option<&str>| Invoice {
+            deleted_at: deleted_at.map(str::to_string),
+            ..test_support::invoice("inv_123", "unpaid")
+        };
+
+        let live = fetch_invoice_response(Some((invoice(None), Some(Vec::new()))), true, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert_eq!(live["status"], "success");
+        assert_eq!(live["data"]["invoice"]["uid"], "inv_123");
+        assert_eq!(live["data"]["invoice"]["amount_decimal"], "10.00");
+        assert_eq!(live["data"]["payment_options"], json!([]));
+        assert_eq!(live["data"]["payable_currencies"], json!([]));
+        assert!(live["data"]["invoice"].get("deleted_at").is_none());
+
+        // Without expansion the payment options are left out
+        let unexpanded = fetch_invoice_response(Some((invoice(None), None)), true, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert!(unexpanded["data"].get("payment_options").is_none());
+        assert!(unexpanded["data"].get("payable_currencies").is_none());
+
+        let missing = fetch_invoice_response(None, true, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert_eq!(missing["code"], "NOT_FOUND");
+
+        let deleted = fetch_invoice_response(Some((invoice(Some("2024-01-02T00:00:00Z")), None)), true, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert_eq!(deleted["code"], "GONE");
+        assert_eq!(deleted["deleted_at"], "2024-01-02T00:00:00Z");
+
+        // With reporting off a deleted invoice looks like it never existed
+        let hidden = fetch_invoice_response(Some((invoice(Some("2024-01-02T00:00:00Z")), None)), false, &CurrencyTable::default(), AmountRepresentation::MinorUnits);
+        assert_eq!(hidden, missing);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_closed_after_first_message_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            first_message_timeout: std::time::Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+
+        tokio::spawn(async move {
+            let (_shutdown, shutdown_rx) = watch::channel(false);
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = AnypayEventsServer::handle_connection(stream, connection_context(config, "http://127.0.0.1:9"), shutdown_rx).await;
+        });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(2), client.next())
+            .await
+            .expect("connection was not closed after the deadline");
+
+        match frame {
+            Some(Ok(WsMessage::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_are_sent_a_close_frame_before_run_returns() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let server = Arc::new(AnypayEventsServer::new(&addr, "http://127.0.0.1:9", "anon", "service"));
+        let running = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await })
+        };
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client = loop {
+                match tokio_tungstenite::connect_async(format!("ws://{}", addr)).await {
+                    Ok((client, _)) => break client,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+            clients.push(client);
+        }
+        while server.sessions.read().await.len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        server.shutdown_handle().shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(2), running)
+            .await
+            .expect("run did not return after shutdown")
+            .unwrap()
+            .unwrap();
+
+        // Whatever run sent before returning is still waiting to be read
+        for mut client in clients {
+            match client.next().await {
+                Some(Ok(WsMessage::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_connections_still_open_after_drain_timeout() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let drain_timeout = std::time::Duration::from_millis(300);
+        let server = Arc::new(AnypayEventsServer::new(&addr, "http://127.0.0.1:9", "anon", "service")
+            .with_config(ServerConfig {
+                shutdown_drain_timeout: drain_timeout,
+                ..ServerConfig::default()
+            }));
+        let running = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await })
+        };
+
+        // A client that never finishes the handshake, and one that never reads its Close frame.
+        // Connections are accepted in order, so once the second has a session both are open.
+        let _stalled = loop {
+            match TcpStream::connect(&addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        while server.sessions.read().await.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let started = std::time::Instant::now();
+        server.shutdown_handle().shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(2), running)
+            .await
+            .expect("shutdown did not finish after the drain timeout")
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= drain_timeout);
+        assert!(server.sessions.read().await.is_empty());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_connection_is_closed_after_max_lifetime() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            max_connection_lifetime: std::time::Duration::from_millis(300),
+            ..ServerConfig::default()
+        };
+
+        tokio::spawn(async move {
+            let (_shutdown, shutdown_rx) = watch::channel(false);
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = AnypayEventsServer::handle_connection(stream, connection_context(config, "http://127.0.0.1:9"), shutdown_rx).await;
+        });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let opened = std::time::Instant::now();
+
+        // An active client is still cut off at the deadline
+        let mut pongs = 0;
+        loop {
+            client.send(WsMessage::Text(r#"{"action":"ping"}"#.to_string())).await.unwrap();
+            let frame = tokio::time::timeout(std::time::Duration::from_secs(2), client.next())
+                .await
+                .expect("connection was not closed after its lifetime");
+            match frame {
+                Some(Ok(WsMessage::Text(_))) => pongs += 1,
+                Some(Ok(WsMessage::Close(Some(frame)))) => {
+                    assert_eq!(frame.code, CloseCode::Again);
+                    assert!(frame.reason.contains("authenticate"));
+                    break;
+                }
+                other => panic!("expected a pong or close frame, got {:?}", other),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(pongs > 0);
+        assert!(opened.elapsed() >= std::time::Duration::from_millis(300));
+    }
+
+    /// Serves one connection with the default config and an unreachable store.
+    /// Returns the address to connect to, the session map and dispatcher it
+    /// uses, and the task handling the connection.
+    async fn spawn_connection() -> (std::net::SocketAddr, Arc<RwLock<HashMap<Uuid, Session>>>, Arc<EventDispatcher>, JoinHandle<()>) {
+        spawn_connection_with(ServerConfig::default()).await
+    }
+
+    async fn spawn_connection_with(config: ServerConfig) -> (std::net::SocketAddr, Arc<RwLock<HashMap<Uuid, Session>>>, Arc<EventDispatcher>, JoinHandle<()>) {
+        spawn_connection_to(config, "http://127.0.0.1:9").await
+    }
+
+    /// As `spawn_connection_with`, against the store at `store_url`.
+    async fn spawn_connection_to(config: ServerConfig, store_url: &str) -> (std::net::SocketAddr, Arc<RwLock<HashMap<Uuid, Session>>>, Arc<EventDispatcher>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let context = connection_context(config, store_url);
+        let (sessions, dispatcher) = (context.sessions.clone(), context.event_dispatcher.clone());
+
+        let connection = tokio::spawn(async move {
+            let (_shutdown, shutdown_rx) = watch::channel(false);
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = AnypayEventsServer::handle_connection(stream, context, shutdown_rx).await;
+        });
+        (addr, sessions, dispatcher, connection)
+    }
+
+    /// Fresh server-wide state with `config`, against the store at `store_url`.
+    fn connection_context(config: ServerConfig, store_url: &str) -> ConnectionContext {
+        ConnectionContext {
+            event_dispatcher: Arc::new(EventDispatcher::new()),
+            sessions: Arc::default(),
+            supabase: Arc::new(SupabaseClient::new(store_url, "anon", "service")),
+            config: Arc::new(config),
+            authorization: Arc::new(AuthorizationCache::new(std::time::Duration::from_secs(30))),
+            account_limits: Arc::new(AccountRateLimits::new(0, std::time::Duration::ZERO)),
+            create_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_subscribed_client_may_stay_silent_past_first_message_deadline() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        // Every api key belongs to account 7
+        let (url, _requests) = spawn_store(|_| async { StoreResponse::json(json!({"account_id": 7})) }).await;
+        let config = ServerConfig {
+            first_message_timeout: std::time::Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+        let (addr, _sessions, dispatcher, _connection) = spawn_connection_to(config, &url).await;
+        let mut request = format!("ws://{}/?auto_subscribe=true", addr).into_client_request().unwrap();
+        request.headers_mut().insert("Authorization", "Bearer key".parse().unwrap());
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        // Well past the deadline the listener is still connected and served
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let invoice = Invoice { account_id: 7, ..test_support::invoice("inv_1", "paid") };
+        assert_eq!(dispatcher.dispatch_invoice_event("invoice.paid", &invoice).await, 1);
+        match tokio::time::timeout(std::time::Duration::from_secs(2), client.next()).await {
+            Ok(Some(Ok(WsMessage::Text(text)))) => {
+                assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap()["type"], "invoice.paid");
+            }
+            other => panic!("expected the event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_is_charged_before_a_message_is_parsed() {
+        let config = ServerConfig {
+            rate_limit_burst: 1,
+            rate_limit_refill: std::time::Duration::from_secs(60),
+            ..ServerConfig::default()
+        };
+        let (addr, _sessions, _dispatcher, _connection) = spawn_connection_with(config).await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+
+        // A malformed message spends the only token, so the ping after it is refused
+        for text in ["not json".to_string(), json!({"action": "ping"}).to_string()] {
+            client.send(WsMessage::Text(text.into())).await.unwrap();
+        }
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let Some(Ok(WsMessage::Text(text))) = client.next().await else {
+                panic!("expected a reply");
+            };
+            replies.push(serde_json::from_str::<serde_json::Value>(&text).unwrap());
+        }
+        assert_eq!(replies[0]["message"], "Invalid message format");
+        let limited = &replies[1];
+        assert_eq!(limited["code"], "RATE_LIMITED");
+        assert!(limited["retry_after_ms"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_last_will_is_sent_only_when_the_connection_drops() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        // Every api key belongs to account 7
+        let (url, _requests) = spawn_store(|_| async { StoreResponse::json(json!({"account_id": 7})) }).await;
+        let will = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("will_topic", "account:7")
+            .append_pair("will_payload", r#"{"reason":"pos offline"}"#)
+            .finish();
+
+        for abrupt in [true, false] {
+            let (addr, sessions, dispatcher, connection) = spawn_connection_to(ServerConfig::default(), &url).await;
+            let (sender, mut observer) = futures::channel::mpsc::unbounded();
+            dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "account", "7").await;
+
+            let mut request = format!("ws://{}/?{}", addr, will).into_client_request().unwrap();
+            request.headers_mut().insert("Authorization", "Bearer key".parse().unwrap());
+            let (mut client, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            // A reply means the handshake, and the will, are settled
+            client.send(WsMessage::Text(json!({"action": "ping"}).to_string().into())).await.unwrap();
+            client.next().await.unwrap().unwrap();
+            let session_id = *sessions.read().await.keys().next().unwrap();
+
+            if abrupt {
+                drop(client);
+            } else {
+                client.close(None).await.unwrap();
+            }
+            connection.await.unwrap();
+
+            if abrupt {
+                let WsMessage::Text(text) = observer.next().await.unwrap() else {
+                    panic!("expected a text frame");
+                };
+                let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(event["type"], "session.will");
+                assert_eq!(event["data"]["session_id"], session_id.to_string());
+                assert_eq!(event["data"]["payload"], json!({"reason": "pos offline"}));
+            } else {
+                assert!(observer.try_recv().is_err(), "a clean close must not send the will");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_that_never_reads_is_paused_or_closed() {
+        const LIMIT: usize = 20;
+        const REQUESTS: usize = 1000;
+
+        for action in [SlowReaderAction::Pause, SlowReaderAction::Close] {
+            let config = ServerConfig {
+                slow_reader_limit: LIMIT,
+                slow_reader_action: action,
+                max_context_bytes: 20_000,
+                ..ServerConfig::default()
+            };
+            let (addr, sessions, _, connection) = spawn_connection_with(config).await;
+            // A small receive buffer, so replies back up on the server soon
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.set_recv_buffer_size(4096).unwrap();
+            let stream = socket.connect(addr).await.unwrap();
+            let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{}", addr), stream).await.unwrap();
+
+            // Every get_context reply then carries 16KB
+            let set_context = json!({ "action": "set_context", "values": { "note": "x".repeat(16_000) } });
+            client.send(WsMessage::Text(set_context.to_string())).await.unwrap();
+            client.next().await.unwrap().unwrap();
+            let send_queue = sessions.read().await.values().next().unwrap().send_queue.clone();
+
+            let mut sent = 0;
+            while sent < REQUESTS && client.send(WsMessage::Text(r#"{"action":"get_context"}"#.to_string())).await.is_ok() {
+                sent += 1;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+            match action {
+                SlowReaderAction::Pause => {
+                    // Requests stopped being read at the limit, and none were dropped
+                    assert_eq!(sent, REQUESTS);
+                    assert!(send_queue.queued() <= LIMIT, "{} queued", send_queue.queued());
+                    for _ in 0..REQUESTS {
+                        match client.next().await {
+                            Some(Ok(WsMessage::Text(_))) => {}
+                            other => panic!("expected a reply, got {:?}", other),
+                        }
+                    }
+                    assert!(!connection.is_finished());
+                }
+                SlowReaderAction::Close => {
+                    // The client is never going to read the close frame, so it is cut off
+                    tokio::time::timeout(std::time::Duration::from_secs(2), connection).await.unwrap().unwrap();
+                    assert!(sessions.read().await.is_empty());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_suppressed_acks_leave_only_the_summary() {
+        let (addr, _sessions, dispatcher, _connection) = spawn_connection().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/?suppress_acks=true", addr)).await.unwrap();
+
+        client.send(WsMessage::Text(json!({
+            "action": "subscribe_many",
+            "subscriptions": [
+                { "type": "invoice", "id": "inv_1" },
+                { "type": "account", "id": "42", "view": "compact" },
+                { "type": "logs", "id": "info" }
+            ]
+        }).to_string())).await.unwrap();
+        client.send(WsMessage::Text(r#"{"action":"ping"}"#.to_string())).await.unwrap();
+
+        let summary: serde_json::Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(summary["status"], "success");
+        assert_eq!(summary["data"]["subscribed"], 2);
+        assert_eq!(summary["data"]["failed"][0]["id"], "info");
+        assert_eq!(summary["data"]["failed"][0]["code"], "FORBIDDEN");
+        assert!(summary["data"].get("results").is_none());
+        assert_eq!(dispatcher.topics().await.len(), 2);
+
+        // The ping's answer comes next: nothing was sent per subscription
+        let pong: serde_json::Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(pong["type"], "pong");
+
+        // A request can still ask for them
+        client.send(WsMessage::Text(json!({
+            "action": "subscribe_many",
+            "subscriptions": [{ "type": "invoice", "id": "inv_2" }],
+            "suppress_acks": false
+        }).to_string())).await.unwrap();
+        let response: serde_json::Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert!(response["data"]["results"][0]["subscription_id"].as_str().unwrap().starts_with("sub_"));
+    }
+
+    #[tokio::test]
+    async fn test_close_action_removes_session_and_sends_close_frame() {
+        let (addr, sessions, dispatcher, connection) = spawn_connection().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        client.send(WsMessage::Text(r#"{"action":"subscribe","type":"invoice","id":"inv_1"}"#.to_string())).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        assert_eq!(sessions.read().await.len(), 1);
+
+        // The ping after the close in the same burst is never answered
+        client.send(WsMessage::Text(r#"{"action":"close"}"#.to_string())).await.unwrap();
+        client.send(WsMessage::Text(r#"{"action":"ping"}"#.to_string())).await.unwrap();
+        let frame = client.next().await;
+        let Some(Ok(WsMessage::Text(text))) = frame else {
+            panic!("expected the close response, got {:?}", frame);
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap()["message"], "Closing connection");
+        match tokio::time::timeout(std::time::Duration::from_secs(2), client.next()).await.unwrap() {
+            Some(Ok(WsMessage::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Normal),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+
+        // Cleanup ran without waiting for the client to hang up
+        tokio::time::timeout(std::time::Duration::from_secs(2), connection).await.unwrap().unwrap();
+        assert!(sessions.read().await.is_empty());
+        assert!(dispatcher.topics().await.is_empty());
+    }
+
+    /// Panics on log lines mentioning `pattern`, raising a panic inside
+    /// whichever handler logs one.
+    struct PanicOnLog(&'static str);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for PanicOnLog {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Message(String);
+            impl tracing::field::Visit for Message {
+                fn record_debug(&mut self, _field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0.push_str(&format!("{:?}", value));
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            if message.0.contains(self.0) {
+                panic!("injected failure");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_handler_still_cleans_up_session() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let logs = LogStream::default();
+        let mut lines = logs.subscribe();
+        let subscriber = tracing_subscriber::registry()
+            .with(PanicOnLog("inv_panic"))
+            .with(logs.layer(tracing::Level::ERROR, 100));
+        // The test runtime is single threaded, so the connection task logs through this too
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let (addr, sessions, dispatcher, connection) = spawn_connection().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        client.send(WsMessage::Text(r#"{"action":"subscribe","type":"invoice","id":"inv_1"}"#.to_string())).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        let session_id = *sessions.read().await.keys().next().unwrap();
+
+        client.send(WsMessage::Text(r#"{"action":"fetch_invoice","id":"inv_panic"}"#.to_string())).await.unwrap();
+        match tokio::time::timeout(std::time::Duration::from_secs(2), client.next()).await.unwrap() {
+            Some(Ok(WsMessage::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Error),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+
+        // The connection task finished normally after cleaning up
+        tokio::time::timeout(std::time::Duration::from_secs(2), connection).await.unwrap().unwrap();
+        assert!(sessions.read().await.is_empty());
+        assert!(dispatcher.topics().await.is_empty());
+
+        let line = lines.try_recv().unwrap();
+        assert!(line.message.contains(&session_id.to_string()));
+        assert!(line.message.contains("injected failure"));
+    }
+
+    #[tokio::test]
+    async fn test_session_debug_logs_only_the_chosen_session() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let logs = LogStream::default();
+        let mut lines = logs.subscribe();
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry().with(logs.layer(tracing::Level::INFO, 1000)));
+
+        let (debugged_addr, debugged_sessions, dispatcher, _debugged_connection) = spawn_connection().await;
+        let (other_addr, other_sessions, _, _other_connection) = spawn_connection().await;
+        let (mut debugged, _) = tokio_tungstenite::connect_async(format!("ws://{}", debugged_addr)).await.unwrap();
+        let (mut other, _) = tokio_tungstenite::connect_async(format!("ws://{}", other_addr)).await.unwrap();
+        for client in [&mut debugged, &mut other] {
+            client.send(WsMessage::Text(r#"{"action":"ping"}"#.to_string())).await.unwrap();
+            client.next().await.unwrap().unwrap();
+        }
+        let debugged_id = *debugged_sessions.read().await.keys().next().unwrap();
+        let other_id = *other_sessions.read().await.keys().next().unwrap();
+
+        // Handled as if sent on a connection to the debugged session's server
+        let context = ConnectionContext {
+            event_dispatcher: dispatcher,
+            sessions: debugged_sessions,
+            ..connection_context(ServerConfig::default(), "http://127.0.0.1:9")
+        };
+        let set_debug = Message::SetSessionDebug { session_id: debugged_id, enabled: true };
+        let mut admin = Session::new(Uuid::new_v4(), futures::channel::mpsc::unbounded().0);
+        let forbidden = Message::SetSessionDebug { session_id: debugged_id, enabled: true };
+        let response = AnypayEventsServer::handle_message(forbidden, &admin, &context).await;
+        assert_eq!(response["code"], "FORBIDDEN");
+        admin.is_admin = true;
+        let response = AnypayEventsServer::handle_message(set_debug, &admin, &context).await;
+        assert_eq!(response["status"], "success");
+        let unknown = Message::SetSessionDebug { session_id: Uuid::new_v4(), enabled: true };
+        let response = AnypayEventsServer::handle_message(unknown, &admin, &context).await;
+        assert_eq!(response["code"], "NOT_FOUND");
+
+        for client in [&mut debugged, &mut other] {
+            client.send(WsMessage::Text(r#"{"action":"server_time"}"#.to_string())).await.unwrap();
+            client.next().await.unwrap().unwrap();
+        }
+
+        let mut logged = Vec::new();
+        while let Ok(line) = lines.try_recv() {
+            logged.push(line.message);
+        }
+        let debugged_received = format!("Session {} received: {{\"action\":\"server_time\"}}", debugged_id);
+        assert!(logged.contains(&debugged_received), "{:?}", logged);
+        assert!(logged.iter().any(|line| line.starts_with(&format!("Session {} sent: ", debugged_id)) && line.contains("epoch_ms")));
+        assert!(!logged.iter().any(|line| line.starts_with(&format!("Session {} ", other_id))));
+    }
+
+    #[tokio::test]
+    async fn test_payments_written_through_another_client_reach_subscribers() {
+        let url = spawn_memory_store().await;
+        // The client a payment watcher such as Blockbook writes through
+        let watcher = SupabaseClient::new(&url, "anon", "service");
+        let created = watcher.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+
+        let server = AnypayEventsServer::new("127.0.0.1:0", &url, "anon", "service")
+            .with_store_events(watcher.events().clone());
+        let shutdown = server.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let running = tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        client.send(WsMessage::Text(json!({ "action": "subscribe", "type": "invoice", "id": uid }).to_string().into())).await.unwrap();
+        let ack: serde_json::Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(ack["status"], "success");
+
+        watcher.mark_paid(&uid).await.unwrap();
+        let event: serde_json::Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(event["type"], "invoice.paid");
+        assert_eq!(event["data"]["uid"], uid);
+
+        shutdown.shutdown();
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_selftest_passes_against_in_memory_store() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig {
+            selftest_account_id: 7,
+            ..ServerConfig::default()
+        }, &url);
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+
+        // Admin only
+        let response = AnypayEventsServer::handle_message(Message::Selftest, &session, &context).await;
+        assert_eq!(response["status"], "error");
+        assert!(response.get("data").is_none());
+
+        session.is_admin = true;
+        let response = AnypayEventsServer::handle_message(Message::Selftest, &session, &context).await;
+        assert_eq!(response["status"], "success", "{}", response);
+        let steps: Vec<(&str, bool)> = response["data"]["steps"].as_array().unwrap().iter()
+            .map(|step| (step["step"].as_str().unwrap(), step["passed"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(steps, vec![("create", true), ("fetch", true), ("event", true), ("cancel", true)]);
+
+        // The invoice it created is left cancelled
+        let uid = response["data"]["invoice_uid"].as_str().unwrap();
+        let invoice = context.supabase.get_invoice_record(uid).await.unwrap().unwrap();
+        assert_eq!(invoice.status, "cancelled");
+        assert_eq!(invoice.account_id, 7);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_invoice_expands_payment_options_as_configured() {
+        let (url, requests) = spawn_store(|request| async move {
+            if request.table() == "invoices" {
+                StoreResponse::json(json!([test_support::invoice_row("inv_1", "unpaid")]))
+            } else {
+                StoreResponse::json("[]")
+            }
+        }).await;
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let fetch = |expand: Option<bool>| -> Message {
+            serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1", "expand": expand })).unwrap()
+        };
+        let loaded_options = || requests.lock().unwrap().iter().any(|request| request.table() == "payment_options");
+
+        // Off by default: only the invoice row is read
+        let context = connection_context(ServerConfig::default(), &url);
+        assert!(!context.config.expand_payment_options);
+        let response = AnypayEventsServer::handle_message(fetch(None), &session, &context).await;
+        assert_eq!(response["data"]["invoice"]["uid"], "inv_1");
+        assert!(response["data"].get("payment_options").is_none());
+        assert!(!loaded_options());
+
+        // A client can still ask for them
+        AnypayEventsServer::handle_message(fetch(Some(true)), &session, &context).await;
+        assert!(loaded_options());
+
+        // Operators can restore the old default, which clients can opt out of
+        requests.lock().unwrap().clear();
+        let context = connection_context(ServerConfig {
+            expand_payment_options: true,
+            ..ServerConfig::default()
+        }, &url);
+        AnypayEventsServer::handle_message(fetch(Some(false)), &session, &context).await;
+        assert!(!loaded_options());
+        AnypayEventsServer::handle_message(fetch(None), &session, &context).await;
+        assert!(loaded_options());
+    }
+
+    #[tokio::test]
+    async fn test_server_time_reads_the_configured_clock() {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let now = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00.250Z").unwrap().with_timezone(&chrono::Utc);
+        let context = connection_context(ServerConfig {
+            clock: Clock::Fixed(now),
+            ..ServerConfig::default()
+        }, "http://127.0.0.1:9");
+
+        let response = AnypayEventsServer::handle_message(Message::ServerTime, &session, &context).await;
+        assert_eq!(response["data"]["time"], "2024-01-01T12:00:00.250+00:00");
+        assert_eq!(response["data"]["epoch_ms"], 1_704_110_400_250i64);
+    }
+
+    #[tokio::test]
+    async fn test_over_length_subscription_id_is_rejected() {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let context = connection_context(ServerConfig {
+            max_subscription_id_length: 16,
+            ..ServerConfig::default()
+        }, "http://127.0.0.1:9");
+        let subscribe = |id: String| Message::Subscribe {
+            sub_type: "tag".to_string(),
+            id,
+            filter: None,
+            fields: None,
+            view: None,
+        };
+
+        let response = AnypayEventsServer::handle_message(subscribe(format!("42:{}", "x".repeat(14))), &session, &context).await;
+        assert_eq!(response["code"], "ID_TOO_LONG");
+        assert_eq!(response["message"], "Subscription id is 17 bytes, at most 16 are allowed");
+        assert!(context.event_dispatcher.topics().await.is_empty());
+
+        let response = AnypayEventsServer::handle_message(subscribe("42:pos".to_string()), &session, &context).await;
+        assert_eq!(response["status"], "success");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_ack_counts_existing_subscribers_and_the_new_one() {
+        use crate::session::SubscriberCounts;
+
+        let context = connection_context(ServerConfig::default(), "http://127.0.0.1:9");
+        for _ in 0..2 {
+            let (sender, _receiver) = futures::channel::mpsc::unbounded();
+            context.event_dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "invoice", "inv_123").await;
+        }
+        let subscribe = |session: Session, subscriber_counts: SubscriberCounts| {
+            let context = ConnectionContext {
+                config: Arc::new(ServerConfig { subscriber_counts, ..ServerConfig::default() }),
+                ..context.clone()
+            };
+            async move {
+                AnypayEventsServer::handle_message(
+                    Message::Subscribe {
+                        sub_type: "invoice".to_string(),
+                        id: "inv_123".to_string(),
+                        filter: None,
+                        fields: None,
+                        view: None,
+                    },
+                    &session,
+                    &context,
+                ).await
+            }
+        };
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+
+        let response = subscribe(session.clone(), SubscriberCounts::Hidden).await;
+        assert_eq!(response["status"], "success");
+        assert!(response.get("subscriber_count").is_none());
+
+        let response = subscribe(session.clone(), SubscriberCounts::AdminsOnly).await;
+        assert!(response.get("subscriber_count").is_none());
+
+        // Subscribing again doesn't count the session twice
+        let response = subscribe(session.clone(), SubscriberCounts::Public).await;
+        assert_eq!(response["subscriber_count"], 3);
+
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut admin = Session::new(Uuid::new_v4(), sender);
+        admin.is_admin = true;
+        let response = subscribe(admin, SubscriberCounts::AdminsOnly).await;
+        assert_eq!(response["subscriber_count"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_compact_view_omits_heavier_fields() {
+        let (url, _requests) = spawn_store(|_| async {
+            StoreResponse::json(json!([Invoice {
+                webhook_url: Some("https://example.com/webhook".to_string()),
+                memo: Some("Order 1001".to_string()),
+                ..test_support::invoice("inv_1", "unpaid")
+            }]))
+        }).await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let compact_keys = |invoice: &serde_json::Value| -> Vec<String> {
+            let mut keys: Vec<String> = invoice.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        let expected = vec!["amount", "currency", "id", "status", "uid"];
+
+        let fetch = serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1", "view": "compact" })).unwrap();
+        let response = AnypayEventsServer::handle_message(fetch, &session, &context).await;
+        assert_eq!(compact_keys(&response["data"]["invoice"]), expected);
+
+        let full = serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1" })).unwrap();
+        let response = AnypayEventsServer::handle_message(full, &session, &context).await;
+        assert_eq!(response["data"]["invoice"]["memo"], "Order 1001");
+        let invoice: Invoice = serde_json::from_value(response["data"]["invoice"].clone()).unwrap();
+
+        let subscribe = serde_json::from_value(json!({ "action": "subscribe", "type": "invoice", "id": "inv_1", "view": "compact" })).unwrap();
+        let response = AnypayEventsServer::handle_message(subscribe, &session, &context).await;
+        assert_eq!(response["status"], "success");
+        while receiver.try_recv().is_ok() {}
+        context.event_dispatcher.dispatch_invoice_event("invoice.updated", &invoice).await;
+        let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+            panic!("expected the event");
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(compact_keys(&event["data"]), expected);
+
+        // A compact view and an explicit projection would contradict each other
+        let both = serde_json::from_value(json!({ "action": "subscribe", "type": "invoice", "id": "inv_2", "view": "compact", "fields": ["uid"] })).unwrap();
+        let response = AnypayEventsServer::handle_message(both, &session, &context).await;
+        assert_eq!(response["code"], "INVALID_FIELDS");
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_fetch_follows_setting() {
+        let (url, requests) = spawn_store(|_| async { StoreResponse::json(json!([test_support::invoice_row("inv_1", "unpaid")])) }).await;
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        let fetch = || -> Message {
+            serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1" })).unwrap()
+        };
+
+        // Allowed by default
+        let context = connection_context(ServerConfig::default(), &url);
+        let response = AnypayEventsServer::handle_message(fetch(), &session, &context).await;
+        assert_eq!(response["data"]["invoice"]["uid"], "inv_1");
+
+        let context = connection_context(ServerConfig {
+            allow_unauthenticated_fetch: false,
+            ..ServerConfig::default()
+        }, &url);
+        requests.lock().unwrap().clear();
+        let response = AnypayEventsServer::handle_message(fetch(), &session, &context).await;
+        assert_eq!(response["code"], "UNAUTHORIZED");
+        assert!(requests.lock().unwrap().is_empty());
+
+        session.set_account_id(42);
+        let response = AnypayEventsServer::handle_message(fetch(), &session, &context).await;
+        assert_eq!(response["data"]["invoice"]["uid"], "inv_1");
+    }
+
+    #[tokio::test]
+    async fn test_revalidation_prunes_subscriptions_to_deleted_invoices() {
+        let (url, _requests) = spawn_store(|request| async move {
+            if request.target.contains("inv_missing") {
+                return StoreResponse::json("[]");
+            }
+            let mut row = test_support::invoice_row("inv_1", "unpaid");
+            if request.target.contains("inv_gone") {
+                row["deleted_at"] = json!("2024-01-02T00:00:00Z");
+            }
+            StoreResponse::json(json!([row]))
+        }).await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        for id in ["inv_1", "inv_gone", "inv_missing"] {
+            context.event_dispatcher.subscribe(session.clone(), "invoice", id).await;
+        }
+        context.event_dispatcher.subscribe(session.clone(), "account", "42").await;
+
+        let response = AnypayEventsServer::handle_message(Message::RevalidateSubscriptions, &session, &context).await;
+        assert_eq!(response["status"], "success");
+        let listed = |key: &str| -> Vec<(String, String)> {
+            response["data"][key].as_array().unwrap().iter()
+                .map(|entry| (entry["id"].as_str().unwrap().to_string(), entry["reason"].as_str().unwrap_or("").to_string()))
+                .collect()
+        };
+        assert_eq!(listed("kept"), vec![("42".to_string(), String::new()), ("inv_1".to_string(), String::new())]);
+        assert_eq!(listed("removed"), vec![
+            ("inv_gone".to_string(), "deleted".to_string()),
+            ("inv_missing".to_string(), "not_found".to_string()),
+        ]);
+
+        let topics: Vec<String> = context.event_dispatcher.session_subscriptions(session.id).await.into_iter().map(|(_, _, id)| id).collect();
+        assert_eq!(topics.len(), 2);
+        assert!(!topics.contains(&"inv_gone".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_of_one_account_share_its_rate_limit() {
+        let (url, requests) = spawn_store(|_| async { StoreResponse::json("[]") }).await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let limits = AccountRateLimits::new(3, std::time::Duration::from_secs(3600));
+        let session_for = |account_id: i32| {
+            let (sender, _receiver) = futures::channel::mpsc::unbounded();
+            let mut session = Session::new(Uuid::new_v4(), sender);
+            session.set_account_id(account_id);
+            session.account_limit = limits.bucket(account_id);
+            session
+        };
+        let fetch = || -> Message {
+            serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1" })).unwrap()
+        };
+        let (first, second) = (session_for(7), session_for(7));
+
+        // Each session stays well under the per-session limit, but together they exhaust the account's
+        for session in [&first, &second, &first] {
+            let response = AnypayEventsServer::handle_message(fetch(), session, &context).await;
+            assert_ne!(response["code"], "ACCOUNT_RATE_LIMITED");
+        }
+        let response = AnypayEventsServer::handle_message(fetch(), &second, &context).await;
+        assert_eq!(response["code"], "ACCOUNT_RATE_LIMITED");
+        assert!(response["retry_after_ms"].as_u64().unwrap() > 0);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        // Requests that don't reach the store and other accounts are unaffected
+        let ping = AnypayEventsServer::handle_message(Message::Ping, &second, &context).await;
+        assert_ne!(ping["code"], "ACCOUNT_RATE_LIMITED");
+        let other = AnypayEventsServer::handle_message(fetch(), &session_for(8), &context).await;
+        assert_ne!(other["code"], "ACCOUNT_RATE_LIMITED");
+    }
+
+    #[cfg(feature = "invoices")]
+    #[tokio::test]
+    async fn test_only_a_missing_account_is_reported_as_invalid() {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut admin = Session::new(Uuid::new_v4(), sender);
+        admin.set_account_id(7);
+        admin.is_admin = true;
+        let create = || -> Message {
+            serde_json::from_value(json!({ "action": "create_invoice", "amount": 1000, "currency": "USD", "account_id": 9 })).unwrap()
+        };
+        let create_against = |url: String| {
+            let (context, create, admin) = (connection_context(ServerConfig::default(), &url), create(), &admin);
+            async move { AnypayEventsServer::handle_message(create, admin, &context).await }
+        };
+
+        let (missing, _requests) = spawn_store(|_| async { StoreResponse::json("[]") }).await;
+        assert_eq!(create_against(missing).await["code"], "INVALID_ACCOUNT");
+
+        // The store turning the lookup away says nothing about the account
+        let (limited, _requests) = spawn_store(|_| async {
+            StoreResponse::status(429, "{}").with_header("retry-after: 30")
+        }).await;
+        let response = create_against(limited).await;
+        assert_eq!(response["code"], "BACKEND_RATE_LIMITED");
+        assert_eq!(response["retry_after_ms"], 30000);
+
+        let (failing, _requests) = spawn_store(|_| async { StoreResponse::status(500, "{}") }).await;
+        let response = create_against(failing).await;
+        assert_eq!(response["status"], "error");
+        assert_ne!(response["code"], "INVALID_ACCOUNT");
+    }
+
+    #[cfg(feature = "invoices")]
+    #[tokio::test]
+    async fn test_create_burst_is_throttled_while_fetches_still_flow() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.set_account_id(7);
+        session.create_limit = Some(Arc::new(std::sync::Mutex::new(TokenBucket::new(2, std::time::Duration::from_secs(3600)))));
+        let create = || -> Message {
+            serde_json::from_value(json!({ "action": "create_invoice", "amount": 1000, "currency": "USD" })).unwrap()
+        };
+
+        let mut created = Vec::new();
+        for _ in 0..2 {
+            let response = AnypayEventsServer::handle_message(create(), &session, &context).await;
+            assert_eq!(response["status"], "success", "{}", response);
+            created.push(response["data"]["invoice"]["uid"].as_str().unwrap().to_string());
+        }
+        let response = AnypayEventsServer::handle_message(create(), &session, &context).await;
+        assert_eq!(response["code"], "CREATE_RATE_LIMITED");
+        assert!(response["retry_after_ms"].as_u64().unwrap() > 0);
+
+        for uid in &created {
+            let fetch = serde_json::from_value(json!({ "action": "fetch_invoice", "id": uid })).unwrap();
+            let response = AnypayEventsServer::handle_message(fetch, &session, &context).await;
+            assert_eq!(response["status"], "success", "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_overlaps_reads_but_not_the_subscribe_they_follow() {
+        let delay = std::time::Duration::from_millis(100);
+        let dispatcher = Arc::new(EventDispatcher::new());
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+
+        // A store that answers every query with no rows after `delay`, noting
+        // whether the subscribe had completed when each query arrived
+        let subscribed_at_query = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (store_url, _requests) = {
+            let dispatcher = dispatcher.clone();
+            let subscribed_at_query = subscribed_at_query.clone();
+            let session_id = session.id;
+            spawn_store(move |_| {
+                let (dispatcher, subscribed_at_query) = (dispatcher.clone(), subscribed_at_query.clone());
+                async move {
+                    let topic = crate::types::Subscription { sub_type: "invoice".to_string(), id: "inv_1".to_string() };
+                    let subscribed = dispatcher.get_subscribers(&topic).await.contains(&session_id);
+                    subscribed_at_query.lock().unwrap().push(subscribed);
+
+                    tokio::time::sleep(delay).await;
+                    StoreResponse::json("[]")
+                }
+            }).await
+        };
+
+        let context = ConnectionContext {
+            event_dispatcher: dispatcher,
+            ..connection_context(ServerConfig {
+                inbound_batch_size: 8,
+                inbound_batch_concurrency: 4,
+                ..ServerConfig::default()
+            }, &store_url)
+        };
+        let mut requests = vec![Ok(serde_json::from_value(json!({ "action": "subscribe", "type": "invoice", "id": "inv_1" })).unwrap())];
+        for _ in 0..4 {
+            requests.push(Ok(serde_json::from_value(json!({ "action": "fetch_invoice", "id": "inv_1" })).unwrap()));
+        }
+
+        let started = std::time::Instant::now();
+        let responses = AnypayEventsServer::handle_batch(requests, &session, &context).await;
+        let elapsed = started.elapsed();
+
+        assert_eq!(responses.len(), 5);
+        assert_eq!(responses[0]["status"], "success");
+        for response in &responses[1..] {
+            assert_eq!(response["code"], "NOT_FOUND");
+        }
+        // One at a time, the four fetches would take at least 4 * delay
+        assert!(elapsed < delay * 3, "batched fetches took {:?}", elapsed);
+        assert_eq!(*subscribed_at_query.lock().unwrap(), vec![true; 4]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribes_are_shed_while_dispatch_queue_is_saturated() {
+        let context = connection_context(ServerConfig {
+            dispatch_queue_high_water_mark: 3,
+            overload_retry_after: std::time::Duration::from_millis(250),
+            ..ServerConfig::default()
+        }, "http://127.0.0.1:9");
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let subscribe = |id: &str| -> Message {
+            serde_json::from_value(json!({ "action": "subscribe", "type": "invoice", "id": id })).unwrap()
+        };
+        let invoice = test_support::invoice("inv_1", "unpaid");
+
+        let response = AnypayEventsServer::handle_message(subscribe("inv_1"), &session, &context).await;
+        assert_eq!(response["status"], "success");
+
+        // A listener that has fallen behind leaves events queued
+        let mut behind = context.supabase.events().subscribe();
+        for _ in 0..3 {
+            context.supabase.events().publish(crate::event_bus::StoreEvent::InvoiceStatusChanged(invoice.clone()));
+        }
+
+        let response = AnypayEventsServer::handle_message(subscribe("inv_2"), &session, &context).await;
+        assert_eq!(response["code"], "OVERLOADED");
+        assert_eq!(response["retry_after_ms"], 250);
+
+        // The existing subscription is still served
+        assert_eq!(context.event_dispatcher.dispatch_invoice_event("invoice.updated", &invoice).await, 1);
+        assert!(matches!(receiver.try_recv(), Ok(WsMessage::Text(_))));
+
+        while behind.try_recv().is_ok() {}
+        let response = AnypayEventsServer::handle_message(subscribe("inv_2"), &session, &context).await;
+        assert_eq!(response["status"], "success");
+    }
+
+    #[tokio::test]
+    async fn test_connection_stats_count_requests_errors_and_events() {
+        let context = connection_context(ServerConfig::default(), "http://127.0.0.1:9");
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let request = |message: serde_json::Value| -> Result<Message, serde_json::Value> {
+            Ok(serde_json::from_value(message).unwrap())
+        };
+
+        let requests = vec![
+            request(json!({ "action": "subscribe", "type": "invoice", "id": "inv_1" })),
+            request(json!({ "action": "ping" })),
+            request(json!({ "action": "resume_subscription", "type": "invoice", "id": "inv_1" })),
+            parse_message("not json", false),
+        ];
+        AnypayEventsServer::handle_batch(requests, &session, &context).await;
+
+        let topic = crate::types::Subscription { sub_type: "invoice".to_string(), id: "inv_1".to_string() };
+        context.event_dispatcher.dispatch(&topic, &json!({ "type": "invoice.updated" })).await;
+        context.event_dispatcher.dispatch(&topic, &json!({ "type": "invoice.paid" })).await;
+
+        let responses = AnypayEventsServer::handle_batch(vec![request(json!({ "action": "connection_stats" }))], &session, &context).await;
+        assert_eq!(responses[0]["data"], json!({
+            "messages_received": 4,
+            "errors": 2,
+            "events_sent": 2
+        }));
+        assert_eq!(session.stats.messages_received(), 5);
+    }
+
+    #[cfg(feature = "invoices")]
+    #[tokio::test]
+    async fn test_test_invoice_is_paid_automatically_for_its_subscribers() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig {
+            test_mode: true,
+            test_payment_delay: std::time::Duration::from_millis(100),
+            ..ServerConfig::default()
+        }, &url);
+        tokio::spawn(event_bus::forward_to_dispatcher(context.supabase.events().subscribe(), context.event_dispatcher.clone()));
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut merchant = Session::new(Uuid::new_v4(), sender);
+        merchant.account_id = Some(7);
+
+        let create = serde_json::from_value(json!({ "action": "create_invoice", "amount": 1000, "currency": "USD", "test": true })).unwrap();
+        let response = AnypayEventsServer::handle_message(create, &merchant, &context).await;
+        assert_eq!(response["status"], "success");
+        let uid = response["data"]["invoice"]["uid"].as_str().unwrap().to_string();
+
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        context.event_dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "invoice", &uid).await;
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.next())
+            .await
+            .expect("no invoice.paid for the test invoice")
+            .unwrap();
+        let WsMessage::Text(text) = frame else { panic!("expected a text frame, got {:?}", frame) };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["type"], "invoice.paid");
+        assert_eq!(event["data"]["uid"], uid);
+    }
+
+    #[tokio::test]
+    async fn test_rebroadcast_sends_the_current_invoice_to_every_subscriber() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let created = context.supabase.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+        // Paid while nobody was listening
+        context.supabase.mark_paid(&uid).await.unwrap();
+
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (sender, receiver) = futures::channel::mpsc::unbounded();
+            context.event_dispatcher.subscribe(Session::new(Uuid::new_v4(), sender), "invoice", &uid).await;
+            receivers.push(receiver);
+        }
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut admin = Session::new(Uuid::new_v4(), sender);
+        admin.is_admin = true;
+
+        let rebroadcast = serde_json::from_value(json!({ "action": "rebroadcast_invoice", "id": uid })).unwrap();
+        let response = AnypayEventsServer::handle_message(rebroadcast, &admin, &context).await;
+        assert_eq!(response, json!({
+            "status": "success",
+            "data": { "invoice_uid": uid, "subscribers": 2 }
+        }));
+        for receiver in &mut receivers {
+            let Ok(WsMessage::Text(text)) = receiver.try_recv() else {
+                panic!("expected the rebroadcast invoice");
+            };
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(event["type"], "invoice.updated");
+            assert_eq!((event["data"]["uid"].as_str(), event["data"]["status"].as_str()), (Some(uid.as_str()), Some("paid")));
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    async fn invoice_history(uid: &str, session: &Session, context: &ConnectionContext) -> Vec<String> {
+        let fetch = serde_json::from_value(json!({ "action": "fetch_invoice_history", "id": uid })).unwrap();
+        let response = AnypayEventsServer::handle_message(fetch, session, context).await;
+        assert_eq!(response["status"], "success");
+        assert_eq!(response["data"]["invoice_uid"], uid);
+        response["data"]["history"].as_array().unwrap().iter()
+            .map(|transition| transition["status"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_invoice_history_records_creation_then_payment_in_order() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let created = context.supabase.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+        assert_eq!(invoice_history(&uid, &session, &context).await, vec!["unpaid"]);
+
+        context.supabase.mark_paid(&uid).await.unwrap();
+        assert_eq!(invoice_history(&uid, &session, &context).await, vec!["unpaid", "paid"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancelling_an_invoice_records_it_in_the_history() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.account_id = Some(7);
+        let created = context.supabase.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+
+        let cancel = serde_json::from_value(json!({ "action": "cancel_invoice", "uid": uid })).unwrap();
+        let response = AnypayEventsServer::handle_message(cancel, &session, &context).await;
+        assert_eq!(response["status"], "success");
+        assert_eq!(invoice_history(&uid, &session, &context).await, vec!["unpaid", "cancelled"]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_payment_answers_later_without_holding_up_other_requests() {
+        let url = spawn_memory_store().await;
+        let context = connection_context(ServerConfig::default(), &url);
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let created = context.supabase.create_invoice(1000, "USD", 7, None, None, None, Vec::new()).await.unwrap();
+        let uid = created["invoice"]["uid"].as_str().unwrap().to_string();
+
+        let wait = serde_json::from_value(json!({ "action": "wait_for_payment", "id": uid })).unwrap();
+        let response = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            AnypayEventsServer::handle_message(wait, &session, &context),
+        ).await.expect("wait_for_payment held up the connection");
+        assert!(response.is_null());
+
+        let ping = serde_json::from_value(json!({ "action": "ping" })).unwrap();
+        let response = AnypayEventsServer::handle_message(ping, &session, &context).await;
+        assert_eq!(response["status"], "success");
+        assert!(receiver.try_next().is_err(), "answered before the invoice was paid");
+
+        context.supabase.mark_paid(&uid).await.unwrap();
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.next())
+            .await
+            .expect("no response once paid")
+            .unwrap();
+        let WsMessage::Text(text) = frame else { panic!("expected a text frame, got {:?}", frame) };
+        let response: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(response["status"], "success");
+        assert_eq!(response["data"]["uid"], uid);
+        assert_eq!(response["data"]["status"], "paid");
+        assert_eq!(response["data"]["amount_decimal"], "10.00");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_send_queue_returns_to_empty_while_the_forward_task_drains_it() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.send_queue = SendQueue::new(0);
+        let mut socket = BrokenSocket { accept: usize::MAX, written: 0, error: || tungstenite::Error::ConnectionClosed, closed: false };
+        let send_queue = session.send_queue.clone();
+        let forward = tokio::spawn(async move {
+            forward_messages(receiver, &mut socket, send_queue, ConnectionState::new(), Uuid::new_v4()).await;
+            socket.written
+        });
+
+        // Several senders race the forward task taking frames off the queue,
+        // yielding after each so the queue stays short and the forward task hot
+        let senders: Vec<_> = (0..4).map(|_| {
+            let session = session.clone();
+            tokio::spawn(async move {
+                for n in 0..5000 {
+                    session.send(WsMessage::Text(format!("frame {}", n))).unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        }).collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        let send_queue = session.send_queue.clone();
+        drop(session);
+
+        assert_eq!(forward.await.unwrap(), 20000);
+        assert_eq!(send_queue.queued(), 0);
+    }
+
+    /// A socket that accepts `accept` frames, then fails with `error`.
+    struct BrokenSocket {
+        accept: usize,
+        written: usize,
+        error: fn() -> tungstenite::Error,
+        closed: bool,
+    }
+
+    impl Sink<WsMessage> for BrokenSocket {
+        type Error = tungstenite::Error;
+
+        fn poll_ready(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: std::pin::Pin<&mut Self>, _: WsMessage) -> Result<(), Self::Error> {
+            if self.written == self.accept {
+                return Err((self.error)());
+            }
+            self.written += 1;
+            Ok(())
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+            self.closed = true;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn forward_through(socket: &mut BrokenSocket, frames: usize) -> (ForwardExit, ConnectionState) {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        for n in 0..frames {
+            sender.unbounded_send(WsMessage::Text(format!("frame {}", n))).unwrap();
+        }
+        drop(sender);
+
+        let connection = ConnectionState::new();
+        let exit = forward_messages(receiver, &mut *socket, SendQueue::new(0), connection.clone(), Uuid::new_v4()).await;
+        (exit, connection)
+    }
+
+    #[tokio::test]
+    async fn test_forward_task_tears_down_on_mid_send_disconnect() {
+        let mut socket = BrokenSocket {
+            accept: 1,
+            written: 0,
+            error: || tungstenite::Error::Io(ErrorKind::BrokenPipe.into()),
+            closed: false,
+        };
+        let (exit, connection) = forward_through(&mut socket, 3).await;
+        assert_eq!(exit, ForwardExit::Disconnected);
+        assert_eq!(socket.written, 1);
+        assert!(connection.is_closed());
+
+        let mut socket = BrokenSocket {
+            accept: 0,
+            written: 0,
+            error: || tungstenite::Error::Capacity(tungstenite::error::CapacityError::MessageTooLong { size: 2, max_size: 1 }),
+            closed: false,
+        };
+        let (exit, connection) = forward_through(&mut socket, 1).await;
+        assert_eq!(exit, ForwardExit::Failed);
+        assert!(connection.is_closed());
+
+        // With nothing going wrong the socket is closed once the session stops sending
+        let mut socket = BrokenSocket {
+            accept: usize::MAX,
+            written: 0,
+            error: || tungstenite::Error::ConnectionClosed,
+            closed: false,
+        };
+        let (exit, connection) = forward_through(&mut socket, 2).await;
+        assert_eq!(exit, ForwardExit::Finished);
+        assert_eq!(socket.written, 2);
+        assert!(socket.closed);
+        assert!(connection.is_closed());
+    }
+
+    async fn create_invoice_unauthenticated() -> serde_json::Value {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+        let message = serde_json::from_value(json!({
+            "action": "create_invoice",
+            "amount": 1000,
+            "currency": "USD"
+        })).unwrap();
+
+        let context = connection_context(ServerConfig::default(), "http://127.0.0.1:9");
+        AnypayEventsServer::handle_message(message, &session, &context).await
+    }
+
+    #[cfg(feature = "invoices")]
+    #[tokio::test]
+    async fn test_create_invoice_available_with_feature() {
+        let response = create_invoice_unauthenticated().await;
+        assert_ne!(response["code"], "FEATURE_DISABLED");
+        assert!(response["message"].as_str().unwrap().starts_with("Unauthorized"));
+    }
+
+    #[cfg(not(feature = "invoices"))]
+    #[tokio::test]
+    async fn test_create_invoice_disabled_without_feature() {
+        let response = create_invoice_unauthenticated().await;
+        assert_eq!(response["status"], "error");
+        assert_eq!(response["code"], "FEATURE_DISABLED");
+    }
+}
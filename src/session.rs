@@ -1,9 +1,18 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
 use tokio_tungstenite::tungstenite::Message as WsMessage;
 use futures::channel::mpsc::UnboundedSender;
 use uuid::Uuid;
-use crate::types::Subscription;
+use serde_json::{Map, Value};
+use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink};
+use crate::envelope;
+use crate::rate_limit::SharedBucket;
+use crate::types::{Message, Subscription};
 
 #[derive(Debug, Clone)]
 pub struct Session {
@@ -12,6 +21,393 @@ pub struct Session {
     pub account_id: Option<i32>,
     pub auth_token: Option<String>,
     pub subscriptions: HashSet<Subscription>,
+    pub envelope_version: u8,
+    pub is_admin: bool,
+    pub send_queue: SendQueue,
+    pub delivery_order: DeliveryOrder,
+    event_seq: Arc<AtomicU64>,
+    pub context: SessionContext,
+    pub stats: ConnectionStats,
+    /// The budget shared with the account's other sessions, once authenticated.
+    pub account_limit: Option<SharedBucket>,
+    /// The server-wide budget for `create_invoice`, shared by every session.
+    pub create_limit: Option<SharedBucket>,
+    /// Leave per-subscription acks out of `subscribe_many` responses by default.
+    pub suppress_acks: bool,
+    // Set by an admin to log this session's frames in full
+    debug: Arc<AtomicBool>,
+    pub event_batch: EventBatch,
+    /// Dispatched when the connection drops without a clean close.
+    pub last_will: Option<LastWill>,
+}
+
+/// A payload a client leaves at connect, dispatched to `topic` if its
+/// connection later drops instead of closing cleanly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastWill {
+    pub topic: Subscription,
+    pub payload: Value,
+}
+
+/// Collects each of a session's subscriptions' event frames for `interval`
+/// and sends them as one JSON array frame, oldest first. A zero interval
+/// sends each event on its own.
+#[derive(Clone, Default)]
+pub struct EventBatch {
+    interval: Duration,
+    pending: Arc<Mutex<HashMap<Subscription, Vec<String>>>>,
+    // Receives the events of batches that could not be sent
+    dead_letters: Option<Arc<dyn DeadLetterSink>>,
+}
+
+impl std::fmt::Debug for EventBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBatch")
+            .field("interval", &self.interval)
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EventBatch {
+    pub fn new(interval: Duration) -> Self {
+        EventBatch {
+            interval,
+            pending: Arc::default(),
+            dead_letters: None,
+        }
+    }
+
+    /// Records the events of batches that fail to send in `sink`.
+    pub fn with_dead_letter_sink(mut self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.dead_letters = Some(sink);
+        self
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// Adds a frame for `topic`. Returns true when it starts a new batch.
+    fn push(&self, topic: &Subscription, frame: String) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let frames = pending.entry(topic.clone()).or_default();
+        frames.push(frame);
+        frames.len() == 1
+    }
+
+    fn take(&self, topic: &Subscription) -> Vec<String> {
+        self.pending.lock().unwrap().remove(topic).unwrap_or_default()
+    }
+}
+
+/// Counters a client can read back with `connection_stats`.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    messages_received: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+    events_sent: Arc<AtomicU64>,
+}
+
+impl ConnectionStats {
+    /// Records a handled request and whether its response was an error.
+    pub fn record_response(&self, response: &Value) {
+        self.messages_received.fetch_add(1, Ordering::SeqCst);
+        if response["status"] == "error" {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Counts the response to a request already recorded with a deferred
+    /// response.
+    pub fn record_deferred_response(&self, response: &Value) {
+        if response["status"] == "error" {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received.load(Ordering::SeqCst)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    pub fn events_sent(&self) -> u64 {
+        self.events_sent.load(Ordering::SeqCst)
+    }
+}
+
+/// Values a client stores for the life of its connection, used as defaults
+/// for the same-named fields of later requests.
+#[derive(Debug, Clone, Default)]
+pub struct SessionContext {
+    values: Arc<Mutex<Map<String, Value>>>,
+}
+
+impl SessionContext {
+    /// Merges `values` into the context; a null value removes its key. Nothing
+    /// changes if the result would hold more than `max_entries` keys or
+    /// serialize to more than `max_bytes`.
+    pub fn update(&self, values: Map<String, Value>, max_entries: usize, max_bytes: usize) -> Result<Map<String, Value>, String> {
+        let mut context = self.values.lock().unwrap();
+        let mut updated = context.clone();
+        for (key, value) in values {
+            if value.is_null() {
+                updated.remove(&key);
+            } else {
+                updated.insert(key, value);
+            }
+        }
+
+        if updated.len() > max_entries {
+            return Err(format!("Context can hold at most {} values", max_entries));
+        }
+        let size = Value::Object(updated.clone()).to_string().len();
+        if size > max_bytes {
+            return Err(format!("Context would be {} bytes, the limit is {}", size, max_bytes));
+        }
+        *context = updated.clone();
+        Ok(updated)
+    }
+
+    pub fn values(&self) -> Map<String, Value> {
+        self.values.lock().unwrap().clone()
+    }
+
+    /// Fills fields the message's action accepts but the client left out from
+    /// the context. The message is returned unchanged if the result doesn't parse.
+    pub fn apply(&self, message: Message) -> Message {
+        let context = self.values.lock().unwrap();
+        if context.is_empty() {
+            return message;
+        }
+        let Ok(Value::Object(mut fields)) = serde_json::to_value(&message) else {
+            return message;
+        };
+        let accepted = fields.get("action")
+            .and_then(Value::as_str)
+            .and_then(Message::fields)
+            .unwrap_or_default();
+
+        let mut changed = false;
+        for (key, value) in context.iter() {
+            if accepted.contains(&key.as_str()) && !fields.contains_key(key) {
+                fields.insert(key.clone(), value.clone());
+                changed = true;
+            }
+        }
+        if !changed {
+            return message;
+        }
+        serde_json::from_value(Value::Object(fields)).unwrap_or(message)
+    }
+}
+
+/// How a session's events are ordered relative to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryOrder {
+    /// Events are only guaranteed to be in order within a topic.
+    #[default]
+    PerTopic,
+    /// Every event carries a `seq` that increases by one across all of the
+    /// session's topics, so clients can rebuild a single ordered stream.
+    Session,
+}
+
+/// Tracks frames handed to a session's channel that the forward task has not
+/// yet written, so slow readers show up before they're dropped.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    queued: Arc<AtomicUsize>,
+    high_water_warnings: Arc<AtomicUsize>,
+    high_water_mark: usize,
+    // Woken as the forward task takes frames off the queue
+    drained: Arc<Notify>,
+}
+
+impl SendQueue {
+    /// A `high_water_mark` of 0 disables the warning.
+    pub fn new(high_water_mark: usize) -> Self {
+        SendQueue {
+            queued: Arc::new(AtomicUsize::new(0)),
+            high_water_warnings: Arc::new(AtomicUsize::new(0)),
+            high_water_mark,
+            drained: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Records a queued frame. Returns true when this frame crossed the high-water mark.
+    fn push(&self) -> bool {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        let crossed = self.high_water_mark > 0 && queued == self.high_water_mark;
+        if crossed {
+            self.high_water_warnings.fetch_add(1, Ordering::SeqCst);
+        }
+        crossed
+    }
+
+    /// Records that the forward task took a frame off the queue.
+    pub fn pop(&self) {
+        let _ = self.queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        self.drained.notify_waiters();
+    }
+
+    /// Resolves once fewer than `level` frames are queued.
+    pub async fn drained_below(&self, level: usize) {
+        loop {
+            // Registered before checking, so a pop in between isn't missed
+            let drained = self.drained.notified();
+            if self.queued() < level {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the queue has filled up to the high-water mark.
+    pub fn high_water_warnings(&self) -> usize {
+        self.high_water_warnings.load(Ordering::SeqCst)
+    }
+}
+
+/// What happens to a session that keeps sending requests while its replies
+/// pile up unread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowReaderAction {
+    /// Stop reading its requests until its queue is down to half the limit.
+    Pause,
+    /// Close the connection.
+    Close,
+}
+
+impl FromStr for SlowReaderAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(SlowReaderAction::Pause),
+            "close" => Ok(SlowReaderAction::Close),
+            other => Err(format!("expected 'pause' or 'close', got '{}'", other)),
+        }
+    }
+}
+
+/// Which sessions see a topic's `subscriber_count` in their subscribe acks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubscriberCounts {
+    #[default]
+    Hidden,
+    AdminsOnly,
+    Public,
+}
+
+impl SubscriberCounts {
+    pub fn shown_to(&self, session: &Session) -> bool {
+        match self {
+            SubscriberCounts::Hidden => false,
+            SubscriberCounts::AdminsOnly => session.is_admin,
+            SubscriberCounts::Public => true,
+        }
+    }
+}
+
+impl FromStr for SubscriberCounts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SubscriberCounts::Hidden),
+            "admin" => Ok(SubscriberCounts::AdminsOnly),
+            "public" => Ok(SubscriberCounts::Public),
+            other => Err(format!("expected 'off', 'admin' or 'public', got '{}'", other)),
+        }
+    }
+}
+
+/// Shared by a connection's receive loop and forward task so that either side
+/// exiting tears the other down instead of waiting for the next failed send.
+#[derive(Debug, Clone)]
+pub struct ConnectionState {
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl ConnectionState {
+    pub fn new() -> Self {
+        ConnectionState {
+            closed: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Resolves once either side has closed the connection.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        // The sender lives in self, so this only returns once the flag is set
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::new()
+    }
+}
+
+/// Options a client can pass as query parameters on the connect URL,
+/// e.g. `ws://host:8080/?envelope=1`.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub envelope_version: Option<u8>,
+    /// Subscribe to the authenticated account's invoice events on connect.
+    pub auto_subscribe: bool,
+    pub delivery_order: Option<DeliveryOrder>,
+    pub suppress_acks: bool,
+    /// From `will_topic=<type>:<id>` and a JSON `will_payload`, both required.
+    pub last_will: Option<LastWill>,
+}
+
+impl ConnectOptions {
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut options = ConnectOptions::default();
+        let (mut will_topic, mut will_payload) = (None, None);
+
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            match key.as_ref() {
+                "envelope" => options.envelope_version = value.parse().ok(),
+                "auto_subscribe" => options.auto_subscribe = matches!(value.as_ref(), "true" | "1"),
+                "suppress_acks" => options.suppress_acks = matches!(value.as_ref(), "true" | "1"),
+                "order" => options.delivery_order = match value.as_ref() {
+                    "session" => Some(DeliveryOrder::Session),
+                    "topic" => Some(DeliveryOrder::PerTopic),
+                    _ => None,
+                },
+                "will_topic" => will_topic = value.split_once(':').map(|(sub_type, id)| Subscription {
+                    sub_type: sub_type.to_string(),
+                    id: id.to_string(),
+                }),
+                "will_payload" => will_payload = serde_json::from_str(&value).ok(),
+                _ => {}
+            }
+        }
+        if let (Some(topic), Some(payload)) = (will_topic, will_payload) {
+            options.last_will = Some(LastWill { topic, payload });
+        }
+
+        options
+    }
 }
 
 impl Session {
@@ -22,19 +418,144 @@ impl Session {
             account_id: None,
             auth_token: None,
             subscriptions: HashSet::new(),
+            envelope_version: 0,
+            is_admin: false,
+            send_queue: SendQueue::new(0),
+            delivery_order: DeliveryOrder::default(),
+            event_seq: Arc::new(AtomicU64::new(0)),
+            context: SessionContext::default(),
+            stats: ConnectionStats::default(),
+            account_limit: None,
+            create_limit: None,
+            suppress_acks: false,
+            debug: Arc::new(AtomicBool::new(false)),
+            event_batch: EventBatch::default(),
+            last_will: None,
         }
     }
 
+    /// Whether frames to and from this session are logged in full. Shared by
+    /// every clone, so an admin can flip it on a connected session.
+    pub fn is_debugging(&self) -> bool {
+        self.debug.load(Ordering::SeqCst)
+    }
+
+    pub fn set_debugging(&self, enabled: bool) {
+        self.debug.store(enabled, Ordering::SeqCst);
+    }
+
     pub fn set_account_id(&mut self, account_id: i32) {
         self.account_id = Some(account_id);
     }
 
+    /// Spends a token from the account's budget, or returns how long until
+    /// the next one. Sessions without an account limit always pass.
+    pub fn acquire_account_token(&self) -> Result<(), std::time::Duration> {
+        match &self.account_limit {
+            Some(bucket) => bucket.lock().unwrap().try_acquire(),
+            None => Ok(()),
+        }
+    }
+
+    /// Spends a token from the server's invoice creation budget, or returns
+    /// how long until the next one.
+    pub fn acquire_create_token(&self) -> Result<(), std::time::Duration> {
+        match &self.create_limit {
+            Some(bucket) => bucket.lock().unwrap().try_acquire(),
+            None => Ok(()),
+        }
+    }
+
     pub fn is_authorized(&self) -> bool {
         self.account_id.is_some()
     }
 
     pub fn send(&self, message: WsMessage) -> Result<(), Box<dyn std::error::Error>> {
-        Ok(self.sender.unbounded_send(message)?)
+        // Counted before it is queued, so the forward task can never take it off first
+        let crossed = self.send_queue.push();
+        if let Err(e) = self.sender.unbounded_send(message) {
+            self.send_queue.pop();
+            return Err(e.into());
+        }
+        if crossed {
+            tracing::warn!(
+                "Session {} send queue reached high-water mark ({} frames), client is reading slowly",
+                self.id,
+                self.send_queue.queued()
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends an event for `topic`, `text` being its serialized form, and
+    /// counts it. With batching on, the event waits for the rest of the
+    /// topic's batch instead and is counted once the batch is sent.
+    pub fn send_event(&self, topic: &Subscription, event: &Value, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let frame = self.event_frame(event, text);
+        if self.event_batch.is_enabled() {
+            if self.sender.is_closed() {
+                return Err("session channel is closed".into());
+            }
+            if self.event_batch.push(topic, frame) {
+                let (session, topic) = (self.clone(), topic.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(session.event_batch.interval).await;
+                    session.flush_event_batch(&topic);
+                });
+            }
+            return Ok(());
+        }
+        self.send(WsMessage::Text(frame))?;
+        self.stats.events_sent.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn flush_event_batch(&self, topic: &Subscription) {
+        let frames = self.event_batch.take(topic);
+        if frames.is_empty() {
+            return;
+        }
+        let events: Vec<Value> = frames.iter().filter_map(|frame| serde_json::from_str(frame).ok()).collect();
+        // Sessions on a newer envelope get the batch as the data of one
+        let batch = match self.envelope_version {
+            0 => format!("[{}]", frames.join(",")),
+            version => envelope::wrap(version, serde_json::json!({ "status": "success", "data": &events })).to_string(),
+        };
+        match self.send(WsMessage::Text(batch)) {
+            Ok(()) => {
+                self.stats.events_sent.fetch_add(frames.len() as u64, Ordering::SeqCst);
+            }
+            Err(e) => {
+                tracing::debug!("Failed to send {} batched events to session {}: {}", frames.len(), self.id, e);
+                if let Some(sink) = &self.event_batch.dead_letters {
+                    for event in events {
+                        sink.record(DeadLetter {
+                            topics: vec![topic.clone()],
+                            event,
+                            reason: DeadLetterReason::SendFailed {
+                                session_id: self.id,
+                                error: e.to_string(),
+                            },
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// The frame to send for an event. Under `DeliveryOrder::Session` this
+    /// takes the session's next `seq`, so frames must be sent in the order
+    /// they are built.
+    fn event_frame(&self, event: &Value, text: &str) -> String {
+        match (self.delivery_order, event) {
+            (DeliveryOrder::Session, Value::Object(fields)) => {
+                let mut fields = fields.clone();
+                let seq = self.event_seq.fetch_add(1, Ordering::SeqCst) + 1;
+                fields.insert("seq".to_string(), seq.into());
+                Value::Object(fields).to_string()
+            }
+            _ => text.to_string(),
+        }
     }
 
     pub fn add_subscription(&mut self, subscription: Subscription) {
@@ -62,4 +583,200 @@ impl Hash for Session {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.id.hash(state);
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_will_needs_topic_and_json_payload() {
+        let options = ConnectOptions::from_query(Some("will_topic=account:7&will_payload=%7B%22a%22%3A1%7D"));
+        assert_eq!(options.last_will, Some(LastWill {
+            topic: Subscription { sub_type: "account".to_string(), id: "7".to_string() },
+            payload: serde_json::json!({"a": 1}),
+        }));
+        assert!(ConnectOptions::from_query(Some("will_topic=account:7")).last_will.is_none());
+        assert!(ConnectOptions::from_query(Some("will_topic=account:7&will_payload=not-json")).last_will.is_none());
+    }
+
+    #[test]
+    fn test_send_queue_high_water_warning() {
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.send_queue = SendQueue::new(3);
+
+        for _ in 0..5 {
+            session.send(WsMessage::Text("event".to_string())).unwrap();
+        }
+        assert_eq!(session.send_queue.queued(), 5);
+        assert_eq!(session.send_queue.high_water_warnings(), 1);
+
+        // Draining below the mark re-arms the warning
+        for _ in 0..4 {
+            session.send_queue.pop();
+        }
+        session.send(WsMessage::Text("event".to_string())).unwrap();
+        session.send(WsMessage::Text("event".to_string())).unwrap();
+        assert_eq!(session.send_queue.high_water_warnings(), 2);
+    }
+
+    #[test]
+    fn test_send_queue_counts_a_frame_before_it_can_be_taken() {
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let session = Session::new(Uuid::new_v4(), sender);
+
+        // Whenever a frame reaches the forward task it has already been counted
+        for burst in 1..=4 {
+            for _ in 0..burst {
+                session.send(WsMessage::Text("event".to_string())).unwrap();
+            }
+            for taken in 0..burst {
+                assert!(receiver.try_recv().is_ok());
+                assert_eq!(session.send_queue.queued(), burst - taken);
+                session.send_queue.pop();
+            }
+            assert_eq!(session.send_queue.queued(), 0);
+        }
+
+        // A frame that could not be queued is not counted
+        drop(receiver);
+        assert!(session.send(WsMessage::Text("event".to_string())).is_err());
+        assert_eq!(session.send_queue.queued(), 0);
+    }
+
+    fn topic(id: &str) -> Subscription {
+        Subscription { sub_type: "invoice".to_string(), id: id.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_events_within_the_interval_arrive_as_one_array_frame_per_subscription() {
+        use futures::StreamExt;
+
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.event_batch = EventBatch::new(Duration::from_millis(50));
+
+        for n in 1..=3 {
+            for id in ["inv_1", "inv_2"] {
+                let event = serde_json::json!({ "type": "invoice.updated", "uid": id, "n": n });
+                session.send_event(&topic(id), &event, &event.to_string()).unwrap();
+            }
+        }
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(session.stats.events_sent(), 0);
+
+        let mut batches = HashMap::new();
+        for _ in 0..2 {
+            let frame = tokio::time::timeout(Duration::from_secs(1), receiver.next()).await.unwrap().unwrap();
+            let WsMessage::Text(text) = frame else {
+                panic!("expected a text frame");
+            };
+            let batch: Vec<Value> = serde_json::from_str(&text).unwrap();
+            let uid = batch[0]["uid"].as_str().unwrap().to_string();
+            assert!(batch.iter().all(|event| event["uid"] == uid.as_str()));
+            batches.insert(uid, batch.iter().map(|event| event["n"].clone()).collect::<Vec<_>>());
+        }
+        assert_eq!(batches["inv_1"], vec![1, 2, 3]);
+        assert_eq!(batches["inv_2"], vec![1, 2, 3]);
+        assert_eq!(session.stats.events_sent(), 6);
+
+        // The next event starts a new batch
+        let event = serde_json::json!({ "type": "invoice.paid" });
+        session.send_event(&topic("inv_1"), &event, &event.to_string()).unwrap();
+        let WsMessage::Text(text) = receiver.next().await.unwrap() else {
+            panic!("expected a text frame");
+        };
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), serde_json::json!([{ "type": "invoice.paid" }]));
+    }
+
+    #[derive(Default)]
+    struct CollectingSink(Mutex<Vec<DeadLetter>>);
+
+    impl DeadLetterSink for CollectingSink {
+        fn record(&self, letter: DeadLetter) {
+            self.0.lock().unwrap().push(letter);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_follow_the_envelope_and_failed_ones_are_dead_lettered() {
+        use futures::StreamExt;
+
+        let sink = Arc::new(CollectingSink::default());
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let mut session = Session::new(Uuid::new_v4(), sender);
+        session.envelope_version = 1;
+        session.event_batch = EventBatch::new(Duration::from_millis(20)).with_dead_letter_sink(sink.clone());
+        let event = serde_json::json!({ "type": "invoice.paid" });
+
+        session.send_event(&topic("inv_1"), &event, &event.to_string()).unwrap();
+        let WsMessage::Text(text) = receiver.next().await.unwrap() else {
+            panic!("expected a text frame");
+        };
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), serde_json::json!({
+            "v": 1,
+            "status": "success",
+            "data": [{ "type": "invoice.paid" }],
+            "error": null
+        }));
+
+        // Batched while the channel was open, then closed before the flush
+        session.send_event(&topic("inv_1"), &event, &event.to_string()).unwrap();
+        drop(receiver);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let letters = sink.0.lock().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!((letters[0].topics.clone(), letters[0].event.clone()), (vec![topic("inv_1")], event));
+        assert!(matches!(letters[0].reason, DeadLetterReason::SendFailed { session_id, .. } if session_id == session.id));
+        assert_eq!(session.stats.events_sent(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_receive_side_sees_forward_task_failure() {
+        let state = ConnectionState::new();
+        let forward_state = state.clone();
+
+        // Stand-in for the forward task failing to write to the socket
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            forward_state.close();
+        });
+
+        tokio::time::timeout(std::time::Duration::from_millis(500), state.closed())
+            .await
+            .expect("receive side was not woken");
+        assert!(state.is_closed());
+
+        // Already closed resolves immediately
+        tokio::time::timeout(std::time::Duration::from_millis(10), state.closed())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_context_defaults_later_requests() {
+        let context = SessionContext::default();
+        let values = |value: Value| value.as_object().unwrap().clone();
+        context.update(values(serde_json::json!({ "account_id": 42, "note": "kept but unused" })), 4, 256).unwrap();
+
+        let request: Message = serde_json::from_value(serde_json::json!({
+            "action": "create_invoice", "amount": 10, "currency": "USD"
+        })).unwrap();
+        assert!(matches!(context.apply(request), Message::CreateInvoice { account_id: Some(42), .. }));
+
+        // Fields the client sends win over the context
+        let request: Message = serde_json::from_value(serde_json::json!({
+            "action": "create_invoice", "amount": 10, "currency": "USD", "account_id": 7
+        })).unwrap();
+        assert!(matches!(context.apply(request), Message::CreateInvoice { account_id: Some(7), .. }));
+
+        // Over the caps nothing changes
+        assert!(context.update(values(serde_json::json!({ "a": 1, "b": 2, "c": 3 })), 4, 256).is_err());
+        assert!(context.update(values(serde_json::json!({ "memo": "x".repeat(300) })), 4, 256).is_err());
+        assert_eq!(context.values().len(), 2);
+
+        let remaining = context.update(values(serde_json::json!({ "account_id": null })), 4, 256).unwrap();
+        assert_eq!(remaining.len(), 1);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::event_dispatcher::EventDispatcher;
+use crate::session::Session;
+
+/// Point-in-time view of connected sessions and what they are subscribed to,
+/// for debugging and for restoring subscription intent after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSnapshot {
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub id: Uuid,
+    pub account_id: Option<i32>,
+    pub subscriptions: Vec<SubscriptionSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionSnapshot {
+    #[serde(rename = "type")]
+    pub sub_type: String,
+    pub id: String,
+    pub subscription_id: String,
+}
+
+impl ServerSnapshot {
+    pub async fn capture(sessions: &HashMap<Uuid, Session>, event_dispatcher: &EventDispatcher) -> Self {
+        let mut subscriptions = event_dispatcher.subscriptions_by_session().await;
+
+        let mut sessions: Vec<SessionSnapshot> = sessions.values()
+            .map(|session| {
+                let mut subscriptions: Vec<SubscriptionSnapshot> = subscriptions.remove(&session.id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(subscription, subscription_id)| SubscriptionSnapshot {
+                        sub_type: subscription.sub_type,
+                        id: subscription.id,
+                        subscription_id,
+                    })
+                    .collect();
+                subscriptions.sort_by(|a, b| (&a.sub_type, &a.id).cmp(&(&b.sub_type, &b.id)));
+
+                SessionSnapshot {
+                    id: session.id,
+                    account_id: session.account_id,
+                    subscriptions,
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+
+        ServerSnapshot {
+            created_at: chrono::Utc::now().to_rfc3339(),
+            sessions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_lists_sessions_and_subscriptions() {
+        let dispatcher = EventDispatcher::new();
+        let mut sessions = HashMap::new();
+
+        let (sender, _alice_rx) = futures::channel::mpsc::unbounded();
+        let mut alice = Session::new(Uuid::new_v4(), sender);
+        alice.set_account_id(7);
+        let (sender, _bob_rx) = futures::channel::mpsc::unbounded();
+        let bob = Session::new(Uuid::new_v4(), sender);
+        sessions.insert(alice.id, alice.clone());
+        sessions.insert(bob.id, bob.clone());
+
+        let invoice_sub = dispatcher.subscribe(alice.clone(), "invoice", "inv_123").await;
+        let account_sub = dispatcher.subscribe(alice.clone(), "account", "7").await;
+
+        let snapshot = ServerSnapshot::capture(&sessions, &dispatcher).await;
+        assert_eq!(snapshot.sessions.len(), 2);
+
+        let alice_snapshot = snapshot.sessions.iter().find(|s| s.id == alice.id).unwrap();
+        assert_eq!(alice_snapshot.account_id, Some(7));
+        assert_eq!(alice_snapshot.subscriptions, vec![
+            SubscriptionSnapshot { sub_type: "account".to_string(), id: "7".to_string(), subscription_id: account_sub },
+            SubscriptionSnapshot { sub_type: "invoice".to_string(), id: "inv_123".to_string(), subscription_id: invoice_sub },
+        ]);
+
+        let bob_snapshot = snapshot.sessions.iter().find(|s| s.id == bob.id).unwrap();
+        assert_eq!(bob_snapshot.account_id, None);
+        assert!(bob_snapshot.subscriptions.is_empty());
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["sessions"].as_array().unwrap().len(), 2);
+    }
+}
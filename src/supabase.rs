@@ -5,27 +5,92 @@ use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use std::sync::RwLock;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::time::{interval, Duration};
 use std::sync::Arc;
 use anyhow::{Result, anyhow};
 use reqwest;
 use crate::confirmations::{Payment, Confirmation};
-use crate::{payment::ConversionRequest, payment_options::create_payment_options, types::{Account, Address, Coin, CreateInvoiceRequest, Invoice, PaymentOption, Price}};
+use crate::event_bus::{EventBus, StoreEvent};
+use crate::uid::UidScheme;
+use crate::{payment::ConversionRequest, payment_options::create_payment_options, types::{Account, Address, Coin, CreateInvoiceRequest, Invoice, InvoiceStatusTransition, PaymentOption, Price}};
 
 lazy_static! {
     static ref COIN_CACHE: RwLock<Option<HashMap<String, Coin>>> = RwLock::new(None);
     static ref PRICE_CACHE: RwLock<HashMap<String, Price>> = RwLock::new(HashMap::new());
 }
 
+/// Header carrying the trace id of the client request a store call is made for.
+pub const TRACE_ID_HEADER: &str = "X-Trace-Id";
+
+tokio::task_local! {
+    /// Trace id of the client request being handled. Store calls made while
+    /// it is set send it as `X-Trace-Id` so backend logs can be correlated.
+    pub static TRACE_ID: String;
+}
+
+fn current_trace_id() -> Option<String> {
+    TRACE_ID.try_with(|trace_id| trace_id.clone()).ok()
+}
+
+/// Whether a client-supplied trace id is safe to forward as a header.
+pub fn is_valid_trace_id(trace_id: &str) -> bool {
+    !trace_id.is_empty()
+        && trace_id.len() <= 128
+        && trace_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// How requests the store answers with 429 Too Many Requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffPolicy {
+    /// Retries after the first attempt. 0 surfaces a 429 straight away.
+    pub retries: usize,
+    /// The longest wait between attempts. A longer `Retry-After` is not waited
+    /// out, it is passed on to the caller.
+    pub max_wait: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            retries: 2,
+            max_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+/// First wait when a 429 carries no `Retry-After`; doubled on each retry.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(250);
+
 #[derive(Clone)]
 pub struct SupabaseClient {
     client: Arc<Postgrest>,
     anon_key: String,
     service_role_key: String,
     base_url: String,
+    events: EventBus,
+    allowed_tables: Arc<HashSet<String>>,
+    uid_scheme: UidScheme,
+    backoff: BackoffPolicy,
+    tolerate_schema_drift: bool,
 }
 
+/// Uids tried for a new invoice before giving up.
+const INVOICE_UID_ATTEMPTS: usize = 3;
+
+/// Tables this client may touch unless overridden with `with_allowed_tables`.
+pub const DEFAULT_ALLOWED_TABLES: &[&str] = &[
+    "access_tokens",
+    "accounts",
+    "addresses",
+    "coins",
+    "invoice_status_history",
+    "invoices",
+    "payment_options",
+    "payments",
+    "prices",
+];
+
 impl SupabaseClient {
     pub fn new(url: &str, anon_key: &str, service_role_key: &str) -> Self {
         // Ensure URL ends with /rest/v1
@@ -44,9 +109,118 @@ impl SupabaseClient {
             anon_key: anon_key.to_string(),
             service_role_key: service_role_key.to_string(),
             base_url: api_url,
+            events: EventBus::default(),
+            allowed_tables: Arc::new(DEFAULT_ALLOWED_TABLES.iter().map(|t| t.to_string()).collect()),
+            uid_scheme: UidScheme::default(),
+            backoff: BackoffPolicy::default(),
+            tolerate_schema_drift: true,
         }
     }
 
+    /// Publishes change events on `events` instead of a bus of its own, so
+    /// writes through several clients reach the same listeners.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Generates new invoice uids with `scheme`.
+    pub fn with_uid_scheme(mut self, scheme: UidScheme) -> Self {
+        self.uid_scheme = scheme;
+        self
+    }
+
+    /// Retries rate-limited requests according to `backoff`.
+    pub fn with_backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Whether invoice rows that don't match the expected schema are patched
+    /// with `Invoice::repair_row` and logged rather than failing the request.
+    pub fn with_schema_drift_tolerance(mut self, tolerate: bool) -> Self {
+        self.tolerate_schema_drift = tolerate;
+        self
+    }
+
+    /// Parses a response's invoice rows, repairing drifted ones when tolerated.
+    fn parse_invoices(&self, text: &str) -> serde_json::Result<Vec<Invoice>> {
+        if !self.tolerate_schema_drift {
+            return serde_json::from_str(text);
+        }
+        let rows: Vec<Value> = serde_json::from_str(text)?;
+        rows.into_iter()
+            .map(|mut row| {
+                let repaired = Invoice::repair_row(&mut row);
+                if !repaired.is_empty() {
+                    tracing::warn!(
+                        invoice_uid = row["uid"].as_str().unwrap_or("unknown"),
+                        fields = ?repaired,
+                        "Invoice row doesn't match the expected schema"
+                    );
+                }
+                serde_json::from_value(row)
+            })
+            .collect()
+    }
+
+    /// Restricts the client to the given tables. Queries against any other
+    /// table fail before a request is sent.
+    pub fn with_allowed_tables<I, S>(mut self, tables: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_tables = Arc::new(tables.into_iter().map(Into::into).collect());
+        self
+    }
+
+    fn check_table(&self, table: &str) -> Result<()> {
+        if self.allowed_tables.contains(table) {
+            Ok(())
+        } else {
+            Err(anyhow!("Table '{}' is not in the allowlist", table))
+        }
+    }
+
+    fn from(&self, table: &str) -> Result<postgrest::Builder> {
+        self.check_table(table)?;
+        Ok(match current_trace_id() {
+            Some(trace_id) => (*self.client).clone().insert_header(TRACE_ID_HEADER, trace_id).from(table),
+            None => self.client.from(table),
+        })
+    }
+
+    /// Sends a query, waiting and retrying while the store answers 429. Once
+    /// retries run out, or the store asks for a longer wait than the policy
+    /// allows, the 429 is returned as a `SupabaseError` carrying `retry_after`.
+    /// Other statuses are passed through.
+    async fn execute(&self, query: postgrest::Builder) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let response = query.clone().execute().await?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+
+            let retry_after = parse_retry_after(response.headers());
+            let wait = retry_after.unwrap_or(DEFAULT_BACKOFF * 2u32.pow(attempt as u32));
+            if attempt >= self.backoff.retries || wait > self.backoff.max_wait {
+                let body = response.text().await.unwrap_or_default();
+                return Err(SupabaseError { status: 429, body, retry_after }.into());
+            }
+
+            attempt += 1;
+            tracing::warn!("Store is rate limiting requests, retrying in {:?} (attempt {})", wait, attempt);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Change events published by this client's write methods.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
     pub async fn get_invoice(&self, invoice_id: &str, use_service_role: bool) -> Result<Option<(Invoice, Vec<PaymentOption>)>> {
         let auth_key = if use_service_role {
             &self.service_role_key
@@ -56,36 +230,20 @@ impl SupabaseClient {
 
         tracing::info!("Fetching invoice with id: {}", invoice_id);
 
-        // Get invoice
-        let response = self.client.as_ref()
-            .from("invoices")
-            .select("*")
-            .eq("uid", invoice_id)
-            .auth(self.service_role_key.to_string())
-            .execute()
-            .await
-            .map_err(|e| anyhow!("Failed to fetch invoice: {}", e))?;
-
-        tracing::info!("Invoice response: {:?}", response);
-
-        let response_text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
-        tracing::info!("Invoice response text: {:?}", response_text);
-        let invoices: Vec<Invoice> = serde_json::from_str(&response_text)
-            .map_err(|e| anyhow!("Failed to parse invoice: {}", e))?;
+        if let Some(invoice) = self.get_invoice_record(invoice_id).await? {
+            // Deleted invoices can't be paid, so there are no options to refresh
+            if invoice.is_deleted() {
+                return Ok(Some((invoice, Vec::new())));
+            }
 
-        tracing::info!("Invoices: {:?}", invoices);
-        
-        if let Some(invoice) = invoices.into_iter().next() {
             // Get payment options
-            let response = self.client.as_ref()
-                .from("payment_options")
+            let query = self.from("payment_options")?
                 .select("*")
                 .eq("invoice_uid", invoice_id)
-                .auth(auth_key)
-                .execute()
+                .auth(auth_key);
+            let response = self.execute(query)
                 .await
-                .map_err(|e| anyhow!("Failed to fetch payment options: {}", e))?;
+                .map_err(request_failed("Failed to fetch payment options"))?;
 
             let response_text = response.text().await
                 .map_err(|e| anyhow!("Failed to read response: {}", e))?;
@@ -110,6 +268,87 @@ impl SupabaseClient {
         }
     }
 
+    /// Fetches only the invoice row, without loading or refreshing payment options.
+    pub async fn get_invoice_record(&self, invoice_id: &str) -> Result<Option<Invoice>> {
+        let query = self.from("invoices")?
+            .select("*")
+            .eq("uid", invoice_id)
+            .auth(self.service_role_key.to_string());
+        let response = self.execute(query)
+            .await
+            .map_err(request_failed("Failed to fetch invoice"))?;
+
+        tracing::info!("Invoice response: {:?}", response);
+
+        let response_text = response.text().await
+            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+        tracing::info!("Invoice response text: {:?}", response_text);
+        let invoices = self.parse_invoices(&response_text)
+            .map_err(|e| anyhow!("Failed to parse invoice: {}", e))?;
+
+        tracing::info!("Invoices: {:?}", invoices);
+
+        Ok(invoices.into_iter().next())
+    }
+
+    /// Inserts an invoice row under a uid from the client's scheme, trying a
+    /// fresh uid if the store reports the generated one is taken.
+    async fn insert_invoice(&self, mut row: Value) -> Result<Invoice> {
+        let mut attempt = 1;
+        let response = loop {
+            let uid = self.uid_scheme.generate();
+            row["uid"] = json!(uid);
+
+            let query = self.from("invoices")?
+                .insert(json!([row]).to_string())
+                .auth(&self.service_role_key);
+            let response = self.execute(query)
+                .await
+                .map_err(request_failed("Failed to create invoice"))?;
+            match SupabaseError::check(response).await {
+                Ok(response) => break response,
+                Err(e) if attempt < INVOICE_UID_ATTEMPTS && e.downcast_ref::<SupabaseError>().is_some_and(SupabaseError::is_unique_violation) => {
+                    tracing::warn!("Invoice uid {} is already taken, generating another", uid);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let response_text = response.text()
+            .await
+            .map_err(|e| anyhow!("Failed to get response text: {}", e))?;
+        tracing::info!("Create invoice response: {}", response_text);
+
+        let invoices = self.parse_invoices(&response_text)
+            .map_err(|e| anyhow!("Failed to parse invoice response: {}", e))?;
+        invoices.into_iter().next()
+            .ok_or_else(|| anyhow!("No invoice created"))
+    }
+
+    /// Which of `uids` are invoices belonging to the account.
+    pub async fn filter_account_invoices(&self, account_id: i64, uids: &[String]) -> Result<Vec<String>> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let query = self.from("invoices")?
+            .select("uid")
+            .eq("account_id", account_id.to_string())
+            .in_("uid", uids)
+            .auth(self.service_role_key.to_string());
+        let response = self.execute(query)
+            .await
+            .map_err(request_failed("Failed to fetch account invoices"))?;
+        let response = SupabaseError::check(response).await?;
+
+        let rows: Vec<serde_json::Value> = response.json().await
+            .map_err(|e| anyhow!("Failed to parse account invoices: {}", e))?;
+        Ok(rows.iter()
+            .filter_map(|row| row["uid"].as_str().map(String::from))
+            .collect())
+    }
+
     pub async fn create_invoice(
         &self,
         amount: i64,
@@ -118,41 +357,29 @@ impl SupabaseClient {
         webhook_url: Option<String>,
         redirect_url: Option<String>,
         memo: Option<String>,
+        tags: Vec<String>,
     ) -> Result<serde_json::Value> {
-        let uid = format!("inv_{}", crate::payment::generate_uid());
-        let new_invoice = serde_json::json!([{
+        let new_invoice = serde_json::json!({
             "amount": amount,
             "currency": currency,
             "account_id": account_id,
             "status": "unpaid",
-            "uid": uid.clone(),
             "webhook_url": webhook_url,
             "redirect_url": redirect_url,
             "memo": memo,
+            "tags": tags,
             "uri": format!("pay:?r=https://api.anypayx.com/r/{}", crate::payment::generate_uid()),
             "createdAt": Utc::now().to_rfc3339(),
             "updatedAt": Utc::now().to_rfc3339(),
-        }]);
+        });
 
         tracing::info!("New invoice: {}", new_invoice);
+        let invoice = self.insert_invoice(new_invoice).await?;
 
-        let response = self.client.as_ref()
-            .from("invoices")
-            .insert(&serde_json::to_string(&new_invoice).map_err(|e| anyhow!("Failed to serialize invoice: {}", e))?)
-            .auth(&self.service_role_key)
-            .execute()
-            .await
-            .map_err(|e| anyhow!("Failed to create invoice: {}", e))?;
-
-        let response_text = response.text()
-            .await
-            .map_err(|e| anyhow!("Failed to get response text: {}", e))?;
-        tracing::info!("Create invoice response: {}", response_text);
-
-        let invoices: Vec<Invoice> = serde_json::from_str(&response_text)
-            .map_err(|e| anyhow!("Failed to parse invoice response: {}", e))?;
-        let invoice = invoices.into_iter().next()
-            .ok_or_else(|| anyhow!("No invoice created"))?;
+        if let Err(e) = self.record_status_transition(&invoice.uid, &invoice.status).await {
+            tracing::error!("Failed to record status history for {}: {}", invoice.uid, e);
+        }
+        self.events.publish(StoreEvent::InvoiceCreated(invoice.clone()));
         
         // Get account and create payment options
         let account = self.get_account(account_id)
@@ -168,14 +395,18 @@ impl SupabaseClient {
         }))
     }
 
-    pub async fn list_prices(&self) -> Result<Vec<Price>> {
-        let response = self.client.as_ref()
-            .from("prices")
-            .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+    pub async fn list_prices(&self, limit: Option<usize>) -> Result<Vec<Price>> {
+        let mut query = self.from("prices")?
+            .select("*");
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+
+        let query = query
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await
-            .map_err(|e| anyhow!("Failed to fetch prices: {}", e))?;
+            .map_err(request_failed("Failed to fetch prices"))?;
 
         let text = response.text().await
             .map_err(|e| anyhow!("Failed to read response: {}", e))?;
@@ -186,30 +417,34 @@ impl SupabaseClient {
     }
 
     pub async fn get_account(&self, account_id: i64) -> Result<Account> {
-        let response = self.client.as_ref()
-            .from("accounts")
+        self.find_account(account_id).await?
+            .ok_or_else(|| anyhow!("Account not found"))
+    }
+
+    /// The account with `account_id`, or None when there is none. Failing to
+    /// ask the store is an error.
+    pub async fn find_account(&self, account_id: i64) -> Result<Option<Account>> {
+        let query = self.from("accounts")?
             .select("*")
             .eq("id", account_id.to_string())
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await
-            .map_err(|e| anyhow!("Failed to fetch account: {}", e))?;
+            .map_err(request_failed("Failed to fetch account"))?;
 
         let text = response.text().await
             .map_err(|e| anyhow!("Failed to read response: {}", e))?;
 
         let accounts: Vec<Account> = serde_json::from_str(&text)
             .map_err(|e| anyhow!("Failed to parse account: {}", e))?;
-        accounts.into_iter().next()
-            .ok_or_else(|| anyhow!("Account not found"))
+        Ok(accounts.into_iter().next())
     }
 
     pub async fn list_available_addresses(&self, account: &Account) -> Result<Vec<Address>> {
-        let response_text = self.client.as_ref()
-            .from("addresses")
+        let query = self.from("addresses")?
             .select("*")
-            .eq("account_id", account.id.to_string())
-            .execute()
+            .eq("account_id", account.id.to_string());
+        let response_text = self.execute(query)
             .await?
             .text()
             .await?;
@@ -238,11 +473,10 @@ impl SupabaseClient {
         }
 
         // Load coins if cache is empty
-        let response = self.client.as_ref()
-            .from("coins")
+        let query = self.from("coins")?
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await?;
 
         let response_text = response.text().await?;
@@ -261,11 +495,10 @@ impl SupabaseClient {
     }
 
     pub async fn get_coins(&self) -> Result<HashMap<String, Coin>> {
-        let response = self.client.as_ref()
-            .from("coins")
+        let query = self.from("coins")?
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await?;
 
         let response_text = response.text().await?;
@@ -299,11 +532,10 @@ impl SupabaseClient {
     }
 
     pub async fn create_payment_options(&self, options: &[PaymentOption]) -> Result<Vec<PaymentOption>> {
-        let response = self.client.as_ref()
-            .from("payment_options")
+        let query = self.from("payment_options")?
             .insert(&serde_json::to_string(&serde_json::json!(options))?)
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await?;
 
         let response_text = response.text().await?;
@@ -327,11 +559,10 @@ impl SupabaseClient {
     }
 
     pub async fn refresh_prices(&self) -> Result<()> {
-        let response = self.client.as_ref()
-            .from("prices")
+        let query = self.from("prices")?
             .select("*")
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await?;
 
         let response_text = response.text().await?;
@@ -355,13 +586,12 @@ impl SupabaseClient {
     }
 
     pub async fn find_price(&self, base_currency: &str, currency: &str) -> Result<Option<Price>> {
-        let response = self.client.as_ref()
-            .from("prices")
+        let query = self.from("prices")?
             .select("*")
             .eq("base_currency", base_currency)
             .eq("currency", currency)
-            .auth(&self.service_role_key)
-            .execute()
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
             .await?;
 
         let response_text = response.text().await?;
@@ -371,25 +601,76 @@ impl SupabaseClient {
     }
 
     pub async fn update_invoice_status(&self, uid: &str, status: &str) -> Result<()> {
-        self.client.as_ref()
-            .from("invoices")
+        let query = self.from("invoices")?
             .update(&serde_json::to_string(&json!({
                 "status": status
             }))?)
-            .eq("uid", uid)
-            .execute()
+            .eq("uid", uid);
+        let response = self.execute(query)
+            .await?;
+
+        if let Err(e) = self.record_status_transition(uid, status).await {
+            tracing::error!("Failed to record status history for {}: {}", uid, e);
+        }
+
+        // The update returns the written rows, which become the event payload
+        let text = response.text().await?;
+        match self.parse_invoices(&text) {
+            Ok(invoices) => {
+                for invoice in invoices {
+                    self.events.publish(StoreEvent::InvoiceStatusChanged(invoice));
+                }
+            }
+            Err(e) => tracing::error!("Failed to parse updated invoice {}: {}", uid, e),
+        }
+        Ok(())
+    }
+
+    pub async fn mark_paid(&self, uid: &str) -> Result<()> {
+        self.update_invoice_status(uid, "paid").await
+    }
+
+    pub async fn record_status_transition(&self, uid: &str, status: &str) -> Result<()> {
+        let transition = InvoiceStatusTransition {
+            invoice_uid: uid.to_string(),
+            status: status.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+        };
+
+        let query = self.from("invoice_status_history")?
+            .insert(&serde_json::to_string(&json!([transition]))?)
+            .auth(&self.service_role_key);
+        self.execute(query)
             .await?;
         Ok(())
     }
 
+    pub async fn get_invoice_status_history(&self, uid: &str, limit: usize) -> Result<Vec<InvoiceStatusTransition>> {
+        let query = self.from("invoice_status_history")?
+            .select("*")
+            .eq("invoice_uid", uid)
+            .order("createdAt.asc")
+            .limit(limit)
+            .auth(&self.service_role_key);
+        let response = self.execute(query)
+            .await
+            .map_err(request_failed("Failed to fetch invoice history"))?;
+
+        let text = response.text().await
+            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+
+        let history = serde_json::from_str::<Vec<InvoiceStatusTransition>>(&text)
+            .map_err(|e| anyhow!("Failed to parse invoice history: {}", e))?;
+        Ok(history)
+    }
+
     pub async fn validate_api_key(&self, api_key: &str) -> Result<Option<i32>> {
         println!("api_key: {:?}", api_key);
-        let response = self.client.as_ref()
-            .from("access_tokens")
+        let query = self.from("access_tokens")?
             .select("account_id")
             .eq("uid", api_key)
-            .single()
-            .execute()
+            .single();
+        let response = self.execute(query)
             .await?;
 
         println!("response: {:?}", response);
@@ -418,33 +699,38 @@ impl SupabaseClient {
     }
 
     async fn get(&self, path: &str) -> Result<reqwest::Response> {
+        self.check_table(table_from_path(path))?;
         Ok(reqwest::Client::new()
             .get(format!("{}{}", self.base_url, path))
             .header("apikey", &self.anon_key)
             .header("Authorization", format!("Bearer {}", self.service_role_key))
+            .headers(trace_headers())
             .send()
             .await?)
     }
 
     async fn patch(&self, path: &str, body: serde_json::Value) -> Result<reqwest::Response> {
+        self.check_table(table_from_path(path))?;
         Ok(reqwest::Client::new()
             .patch(format!("{}{}", self.base_url, path))
             .header("apikey", &self.anon_key)
             .header("Authorization", format!("Bearer {}", self.service_role_key))
+            .header("Prefer", "return=representation")
+            .headers(trace_headers())
             .json(&body)
             .send()
             .await?)
     }
 
     pub async fn get_unconfirmed_payment_by_txid(&self, txid: &str) -> Result<Option<Payment>> {
-        let path = format!("/rest/v1/payments?txid=eq.{}&confirmation_hash=is.null", txid);
+        let path = format!("/payments?txid=eq.{}&confirmation_hash=is.null", txid);
         let response = self.get(&path).await?;
         let payments: Vec<Payment> = response.json().await?;
         Ok(payments.into_iter().next())
     }
 
     pub async fn confirm_payment(&self, payment: Payment, confirmation: Confirmation) -> Result<Payment> {
-        let path = format!("/rest/v1/payments?id=eq.{}", payment.id);
+        let path = format!("/payments?id=eq.{}", payment.id);
         let response = self.patch(&path, json!({
             "confirmation_hash": confirmation.confirmation_hash,
             "confirmation_height": confirmation.confirmation_height,
@@ -452,17 +738,18 @@ impl SupabaseClient {
             "status": "confirmed"
         })).await?;
 
-        Ok(response.json().await?)
+        let payments: Vec<Payment> = response.error_for_status()?.json().await?;
+        payments.into_iter().next().ok_or_else(|| anyhow!("Payment {} not found", payment.id))
     }
 
     pub async fn get_unconfirmed_payments(&self, chain: &str, currency: &str) -> Result<Vec<Payment>> {
-        let path = format!("/rest/v1/payments?chain=eq.{}&currency=eq.{}&confirmation_hash=is.null", chain, currency);
+        let path = format!("/payments?chain=eq.{}&currency=eq.{}&confirmation_hash=is.null", chain, currency);
         let response = self.get(&path).await?;
         Ok(response.json().await?)
     }
 
     pub async fn get_payment_by_txid(&self, txid: &str) -> Result<Option<Payment>> {
-        let path = format!("/rest/v1/payments?txid=eq.{}", txid);
+        let path = format!("/payments?txid=eq.{}", txid);
         let response = self.get(&path).await?;
         let payments: Vec<Payment> = response.json().await?;
         Ok(payments.into_iter().next())
@@ -475,7 +762,7 @@ impl SupabaseClient {
         confirmation_height: i32,
         confirmation_date: &DateTime<Utc>,
     ) -> Result<Payment> {
-        let path = format!("/rest/v1/payments?id=eq.{}", id);
+        let path = format!("/payments?id=eq.{}", id);
         let response = self.patch(&path, json!({
             "confirmation_hash": confirmation_hash,
             "confirmation_height": confirmation_height,
@@ -483,7 +770,8 @@ impl SupabaseClient {
             "status": "confirmed"
         })).await?;
 
-        Ok(response.json().await?)
+        let payments: Vec<Payment> = response.error_for_status()?.json().await?;
+        payments.into_iter().next().ok_or_else(|| anyhow!("Payment {} not found", id))
     }
 }
 
@@ -524,4 +812,282 @@ pub async fn convert(
 
     Ok(result)*/
     Ok(converted)
-}
\ No newline at end of file
+}
+/// A request Supabase rejected, e.g. a constraint violation on insert. The body
+/// is kept so debug builds of a response can show why.
+#[derive(Debug)]
+pub struct SupabaseError {
+    pub status: u16,
+    pub body: String,
+    /// How long the store asked callers to wait, from a 429's `Retry-After`.
+    pub retry_after: Option<Duration>,
+}
+
+impl SupabaseError {
+    /// Passes successful responses through and turns the rest into a `SupabaseError`.
+    pub async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let retry_after = parse_retry_after(response.headers());
+        let body = response.text().await.unwrap_or_default();
+        Err(SupabaseError { status: status.as_u16(), body, retry_after }.into())
+    }
+
+    /// The PostgREST error fields (`code`, `message`, `details`, `hint`), without
+    /// anything else the body may carry.
+    pub fn detail(&self) -> Value {
+        let mut detail = json!({ "status": self.status });
+        match serde_json::from_str::<Value>(&self.body) {
+            Ok(Value::Object(body)) => {
+                for field in ["code", "message", "details", "hint"] {
+                    if let Some(value) = body.get(field) {
+                        detail[field] = value.clone();
+                    }
+                }
+            }
+            _ => detail["message"] = json!(self.body.chars().take(500).collect::<String>()),
+        }
+        detail
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == 429
+    }
+
+    /// Whether the request broke a unique constraint (Postgres error 23505).
+    pub fn is_unique_violation(&self) -> bool {
+        self.status == 409 && self.detail()["code"] == "23505"
+    }
+}
+
+impl std::fmt::Display for SupabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Supabase request failed with status {}", self.status)
+    }
+}
+
+impl std::error::Error for SupabaseError {}
+
+/// Delay-seconds form of `Retry-After`. HTTP dates are not supported.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers.get(reqwest::header::RETRY_AFTER)?
+        .to_str().ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Prefixes a failed request's error with what was being done, keeping the
+/// error itself so callers can still inspect a `SupabaseError`.
+fn request_failed(what: &'static str) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+    move |e| {
+        let message = format!("{}: {}", what, e);
+        e.context(message)
+    }
+}
+
+fn trace_headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(value) = current_trace_id().and_then(|trace_id| trace_id.parse().ok()) {
+        headers.insert(TRACE_ID_HEADER, value);
+    }
+    headers
+}
+
+/// Table name from a REST path such as `/rest/v1/payments?id=eq.1`.
+fn table_from_path(path: &str) -> &str {
+    let path = path.trim_start_matches("/rest/v1/").trim_start_matches('/');
+    path.split(['?', '/']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{spawn_store, StoreResponse};
+
+    #[tokio::test]
+    async fn test_tables_outside_allowlist_are_refused() {
+        // Nothing listens on the discard port, so any request would fail differently
+        let client = SupabaseClient::new("http://127.0.0.1:9", "anon", "service")
+            .with_allowed_tables(["invoices"]);
+
+        assert!(client.from("invoices").is_ok());
+        let err = client.from("accounts").err().unwrap();
+        assert_eq!(err.to_string(), "Table 'accounts' is not in the allowlist");
+
+        let err = client.get_account(1).await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'accounts' is not in the allowlist");
+        let err = client.get_unconfirmed_payment_by_txid("abc").await.unwrap_err();
+        assert_eq!(err.to_string(), "Table 'payments' is not in the allowlist");
+    }
+
+    #[tokio::test]
+    async fn test_trace_id_is_sent_with_store_requests() {
+        // A store that answers every query with no rows
+        let (url, requests) = spawn_store(|_| async { StoreResponse::json("[]") }).await;
+        let client = SupabaseClient::new(&url, "anon", "service");
+        let last_head = || requests.lock().unwrap().last().unwrap().head.clone();
+
+        let found = TRACE_ID.scope("trace-abc".to_string(), client.get_invoice_record("inv_123")).await.unwrap();
+        assert!(found.is_none());
+        assert!(last_head().contains("x-trace-id: trace-abc"));
+
+        TRACE_ID.scope("trace-def".to_string(), client.get_unconfirmed_payment_by_txid("abc")).await.unwrap();
+        assert!(last_head().contains("x-trace-id: trace-def"));
+
+        // Outside a request there is nothing to forward
+        client.get_invoice_record("inv_123").await.unwrap();
+        assert!(!last_head().contains("x-trace-id"));
+
+        assert!(is_valid_trace_id("4bf92f35-77b3.4a_1"));
+        assert!(!is_valid_trace_id("bad\r\nheader"));
+        assert!(!is_valid_trace_id(""));
+    }
+
+    #[tokio::test]
+    async fn test_invoices_use_the_configured_uid_scheme_and_retry_collisions() {
+        // The first uid collides with an existing invoice; later inserts succeed
+        let inserts = std::sync::atomic::AtomicUsize::new(0);
+        let (url, requests) = spawn_store(move |request| {
+            let response = if inserts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                StoreResponse::status(409, json!({
+                    "code": "23505",
+                    "message": "duplicate key value violates unique constraint \"invoices_uid_key\""
+                }))
+            } else {
+                let mut rows: Value = serde_json::from_str(&request.body).unwrap();
+                rows[0]["id"] = json!(1);
+                StoreResponse::status(201, rows)
+            };
+            async { response }
+        }).await;
+        let client = SupabaseClient::new(&url, "anon", "service")
+            .with_uid_scheme("base62:pos_".parse().unwrap());
+
+        let invoice = client.insert_invoice(json!({
+            "amount": 1000,
+            "currency": "USD",
+            "account_id": 42,
+            "status": "unpaid",
+            "uri": "pay:?r=https://api.anypayx.com/r/abc",
+            "createdAt": "2024-01-01T12:00:00Z",
+            "updatedAt": "2024-01-01T12:00:00Z"
+        })).await.unwrap();
+
+        assert!(invoice.uid.starts_with("pos_"), "{}", invoice.uid);
+        let inserted: Vec<Value> = requests.lock().unwrap().iter().map(|request| serde_json::from_str(&request.body).unwrap()).collect();
+        let (first, second) = (&inserted[0], &inserted[1]);
+        assert_ne!(first[0]["uid"], second[0]["uid"]);
+        assert_eq!(second[0]["uid"], invoice.uid.as_str());
+    }
+
+    #[test]
+    fn test_supabase_error_detail_is_sanitized() {
+        let err = SupabaseError {
+            status: 409,
+            body: json!({
+                "code": "23505",
+                "message": "duplicate key value violates unique constraint \"invoices_uid_key\"",
+                "details": "Key (uid)=(inv_123) already exists.",
+                "hint": null,
+                "internal": "not for clients"
+            }).to_string(),
+            retry_after: None,
+        };
+
+        assert_eq!(err.detail(), json!({
+            "status": 409,
+            "code": "23505",
+            "message": "duplicate key value violates unique constraint \"invoices_uid_key\"",
+            "details": "Key (uid)=(inv_123) already exists.",
+            "hint": null
+        }));
+        assert_eq!(err.to_string(), "Supabase request failed with status 409");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_requests_back_off_and_surface_retry_after() {
+        // Two 429s asking for no wait, then rows
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = attempts.clone();
+        let (url, _requests) = spawn_store(move |_| {
+            let response = match seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => StoreResponse::status(429, "{}").with_header("retry-after: 0"),
+                _ => StoreResponse::json("[]"),
+            };
+            async { response }
+        }).await;
+        let client = SupabaseClient::new(&url, "anon", "service");
+        assert!(client.get_invoice_record("inv_123").await.unwrap().is_none());
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // A wait past the policy's limit is passed on instead of waited out
+        let (url, requests) = spawn_store(|_| async {
+            StoreResponse::status(429, "{}").with_header("retry-after: 30")
+        }).await;
+        let client = SupabaseClient::new(&url, "anon", "service");
+        let err = client.get_invoice_record("inv_123").await.unwrap_err();
+        let err = err.downcast_ref::<SupabaseError>().expect("the 429 is kept");
+        assert!(err.is_rate_limited());
+        assert_eq!(err.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Without retries the first 429 is returned
+        let (url, requests) = spawn_store(|_| async { StoreResponse::status(429, "{}") }).await;
+        let client = SupabaseClient::new(&url, "anon", "service").with_backoff(BackoffPolicy {
+            retries: 0,
+            max_wait: Duration::from_secs(5),
+        });
+        let err = client.get_invoice_record("inv_123").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<SupabaseError>().unwrap().retry_after, None);
+        assert!(err.to_string().starts_with("Failed to fetch invoice: "));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drifted_invoice_rows_are_repaired_when_tolerated() {
+        let row = |amount: Value, with_uri: bool| {
+            let mut row = json!({
+                "id": 1,
+                "uid": "inv_123",
+                "amount": amount,
+                "currency": "USD",
+                "status": "unpaid",
+                "account_id": 42,
+                "createdAt": "2024-01-01T12:00:00Z",
+                "updatedAt": "2024-01-01T12:00:00Z"
+            });
+            if with_uri {
+                row["uri"] = json!("pay:?r=https://api.anypayx.com/r/inv_123");
+            }
+            json!([row]).to_string()
+        };
+
+        // No memo or other optional fields, and no uri either
+        let missing = row(json!(1000), false);
+        let (url, _requests) = spawn_store(move |_| {
+            let response = StoreResponse::json(&missing);
+            async { response }
+        }).await;
+        let invoice = SupabaseClient::new(&url, "anon", "service").get_invoice_record("inv_123").await.unwrap().unwrap();
+        assert_eq!(invoice.memo, None);
+        assert_eq!(invoice.uri, "");
+
+        // The amount as a string
+        let stringly = row(json!("1000"), true);
+        let (url, _requests) = spawn_store(move |_| {
+            let response = StoreResponse::json(&stringly);
+            async { response }
+        }).await;
+        let client = SupabaseClient::new(&url, "anon", "service");
+        let invoice = client.get_invoice_record("inv_123").await.unwrap().unwrap();
+        assert_eq!(invoice.amount, 1000);
+
+        // Strict clients still refuse the row
+        let err = client.with_schema_drift_tolerance(false).get_invoice_record("inv_123").await.unwrap_err();
+        assert!(err.to_string().starts_with("Failed to parse invoice: "));
+    }
+}
@@ -0,0 +1,202 @@
+//! A stand-in for the Supabase REST API and the invoice fixture the tests share.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use crate::types::Invoice;
+
+/// An unpaid-looking 1000 USD invoice for account 42 in `status`. Adjust
+/// other fields with struct update syntax.
+pub fn invoice(uid: &str, status: &str) -> Invoice {
+    Invoice {
+        id: 1,
+        uid: uid.to_string(),
+        amount: 1000,
+        currency: "USD".to_string(),
+        status: status.to_string(),
+        account_id: 42,
+        complete: None,
+        webhook_url: None,
+        redirect_url: None,
+        memo: None,
+        uri: format!("pay:?r=https://api.anypayx.com/r/{}", uid),
+        createdAt: "2024-01-01T12:00:00Z".to_string(),
+        updatedAt: "2024-01-01T12:00:00Z".to_string(),
+        tags: Vec::new(),
+        deleted_at: None,
+    }
+}
+
+/// `invoice` as the store returns it.
+pub fn invoice_row(uid: &str, status: &str) -> Value {
+    serde_json::to_value(invoice(uid, status)).unwrap()
+}
+
+/// A request the store received.
+#[derive(Debug, Clone)]
+pub struct StoreRequest {
+    pub method: String,
+    /// Path and query, e.g. `/rest/v1/invoices?uid=eq.inv_1`.
+    pub target: String,
+    /// Request line and headers, lowercased.
+    pub head: String,
+    pub body: String,
+}
+
+impl StoreRequest {
+    /// The table the request is for: the last segment of its path.
+    pub fn table(&self) -> &str {
+        let path = self.target.split_once('?').map_or(self.target.as_str(), |(path, _)| path);
+        path.rsplit('/').next().unwrap_or("")
+    }
+
+    /// The request's `column=eq.value` filters.
+    pub fn filters(&self) -> Vec<(String, String)> {
+        let query = self.target.split_once('?').map_or("", |(_, query)| query);
+        query.split('&')
+            .filter_map(|pair| pair.split_once("=eq."))
+            .map(|(column, value)| (column.to_string(), value.to_string()))
+            .collect()
+    }
+}
+
+pub struct StoreResponse {
+    status: u16,
+    headers: String,
+    body: String,
+}
+
+impl StoreResponse {
+    pub fn json(body: impl ToString) -> Self {
+        StoreResponse::status(200, body)
+    }
+
+    pub fn status(status: u16, body: impl ToString) -> Self {
+        StoreResponse {
+            status,
+            headers: String::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Adds a header line such as `retry-after: 1`.
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.headers.push_str(header);
+        self.headers.push_str("\r\n");
+        self
+    }
+}
+
+/// Requests a store has seen, oldest first.
+pub type StoreRequests = Arc<Mutex<Vec<StoreRequest>>>;
+
+/// Serves each request with `respond`, one connection at a time in parallel.
+/// A request is recorded before it is answered, so a client that has its
+/// response can count on finding the request. Returns the store's url.
+pub async fn spawn_store<F, Fut>(respond: F) -> (String, StoreRequests)
+where
+    F: Fn(StoreRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = StoreResponse> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = StoreRequests::default();
+    let seen = requests.clone();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let (seen, respond) = (seen.clone(), respond.clone());
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let head_end = loop {
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                };
+                let raw_head = String::from_utf8_lossy(&request[..head_end]).to_string();
+                let head = raw_head.to_lowercase();
+                let length: usize = head.lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|length| length.trim().parse().ok())
+                    .unwrap_or(0);
+                while request.len() < head_end + length {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+
+                let mut line = raw_head.lines().next().unwrap_or("").split(' ');
+                let request = StoreRequest {
+                    method: line.next().unwrap_or("").to_string(),
+                    target: line.next().unwrap_or("").to_string(),
+                    head,
+                    body: String::from_utf8_lossy(&request[head_end..]).to_string(),
+                };
+                seen.lock().unwrap().push(request.clone());
+                let response = respond(request).await;
+                let _ = stream.write_all(format!(
+                    "HTTP/1.1 {} OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n{}connection: close\r\n\r\n{}",
+                    response.status, response.body.len(), response.headers, response.body
+                ).as_bytes()).await;
+            });
+        }
+    });
+    (url, requests)
+}
+
+/// A store keeping rows in memory: inserts are stored and returned, reads
+/// filter on `column=eq.value`, and updates patch the matching rows.
+/// Accounts always exist and have no addresses.
+pub async fn spawn_memory_store() -> String {
+    let tables: Arc<Mutex<HashMap<String, Vec<Value>>>> = Arc::default();
+    let (url, _) = spawn_store(move |request| {
+        let response = StoreResponse::json(json!(memory_store_rows(&tables, &request)));
+        async move { response }
+    }).await;
+    url
+}
+
+fn memory_store_rows(tables: &Mutex<HashMap<String, Vec<Value>>>, request: &StoreRequest) -> Vec<Value> {
+    let body: Value = serde_json::from_str(&request.body).unwrap_or_default();
+    let filters = request.filters();
+    let matches = |row: &Value| filters.iter().all(|(column, value)| {
+        row[column].as_str().map(str::to_string).unwrap_or_else(|| row[column].to_string()) == *value
+    });
+
+    let mut tables = tables.lock().unwrap();
+    let rows = tables.entry(request.table().to_string()).or_default();
+    match request.method.as_str() {
+        "POST" => {
+            let inserted: Vec<Value> = body.as_array().cloned().unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut row)| {
+                    row["id"] = json!(rows.len() + i + 1);
+                    row
+                })
+                .collect();
+            rows.extend(inserted.iter().cloned());
+            inserted
+        }
+        "PATCH" => rows.iter_mut()
+            .filter(|row| matches(row))
+            .map(|row| {
+                for (key, value) in body.as_object().into_iter().flatten() {
+                    row[key] = value.clone();
+                }
+                row.clone()
+            })
+            .collect(),
+        _ if request.table() == "accounts" => vec![json!({ "id": 1, "denomination": "USD" })],
+        _ => rows.iter().filter(|row| matches(row)).cloned().collect(),
+    }
+}
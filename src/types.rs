@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use crate::currency::AmountInput;
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,20 +11,71 @@ pub enum Message {
         #[serde(rename = "type")]
         sub_type: String,
         id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<SubscriptionFilter>,
+        /// Invoice fields to include in event payloads, e.g. `["uid", "status"]`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<String>>,
+        /// `compact` delivers only `COMPACT_INVOICE_FIELDS`. Can't be combined with `fields`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        view: Option<InvoiceView>,
+    },
+    #[serde(rename = "subscribe_many")]
+    SubscribeMany {
+        subscriptions: Vec<SubscribeRequest>,
+        /// Answer with only the summary, leaving out each subscription's ack.
+        /// Defaults to the connection's `suppress_acks` option.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suppress_acks: Option<bool>,
     },
     #[serde(rename = "unsubscribe")]
     Unsubscribe {
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        sub_type: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subscription_id: Option<String>,
+    },
+    #[serde(rename = "pause_subscription")]
+    PauseSubscription {
+        #[serde(rename = "type")]
+        sub_type: String,
+        id: String,
+    },
+    #[serde(rename = "resume_subscription")]
+    ResumeSubscription {
+        #[serde(rename = "type")]
+        sub_type: String,
+        id: String,
+    },
+    #[serde(rename = "update_subscription_filter")]
+    UpdateSubscriptionFilter {
+        #[serde(rename = "type")]
+        sub_type: String,
+        id: String,
+        filter: SubscriptionFilter,
+    },
+    #[serde(rename = "recent_events")]
+    RecentEvents {
         #[serde(rename = "type")]
         sub_type: String,
         id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
     },
     #[serde(rename = "fetch_invoice")]
     FetchInvoice {
         id: String,
+        /// Load and refresh payment options. Defaults to the server's setting.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expand: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        view: Option<InvoiceView>,
     },
     #[serde(rename = "create_invoice")]
     CreateInvoice {        
-        amount: i64,
+        amount: AmountInput,
         currency: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         webhook_url: Option<String>,
@@ -31,9 +83,18 @@ pub enum Message {
         redirect_url: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         memo: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        test: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account_id: Option<i64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
     },
     #[serde(rename = "list_prices")]
-    ListPrices,
+    ListPrices {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
     #[serde(rename = "convert_price")]
     ConvertPrice {
         quote_currency: String,
@@ -45,8 +106,114 @@ pub enum Message {
     CancelInvoice {
         uid: String,
     },
+    #[serde(rename = "fetch_invoice_history")]
+    FetchInvoiceHistory {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    #[serde(rename = "rebroadcast_invoice")]
+    RebroadcastInvoice {
+        id: String,
+    },
+    #[serde(rename = "wait_for_payment")]
+    WaitForPayment {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout_secs: Option<u64>,
+    },
+    #[serde(rename = "broadcast_account_event")]
+    BroadcastAccountEvent {
+        account_id: i64,
+        event: serde_json::Value,
+    },
+    #[serde(rename = "set_session_debug")]
+    SetSessionDebug {
+        session_id: uuid::Uuid,
+        enabled: bool,
+    },
+    #[serde(rename = "set_context")]
+    SetContext {
+        values: serde_json::Map<String, serde_json::Value>,
+    },
+    #[serde(rename = "get_context")]
+    GetContext,
+    #[serde(rename = "revalidate_subscriptions")]
+    RevalidateSubscriptions,
+    #[serde(rename = "selftest")]
+    Selftest,
+    #[serde(rename = "close")]
+    Close,
+    #[serde(rename = "connection_stats")]
+    ConnectionStats,
     #[serde(rename = "ping")]
     Ping,
+    #[serde(rename = "server_time")]
+    ServerTime,
+}
+
+impl Message {
+    /// Fields each action accepts besides `action`, used by strict mode to
+    /// reject misspelled fields. `None` for unknown actions.
+    pub fn fields(action: &str) -> Option<&'static [&'static str]> {
+        let fields: &'static [&'static str] = match action {
+            "subscribe" => &["type", "id", "filter", "fields", "view"],
+            "subscribe_many" => &["subscriptions", "suppress_acks"],
+            "unsubscribe" => &["type", "id", "subscription_id"],
+            "pause_subscription" | "resume_subscription" => &["type", "id"],
+            "update_subscription_filter" => &["type", "id", "filter"],
+            "recent_events" => &["type", "id", "limit"],
+            "fetch_invoice" => &["id", "expand", "view"],
+            "create_invoice" => &["amount", "currency", "webhook_url", "redirect_url", "memo", "test", "account_id", "tags"],
+            "list_prices" => &["limit"],
+            "convert_price" => &["quote_currency", "base_currency", "quote_value"],
+            "cancel_invoice" => &["uid"],
+            "fetch_invoice_history" => &["id", "limit"],
+            "rebroadcast_invoice" => &["id"],
+            "wait_for_payment" => &["id", "timeout_secs"],
+            "broadcast_account_event" => &["account_id", "event"],
+            "set_session_debug" => &["session_id", "enabled"],
+            "set_context" => &["values"],
+            "get_context" | "revalidate_subscriptions" | "selftest" | "close" | "connection_stats" | "ping" | "server_time" => &[],
+            _ => return None,
+        };
+        Some(fields)
+    }
+
+    /// Whether handling the message may query the store, which per-account
+    /// rate limits guard.
+    pub fn uses_backend(&self) -> bool {
+        matches!(
+            self,
+            Message::Subscribe { .. }
+                | Message::RevalidateSubscriptions
+                | Message::Selftest
+                | Message::FetchInvoice { .. }
+                | Message::CreateInvoice { .. }
+                | Message::ListPrices { .. }
+                | Message::ConvertPrice { .. }
+                | Message::CancelInvoice { .. }
+                | Message::FetchInvoiceHistory { .. }
+                | Message::RebroadcastInvoice { .. }
+                | Message::WaitForPayment { .. }
+                | Message::BroadcastAccountEvent { .. }
+        )
+    }
+
+    /// Whether handling the message leaves session and subscription state
+    /// untouched, so it can run concurrently with its read-only neighbours.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Message::FetchInvoice { .. }
+                | Message::FetchInvoiceHistory { .. }
+                | Message::ListPrices { .. }
+                | Message::RecentEvents { .. }
+                | Message::ConvertPrice { .. }
+                | Message::Ping
+                | Message::ServerTime
+        )
+    }
 }
 
 fn deserialize_number_from_string<'de, D>(deserializer: D) -> Result<f64, D::Error>
@@ -76,6 +243,99 @@ pub struct Response {
     pub message: String,
 }
 
+/// One topic of a `subscribe_many`, with the same options as `subscribe`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    #[serde(rename = "type")]
+    pub sub_type: String,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<SubscriptionFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view: Option<InvoiceView>,
+}
+
+/// Narrows the events a subscription delivers. Empty lists match everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    /// Event types to deliver, e.g. `["invoice.paid"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    /// Invoice statuses to deliver, matched against the event's `data.status`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<String>,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, event: &serde_json::Value) -> bool {
+        let listed = |allowed: &[String], value: Option<&str>| {
+            allowed.is_empty() || value.is_some_and(|value| allowed.iter().any(|allowed| allowed == value))
+        };
+        listed(&self.events, event["type"].as_str())
+            && listed(&self.statuses, event["data"]["status"].as_str())
+    }
+}
+
+/// Fields of an invoice event's `data` a subscription can be projected to.
+pub const PROJECTABLE_FIELDS: &[&str] = &[
+    "id", "uid", "amount", "currency", "status", "account_id", "complete", "webhook_url",
+    "redirect_url", "memo", "uri", "createdAt", "updatedAt", "tags", "deleted_at",
+];
+
+/// Fields kept by the compact invoice view.
+pub const COMPACT_INVOICE_FIELDS: &[&str] = &["id", "uid", "status", "amount", "currency"];
+
+/// How much of an invoice `fetch_invoice` and subscriptions carry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoiceView {
+    /// Only `COMPACT_INVOICE_FIELDS`, for list-heavy UIs.
+    Compact,
+    #[default]
+    Full,
+}
+
+impl InvoiceView {
+    /// The projection a subscription with this view applies, or None for whole events.
+    pub fn fields(self) -> Option<Vec<String>> {
+        match self {
+            InvoiceView::Compact => Some(COMPACT_INVOICE_FIELDS.iter().map(|field| field.to_string()).collect()),
+            InvoiceView::Full => None,
+        }
+    }
+
+    /// Trims a serialized invoice to this view.
+    pub fn apply(self, invoice: &mut serde_json::Value) {
+        if let (InvoiceView::Compact, Some(fields)) = (self, invoice.as_object_mut()) {
+            fields.retain(|key, _| COMPACT_INVOICE_FIELDS.contains(&key.as_str()));
+        }
+    }
+}
+
+/// Checks a subscribe `fields` projection names at least one known field.
+pub fn validate_projection(fields: &[String]) -> Result<(), String> {
+    if fields.is_empty() {
+        return Err("fields must name at least one field".to_string());
+    }
+    match fields.iter().find(|field| !PROJECTABLE_FIELDS.contains(&field.as_str())) {
+        Some(unknown) => Err(format!("Unknown field '{}', expected one of {}", unknown, PROJECTABLE_FIELDS.join(", "))),
+        None => Ok(()),
+    }
+}
+
+/// `event` with its `data` cut down to `fields`. Only `invoice.*` events are
+/// projected; others carry different payloads and pass through whole.
+pub fn project_event(event: &serde_json::Value, fields: &[String]) -> serde_json::Value {
+    let is_invoice_event = event["type"].as_str().is_some_and(|kind| kind.starts_with("invoice."));
+    let mut projected = event.clone();
+    if let (true, Some(data)) = (is_invoice_event, projected.get_mut("data").and_then(serde_json::Value::as_object_mut)) {
+        data.retain(|key, _| fields.contains(key));
+    }
+    projected
+}
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Subscription {
     pub sub_type: String,
@@ -113,6 +373,61 @@ pub struct Invoice {
     pub uri: String,
     pub createdAt: String,
     pub updatedAt: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+}
+
+impl Invoice {
+    /// Soft-deleted invoices keep their row but are no longer payable.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Patches an invoice row that has drifted from the expected schema so it
+    /// can still be deserialized: integers sent as strings are parsed, and
+    /// missing or null text fields that clients can do without become empty.
+    /// Returns the names of the fields that were patched.
+    pub fn repair_row(row: &mut serde_json::Value) -> Vec<&'static str> {
+        let mut repaired = Vec::new();
+        let Some(fields) = row.as_object_mut() else {
+            return repaired;
+        };
+
+        for field in ["id", "amount", "account_id"] {
+            let parsed = match fields.get(field) {
+                Some(serde_json::Value::String(text)) => text.trim().parse::<i64>().ok(),
+                Some(serde_json::Value::Number(number)) if number.as_i64().is_none() => {
+                    number.as_f64().filter(|value| value.fract() == 0.0).map(|value| value as i64)
+                }
+                _ => None,
+            };
+            if let Some(parsed) = parsed {
+                fields.insert(field.to_string(), parsed.into());
+                repaired.push(field);
+            }
+        }
+        for field in ["uri", "createdAt", "updatedAt"] {
+            if fields.get(field).is_none_or(serde_json::Value::is_null) {
+                fields.insert(field.to_string(), String::new().into());
+                repaired.push(field);
+            }
+        }
+        if fields.get("tags").is_some_and(serde_json::Value::is_null) {
+            fields.remove("tags");
+            repaired.push("tags");
+        }
+        repaired
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceStatusTransition {
+    pub invoice_uid: String,
+    pub status: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::str::FromStr;
+
+const BASE62: [char; 62] = [
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M',
+    'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm',
+    'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+];
+
+/// 22 base62 characters carry about as much randomness as a UUID.
+const BASE62_LENGTH: usize = 22;
+
+/// How new invoice uids are generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UidScheme {
+    /// `inv_` followed by a 12 character nanoid, as invoices have always used.
+    Nanoid { prefix: String },
+    /// A random UUID such as `1b4e28ba-2fa1-41d2-883f-0016d3cca427`.
+    Uuid,
+    /// A prefix followed by 22 alphanumeric characters, e.g. `inv_4fZq...`.
+    Base62 { prefix: String },
+}
+
+impl Default for UidScheme {
+    fn default() -> Self {
+        UidScheme::Nanoid { prefix: "inv_".to_string() }
+    }
+}
+
+impl UidScheme {
+    pub fn generate(&self) -> String {
+        match self {
+            UidScheme::Nanoid { prefix } => format!("{}{}", prefix, crate::payment::generate_uid()),
+            UidScheme::Uuid => uuid::Uuid::new_v4().to_string(),
+            UidScheme::Base62 { prefix } => format!("{}{}", prefix, nanoid::nanoid!(BASE62_LENGTH, &BASE62)),
+        }
+    }
+}
+
+/// Parses `uuid`, `nanoid`, `nanoid:<prefix>`, `base62` or `base62:<prefix>`.
+/// Without a prefix, `inv_` is used.
+impl FromStr for UidScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, prefix) = match s.split_once(':') {
+            Some((kind, prefix)) => (kind, prefix.to_string()),
+            None => (s, "inv_".to_string()),
+        };
+        if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(format!("invalid uid prefix '{}'", prefix));
+        }
+
+        match kind {
+            "uuid" if !s.contains(':') => Ok(UidScheme::Uuid),
+            "nanoid" => Ok(UidScheme::Nanoid { prefix }),
+            "base62" => Ok(UidScheme::Base62 { prefix }),
+            _ => Err(format!("expected 'uuid', 'nanoid[:prefix]' or 'base62[:prefix]', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for UidScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UidScheme::Nanoid { prefix } => write!(f, "nanoid:{}", prefix),
+            UidScheme::Uuid => write!(f, "uuid"),
+            UidScheme::Base62 { prefix } => write!(f, "base62:{}", prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uid_schemes() {
+        assert_eq!(UidScheme::default().to_string(), "nanoid:inv_");
+        assert_eq!("uuid".parse(), Ok(UidScheme::Uuid));
+        assert!("uuid:inv_".parse::<UidScheme>().is_err());
+        assert!("base62:inv/".parse::<UidScheme>().is_err());
+
+        let scheme: UidScheme = "base62:inv_".parse().unwrap();
+        let uid = scheme.generate();
+        assert!(uid.starts_with("inv_"));
+        assert_eq!(uid.len(), 4 + BASE62_LENGTH);
+        assert!(uid[4..].chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(uid, scheme.generate());
+
+        assert!(uuid::Uuid::parse_str(&UidScheme::Uuid.generate()).is_ok());
+    }
+}
@@ -1,4 +1,14 @@
 use serde::{Deserialize, Serialize};
+use crate::currency::CurrencyTable;
+
+/// BIP21 URI schemes by currency.
+const BIP21_SCHEMES: &[(&str, &str)] = &[
+    ("BTC", "bitcoin"),
+    ("BCH", "bitcoincash"),
+    ("LTC", "litecoin"),
+    ("DOGE", "dogecoin"),
+    ("DASH", "dash"),
+];
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InvoiceUriParams {
@@ -11,6 +21,24 @@ pub fn compute_invoice_uri(params: &InvoiceUriParams) -> String {
     format!("anypay:{}_{}", params.currency.to_lowercase(), params.uid)
 }
 
+/// A wallet URI paying `amount` minor units of `currency` to `address`: BIP21
+/// for bitcoin-like coins, EIP-681 for ether. None for currencies without a
+/// standard scheme here, such as tokens, whose URIs need a contract address.
+pub fn payment_uri(currency: &str, chain: &str, address: &str, amount: i64, currencies: &CurrencyTable) -> Option<String> {
+    let currency = currency.to_uppercase();
+    if let Some((_, scheme)) = BIP21_SCHEMES.iter().find(|(code, _)| *code == currency && chain.eq_ignore_ascii_case(code)) {
+        let amount = currencies.format_minor_units(amount, &currency)?;
+        // Cash address payments already carry the scheme prefix
+        let address = address.strip_prefix(&format!("{}:", scheme)).unwrap_or(address);
+        return Some(format!("{}:{}?amount={}", scheme, address, amount));
+    }
+    if currency == "ETH" && chain.eq_ignore_ascii_case("ETH") {
+        // EIP-681 amounts are in wei, which is the minor unit
+        return Some(format!("ethereum:{}?value={}", address, amount));
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,4 +53,22 @@ mod tests {
         let uri = compute_invoice_uri(&params);
         assert_eq!(uri, "anypay:btc_inv_123");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_payment_uris() {
+        let currencies = CurrencyTable::default();
+        assert_eq!(
+            payment_uri("BTC", "BTC", "1BoatSLRHtKNngkdXEeobR76b53LETtpyT", 150000, &currencies).as_deref(),
+            Some("bitcoin:1BoatSLRHtKNngkdXEeobR76b53LETtpyT?amount=0.00150000")
+        );
+        assert_eq!(
+            payment_uri("bch", "BCH", "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a", 1, &currencies).as_deref(),
+            Some("bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a?amount=0.00000001")
+        );
+        assert_eq!(
+            payment_uri("ETH", "ETH", "0xfb6916095ca1df60bb79Ce92ce3ea74c37c5d359", 2014000000000000000, &currencies).as_deref(),
+            Some("ethereum:0xfb6916095ca1df60bb79Ce92ce3ea74c37c5d359?value=2014000000000000000")
+        );
+        assert_eq!(payment_uri("USDC", "ETH", "0xfb6916095ca1df60bb79Ce92ce3ea74c37c5d359", 1, &currencies), None);
+    }
+}
@@ -31,6 +31,8 @@ fn create_test_invoice() -> Invoice {
         uri: format!("pay:?r=https://api.anypayx.com/r/{}", uuid::Uuid::new_v4()),
         createdAt: chrono::Utc::now().to_rfc3339(),
         updatedAt: chrono::Utc::now().to_rfc3339(),
+        tags: Vec::new(),
+        deleted_at: None,
     }
 }
 